@@ -0,0 +1,473 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use morpheus::DreamImage;
+use quicksand::{Instruction, Register};
+
+use crate::vm::{VMError, VM};
+
+const HELP: &str = "\
+break [OFFSET|LABEL]   (b)  set a breakpoint, or list them without an argument
+delete OFFSET|LABEL    (d)  remove a breakpoint
+step [N]               (s)  execute N instructions
+next                   (n)  execute one instruction, stepping over calls
+continue               (c)  run until a breakpoint or the end of the program
+regs                   (r)  print the registers that are not zero
+print REG              (p)  print a register
+set REG VALUE               change a register
+stack [N]              (x)  dump the top N 8-byte words of the stack
+disasm [N]             (l)  disassemble N instructions around the program counter
+frames                 (bt) print the call stack
+quit                   (q)  leave the debugger
+Offsets are code offsets, labels are the L0, L1, ... of the disassembly and ENTRY.
+An empty line repeats the last command.";
+
+/// Why the program stopped running.
+enum Stop {
+    Breakpoint,
+    Stepped,
+    Exited(u64),
+    Faulted(VMError),
+}
+
+/// What a command asks the REPL to do next.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+/// An interactive debugger around a loaded VM.
+pub struct Debugger {
+    vm: VM,
+    entry_point: usize,
+    /// Code offsets of the disassembler's labels, indexed by label number.
+    labels: Vec<usize>,
+    /// Code offset of every instruction, found by decoding the code in order.
+    inst_starts: Vec<usize>,
+    breakpoints: BTreeSet<usize>,
+    /// Set once the program exited or faulted.
+    finished: bool,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(mut vm: VM, image: &DreamImage) -> Self {
+        vm.load_image(image);
+
+        let mut inst_starts = vec![];
+        let mut offset = 0;
+        while offset < image.code.len() {
+            let Ok((_, size)) = morpheus::disassemble_instruction(&image.code, offset) else {
+                break;
+            };
+            inst_starts.push(offset);
+            offset += size;
+        }
+
+        Self {
+            vm,
+            entry_point: image.entry_point,
+            labels: morpheus::code_labels(image).unwrap_or_default(),
+            inst_starts,
+            breakpoints: BTreeSet::new(),
+            finished: false,
+            last_command: String::new(),
+        }
+    }
+
+    /// Reads commands from `input` until it ends or the user quits.
+    pub fn repl(&mut self, input: impl BufRead, out: &mut dyn Write) -> io::Result<()> {
+        self.show_pc(out)?;
+        write!(out, "(dream) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            if self.command(&line?, out)? == Flow::Quit {
+                return Ok(());
+            }
+            write!(out, "(dream) ")?;
+            out.flush()?;
+        }
+
+        writeln!(out)
+    }
+
+    /// Executes one command line.
+    pub fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<Flow> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(Flow::Continue);
+        };
+        let args: Vec<&str> = words.collect();
+
+        let result = match (command, args.as_slice()) {
+            ("break" | "b", []) => Ok(self.list_breakpoints(out)),
+            ("break" | "b", [location]) => self.location(location).map(|offset| {
+                self.breakpoints.insert(offset);
+                writeln!(out, "Breakpoint at {offset:08X}")
+            }),
+            ("delete" | "d", [location]) => self.location(location).map(|offset| {
+                if self.breakpoints.remove(&offset) {
+                    Ok(())
+                } else {
+                    writeln!(out, "No breakpoint at {offset:08X}")
+                }
+            }),
+            ("step" | "s", []) => Ok(self.step(1, out)),
+            ("step" | "s", [n]) => parse_number(n).map(|n| self.step(n as usize, out)),
+            ("next" | "n", []) => Ok(self.next(out)),
+            ("continue" | "c", []) => Ok(self.resume(None, out)),
+            ("regs" | "r", []) => Ok(self.print_registers(out)),
+            ("print" | "p", [reg]) => parse_register(reg).map(|reg| {
+                let value = self.vm.reg.r.get(reg);
+                writeln!(out, "{reg} = {value} ({value:#X})")
+            }),
+            ("set", [reg, value]) => parse_register(reg).and_then(|reg| {
+                let value = parse_number(value)?;
+                self.vm.reg.r.set(reg, value);
+                Ok(writeln!(out, "{reg} = {}", self.vm.reg.r.get(reg)))
+            }),
+            ("stack" | "x", []) => Ok(self.dump_stack(8, out)),
+            ("stack" | "x", [n]) => parse_number(n).map(|n| self.dump_stack(n as usize, out)),
+            ("disasm" | "l", []) => Ok(self.disassemble(10, out)),
+            ("disasm" | "l", [n]) => parse_number(n).map(|n| self.disassemble(n as usize, out)),
+            ("frames" | "bt", []) => Ok(self.print_frames(out)),
+            ("help" | "h", []) => Ok(writeln!(out, "{HELP}")),
+            ("quit" | "q", []) => return Ok(Flow::Quit),
+            _ => Err(format!("Unknown command {line:?}, try 'help'.")),
+        };
+
+        match result {
+            Ok(written) => written?,
+            Err(err) => writeln!(out, "{err}")?,
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Resolves a code offset or a label.
+    fn location(&self, location: &str) -> Result<usize, String> {
+        if location.eq_ignore_ascii_case("entry") {
+            return Ok(self.entry_point);
+        }
+        if let Some(n) = location.strip_prefix('L') {
+            return n
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.labels.get(n).copied())
+                .ok_or_else(|| format!("No label {location}."));
+        }
+        parse_number(location).map(|offset| offset as usize)
+    }
+
+    fn step(&mut self, n: usize, out: &mut dyn Write) -> io::Result<()> {
+        for _ in 0..n {
+            match self.execute() {
+                Some(Stop::Stepped) => {}
+                Some(stop) => return self.report(stop, out),
+                None => return writeln!(out, "The program is not running."),
+            }
+        }
+        self.report(Stop::Stepped, out)
+    }
+
+    /// Steps over calls by running until the call returns.
+    fn next(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let is_call = self
+            .vm
+            .code
+            .get(self.vm.pc)
+            .is_some_and(|&inst| inst & !Instruction::ALT_MODE == Instruction::Call as u8);
+        if is_call {
+            self.resume(Some(self.vm.frames.len()), out)
+        } else {
+            self.step(1, out)
+        }
+    }
+
+    /// Runs until a breakpoint or, if `depth` is given, until the call stack
+    /// is back to that depth.
+    fn resume(&mut self, depth: Option<usize>, out: &mut dyn Write) -> io::Result<()> {
+        loop {
+            let stop = match self.execute() {
+                Some(Stop::Stepped) if depth.is_some_and(|depth| self.vm.frames.len() <= depth) => {
+                    Stop::Stepped
+                }
+                Some(Stop::Stepped) if self.breakpoints.contains(&self.vm.pc) => Stop::Breakpoint,
+                Some(Stop::Stepped) => continue,
+                Some(stop) => stop,
+                None => return writeln!(out, "The program is not running."),
+            };
+            return self.report(stop, out);
+        }
+    }
+
+    fn execute(&mut self) -> Option<Stop> {
+        if self.finished {
+            return None;
+        }
+
+        let stop = match self.vm.step() {
+            Ok(None) => return Some(Stop::Stepped),
+            Ok(Some(exit_code)) => Stop::Exited(exit_code),
+            Err(err) => Stop::Faulted(err),
+        };
+        self.finished = true;
+        Some(stop)
+    }
+
+    fn report(&mut self, stop: Stop, out: &mut dyn Write) -> io::Result<()> {
+        match stop {
+            Stop::Breakpoint => {
+                writeln!(out, "Breakpoint at {:08X}", self.vm.pc)?;
+                self.show_pc(out)
+            }
+            Stop::Stepped => self.show_pc(out),
+            Stop::Exited(exit_code) => writeln!(out, "The program exited with code {exit_code}."),
+            Stop::Faulted(err) => writeln!(out, "The program stopped: {err}."),
+        }
+    }
+
+    /// Prints the instruction at the program counter.
+    fn show_pc(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.write_instruction(self.vm.pc, out)
+    }
+
+    fn write_instruction(&self, offset: usize, out: &mut dyn Write) -> io::Result<()> {
+        let marker = match (offset == self.vm.pc, self.breakpoints.contains(&offset)) {
+            (true, _) => "=>",
+            (false, true) => " *",
+            (false, false) => "  ",
+        };
+        let inst = match morpheus::disassemble_instruction(&self.vm.code, offset) {
+            Ok((inst, _)) => inst,
+            Err(_) => "???".to_string(),
+        };
+        writeln!(out, "{marker} {offset:08X}      {inst}")
+    }
+
+    fn disassemble(&self, n: usize, out: &mut dyn Write) -> io::Result<()> {
+        let at = self
+            .inst_starts
+            .partition_point(|&offset| offset < self.vm.pc);
+        if self.inst_starts.get(at) != Some(&self.vm.pc) {
+            // The program counter is not where linear decoding expects an
+            // instruction, so only show what follows it.
+            return self.write_instruction(self.vm.pc, out);
+        }
+
+        let begin = at.saturating_sub(n / 3);
+        let end = (begin + n).min(self.inst_starts.len());
+        for &offset in &self.inst_starts[begin..end] {
+            if offset == self.entry_point {
+                writeln!(out, "   ENTRY:")?;
+            }
+            if let Some(n) = self.labels.iter().position(|&label| label == offset) {
+                writeln!(out, "   L{n}:")?;
+            }
+            self.write_instruction(offset, out)?;
+        }
+        Ok(())
+    }
+
+    fn list_breakpoints(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.breakpoints.is_empty() {
+            return writeln!(out, "No breakpoints.");
+        }
+        for &offset in &self.breakpoints {
+            self.write_instruction(offset, out)?;
+        }
+        Ok(())
+    }
+
+    fn print_registers(&self, out: &mut dyn Write) -> io::Result<()> {
+        let reg = &self.vm.reg;
+        writeln!(out, "pc    = {:08X}", self.vm.pc)?;
+        writeln!(out, "flags = {:#05b}", reg.flags)?;
+        write!(out, "{}", reg.r)
+    }
+
+    fn dump_stack(&self, words: usize, out: &mut dyn Write) -> io::Result<()> {
+        let depth = self.vm.stack.depth();
+        writeln!(out, "Stack depth {depth} of {}", self.vm.stack.size())?;
+
+        let mut end = depth;
+        for _ in 0..words {
+            if end == 0 {
+                break;
+            }
+            let begin = end.saturating_sub(std::mem::size_of::<u64>());
+            let bytes = self
+                .vm
+                .stack
+                .load_bytes(begin, end - begin)
+                .unwrap_or_default();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02X}")).collect();
+            let mut value = [0; 8];
+            value[..bytes.len()].copy_from_slice(bytes);
+
+            let fp = if self.vm.frames.iter().any(|frame| frame.fp == begin) {
+                "  <- fp"
+            } else {
+                ""
+            };
+            writeln!(
+                out,
+                "[stk+{begin}]{:pad$}{}  {}{fp}",
+                "",
+                hex.join(" "),
+                u64::from_le_bytes(value),
+                pad = 10usize.saturating_sub(format!("{begin}").len()),
+            )?;
+            end = begin;
+        }
+        Ok(())
+    }
+
+    fn print_frames(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "#0  {:08X}", self.vm.pc)?;
+        for (n, frame) in self.vm.frames.iter().rev().enumerate() {
+            writeln!(
+                out,
+                "#{:<2} {:08X}  fp = {}",
+                n + 1,
+                frame.return_pc,
+                frame.fp
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a number the way the assembler reads literals after a `$`, e.g.
+/// `42`, `0x2A` or `-1`.
+fn parse_number(s: &str) -> Result<u64, String> {
+    quicksand::parse_literal(s).map_err(|_| format!("Invalid number {s:?}."))
+}
+
+/// Parses a register the way it is displayed, e.g. `rq3`, `rs1` or `rsi`.
+fn parse_register(name: &str) -> Result<Register, String> {
+    name.parse()
+        .map_err(|_| format!("Invalid register {name:?}."))
+}
+
+#[cfg(test)]
+mod tests {
+    use morpheus::{Builder, Operand, OutputType, Version};
+    use quicksand::RegisterType;
+
+    use super::*;
+
+    fn q(x: u8) -> Register {
+        Register::new(RegisterType::Q, x).unwrap()
+    }
+
+    /// A program whose entry procedure at offset 5 calls a procedure at
+    /// offset 0 that adds rq1 to rq0, then loops until rq0 is 4.
+    fn debugger() -> Debugger {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let add = builder
+            .procedure(|proc| proc.body(|block| block.emit_add(q(0), Operand::reg(q(1)))))
+            .unwrap();
+        let entry = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    let top = block.new_label();
+                    block.emit_move_imm(Operand::reg(q(1)), 2)?;
+                    block.emit_push(Operand::reg(q(1)));
+                    block.bind_label(top)?;
+                    block.emit_call(add);
+                    block.emit_cmp(q(0), Operand::lit64(4))?;
+                    block.emit_jnz(top);
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+        Debugger::new(VM::default(), &builder.link().unwrap())
+    }
+
+    fn run(debugger: &mut Debugger, commands: &str) -> String {
+        let mut out = vec![];
+        for command in commands.lines() {
+            debugger.command(command, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn stepping() {
+        let mut dbg = debugger();
+        assert_eq!(
+            run(&mut dbg, "step\nstep\nnext"),
+            "=> 0000000F      Push        rq1\n\
+             => 00000011      Call        $0\n\
+             => 0000001A      Cmp         rq0, $4\n"
+        );
+        assert_eq!(dbg.vm.frames.len(), 0);
+
+        assert_eq!(
+            run(&mut dbg, "b L1\nb 4\ncontinue\n\ndelete 4\nc\nc"),
+            "Breakpoint at 00000011\n\
+             Breakpoint at 00000004\n\
+             Breakpoint at 00000011\n\
+             => 00000011      Call        $0\n\
+             Breakpoint at 00000004\n\
+             => 00000004      Ret\n\
+             The program exited with code 4.\n\
+             The program is not running.\n"
+        );
+    }
+
+    #[test]
+    fn registers_and_stack() {
+        let mut dbg = debugger();
+        let out = run(&mut dbg, "s 2\nset rq0 -1\np rq0\nset rb3 0x1FF\nregs\nx");
+        assert_eq!(
+            out,
+            "=> 00000011      Call        $0\n\
+             rq0 = 18446744073709551615\n\
+             rq0 = 18446744073709551615 (0xFFFFFFFFFFFFFFFF)\n\
+             rb3 = 255\n\
+             pc    = 00000011\n\
+             flags = 0b000\n\
+             rb3   = 255 (0xFF)\n\
+             rq0   = 18446744073709551615 (0xFFFFFFFFFFFFFFFF)\n\
+             rq1   = 2 (0x2)\n\
+             Stack depth 8 of 4096\n\
+             [stk+0]         02 00 00 00 00 00 00 00  2\n"
+        );
+
+        assert_eq!(
+            run(&mut dbg, "p rq99\nset rq0 many\nfrob"),
+            "Invalid register \"rq99\".\n\
+             Invalid number \"many\".\n\
+             Unknown command \"frob\", try 'help'.\n"
+        );
+    }
+
+    #[test]
+    fn listing() {
+        let mut dbg = debugger();
+        let out = run(&mut dbg, "b L0\ns 3\ndisasm 4\nbt");
+        assert_eq!(
+            out,
+            "Breakpoint at 00000000\n\
+             => 00000000      Add         rq0, rq1\n\
+             \x20  L0:\n\
+             => 00000000      Add         rq0, rq1\n\
+             \x20  00000004      Ret\n\
+             \x20  ENTRY:\n\
+             \x20  00000005      MoveImm     rq1, $2\n\
+             \x20  0000000F      Push        rq1\n\
+             #0  00000000\n\
+             #1  0000001A  fp = 8\n"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+
+const ALIGNMENT: usize = 16;
+const DEFAULT_LIMIT: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapError {
+    OutOfBounds,
+    UseAfterFree,
+    InvalidFree,
+    DoubleFree,
+}
+
+/// Guest heap. Works in offsets from the start of the heap segment, the VM
+/// turns them into guest addresses.
+///
+/// In debug mode freed blocks are never handed out again, which lets every
+/// access be checked against the live allocations and double frees be told
+/// apart from frees of pointers that were never allocated.
+#[derive(Debug)]
+pub struct Heap {
+    bytes: Vec<u8>,
+    limit: usize,
+    /// Offset and size of every live allocation.
+    live: BTreeMap<usize, usize>,
+    /// Offset and size of every reusable block, adjacent blocks are merged.
+    free: BTreeMap<usize, usize>,
+    /// Offset and size of every block freed in debug mode.
+    freed: BTreeMap<usize, usize>,
+    pub debug: bool,
+}
+
+impl std::default::Default for Heap {
+    fn default() -> Self {
+        Self::new(DEFAULT_LIMIT)
+    }
+}
+
+impl Heap {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            bytes: vec![],
+            limit,
+            live: BTreeMap::new(),
+            free: BTreeMap::new(),
+            freed: BTreeMap::new(),
+            debug: false,
+        }
+    }
+
+    /// Frees everything, keeping the limit and debug mode.
+    pub fn reset(&mut self) {
+        *self = Self {
+            debug: self.debug,
+            ..Self::new(self.limit)
+        };
+    }
+
+    /// Allocates `size` zeroed bytes, returning their offset or `None` if the
+    /// heap is exhausted.
+    pub fn alloc(&mut self, size: usize) -> Option<usize> {
+        let size = size.max(1).checked_next_multiple_of(ALIGNMENT)?;
+
+        let reusable = self
+            .free
+            .iter()
+            .find(|(_, &block_size)| block_size >= size)
+            .map(|(&offset, &block_size)| (offset, block_size));
+
+        let offset = match reusable {
+            Some((offset, block_size)) => {
+                self.free.remove(&offset);
+                if block_size > size {
+                    self.free.insert(offset + size, block_size - size);
+                }
+                self.bytes[offset..offset + size].fill(0);
+                offset
+            }
+            None => {
+                let offset = self.bytes.len();
+                let end = offset.checked_add(size).filter(|&end| end <= self.limit)?;
+                self.bytes.resize(end, 0);
+                offset
+            }
+        };
+
+        self.live.insert(offset, size);
+        Some(offset)
+    }
+
+    pub fn free(&mut self, offset: usize) -> Result<(), HeapError> {
+        let size = self.live_size(offset)?;
+        self.live.remove(&offset);
+        if self.debug {
+            self.freed.insert(offset, size);
+        } else {
+            self.release(offset, size);
+        }
+        Ok(())
+    }
+
+    /// Resizes the allocation at `offset`, moving it if it has to grow.
+    /// Returns the new offset, or `None` if the heap is exhausted in which case
+    /// the old allocation is left untouched.
+    pub fn realloc(&mut self, offset: usize, size: usize) -> Result<Option<usize>, HeapError> {
+        let old_size = self.live_size(offset)?;
+
+        if size <= old_size && !self.debug {
+            return Ok(Some(offset));
+        }
+
+        let Some(new_offset) = self.alloc(size) else {
+            return Ok(None);
+        };
+        let n = old_size.min(size);
+        self.bytes.copy_within(offset..offset + n, new_offset);
+        self.free(offset)?;
+        Ok(Some(new_offset))
+    }
+
+    pub fn bytes(&self, offset: usize, size: usize) -> Result<&[u8], HeapError> {
+        let end = self.check(offset, size)?;
+        Ok(&self.bytes[offset..end])
+    }
+
+    pub fn bytes_mut(&mut self, offset: usize, size: usize) -> Result<&mut [u8], HeapError> {
+        let end = self.check(offset, size)?;
+        Ok(&mut self.bytes[offset..end])
+    }
+
+    pub fn live_allocations(&self) -> usize {
+        self.live.len()
+    }
+
+    fn live_size(&self, offset: usize) -> Result<usize, HeapError> {
+        match self.live.get(&offset) {
+            Some(&size) => Ok(size),
+            None if self.freed.contains_key(&offset) => Err(HeapError::DoubleFree),
+            None => Err(HeapError::InvalidFree),
+        }
+    }
+
+    /// Returns a block to the free list, merging it with its neighbours.
+    fn release(&mut self, mut offset: usize, mut size: usize) {
+        if let Some((&prev, &prev_size)) = self.free.range(..offset).next_back() {
+            if prev + prev_size == offset {
+                self.free.remove(&prev);
+                offset = prev;
+                size += prev_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&(offset + size)) {
+            size += next_size;
+        }
+        self.free.insert(offset, size);
+    }
+
+    /// Checks that `size` bytes at `offset` may be accessed, returning the end
+    /// of the range.
+    fn check(&self, offset: usize, size: usize) -> Result<usize, HeapError> {
+        let end = offset.checked_add(size).ok_or(HeapError::OutOfBounds)?;
+        if !self.debug {
+            if end > self.bytes.len() {
+                return Err(HeapError::OutOfBounds);
+            }
+            return Ok(end);
+        }
+
+        let within = |blocks: &BTreeMap<usize, usize>| {
+            blocks
+                .range(..=offset)
+                .next_back()
+                .is_some_and(|(&start, &block_size)| end <= start + block_size)
+        };
+
+        if within(&self.live) {
+            Ok(end)
+        } else if within(&self.freed) {
+            Err(HeapError::UseAfterFree)
+        } else {
+            Err(HeapError::OutOfBounds)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_reused() {
+        let mut heap = Heap::default();
+        let a = heap.alloc(10).unwrap();
+        let b = heap.alloc(10).unwrap();
+        let c = heap.alloc(10).unwrap();
+        assert_eq!((a, b, c), (0, 16, 32));
+
+        heap.bytes_mut(a, 10).unwrap().fill(0xFF);
+        heap.free(a).unwrap();
+        heap.free(b).unwrap();
+
+        // The two freed blocks are merged and zeroed before reuse.
+        let d = heap.alloc(32).unwrap();
+        assert_eq!(d, a);
+        assert_eq!(heap.bytes(d, 32).unwrap(), &[0; 32]);
+        assert_eq!(heap.live_allocations(), 2);
+    }
+
+    #[test]
+    fn realloc_keeps_contents() {
+        let mut heap = Heap::default();
+        let a = heap.alloc(4).unwrap();
+        heap.alloc(4).unwrap();
+        heap.bytes_mut(a, 4).unwrap().copy_from_slice(b"abcd");
+
+        let b = heap.realloc(a, 64).unwrap().unwrap();
+        assert_ne!(a, b);
+        assert_eq!(heap.bytes(b, 4).unwrap(), b"abcd");
+        assert_eq!(heap.free(a), Err(HeapError::InvalidFree));
+    }
+
+    #[test]
+    fn limit() {
+        let mut heap = Heap::new(64);
+        assert!(heap.alloc(48).is_some());
+        assert!(heap.alloc(32).is_none());
+        assert!(heap.alloc(16).is_some());
+    }
+
+    #[test]
+    fn debug_mode() {
+        let mut heap = Heap {
+            debug: true,
+            ..Heap::default()
+        };
+
+        let a = heap.alloc(8).unwrap();
+        assert!(heap.bytes(a, 16).is_ok());
+        assert_eq!(heap.bytes(a, 17), Err(HeapError::OutOfBounds));
+
+        heap.free(a).unwrap();
+        assert_eq!(heap.bytes(a, 8), Err(HeapError::UseAfterFree));
+        assert_eq!(heap.free(a), Err(HeapError::DoubleFree));
+        assert_eq!(heap.free(a + 1), Err(HeapError::InvalidFree));
+
+        // Freed blocks are never handed out again.
+        assert_ne!(heap.alloc(8).unwrap(), a);
+    }
+}
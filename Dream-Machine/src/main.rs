@@ -1,115 +1,551 @@
-use std::{fs::File, io::{BufRead, BufReader, Read}};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use morpheus::{DisassemblyOptions, DreamImage};
+use trace::{TraceFilter, TraceFormat, Tracer};
 
+mod debugger;
+mod heap;
 mod sys;
 mod syscalls;
+mod trace;
 mod vm;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Execute a dream file
+    Run(RunArgs),
+
+    /// Print human-readable disassembly of a dream file
+    Disasm {
+        /// Dream file to disassemble
+        file: PathBuf,
+
+        /// Write the listing to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Show the encoding of every instruction
+        #[arg(long = "raw-bytes")]
+        raw_bytes: bool,
+    },
+
+    /// Assemble a text listing into a dream file
+    Asm {
+        /// Listing to assemble
+        file: PathBuf,
+
+        /// Dream file to write, defaults to the listing with a .dream extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print the header and section sizes of a dream file
+    Info {
+        /// Dream file to inspect
+        file: PathBuf,
+    },
+
+    /// Step through a dream file interactively
+    Debug {
+        /// Dream file to debug
+        file: PathBuf,
+
+        /// Detect double frees and use after free of heap memory
+        #[arg(long = "debug-heap")]
+        debug_heap: bool,
+
+        /// Size of the stack in bytes, with an optional K or M suffix
+        #[arg(
+            long = "stack-size",
+            value_name = "SIZE",
+            value_parser = parse_size,
+            default_value_t = vm::DEFAULT_STACK_SIZE
+        )]
+        stack_size: usize,
+    },
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
     /// Dream file to execute
-    file: String,
+    file: PathBuf,
+
+    /// Detect double frees and use after free of heap memory
+    #[arg(long = "debug-heap")]
+    debug_heap: bool,
 
-    /// Print human-readable disassembly of the dream file
-    #[arg(long = "emit-disassembly")]
-    emit_disassembly: Option<String>,
+    /// Size of the stack in bytes, with an optional K or M suffix
+    #[arg(
+        long = "stack-size",
+        value_name = "SIZE",
+        value_parser = parse_size,
+        default_value_t = vm::DEFAULT_STACK_SIZE
+    )]
+    stack_size: usize,
+
+    /// Print every executed instruction and the registers it reads and writes
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    trace: Option<TraceFormat>,
+
+    /// Write the trace to this file instead of stderr
+    #[arg(long = "trace-output", value_name = "FILE")]
+    trace_output: Option<PathBuf>,
+
+    /// Only trace instructions at code offsets in START..END
+    #[arg(long = "trace-range", value_name = "START..END", value_parser = parse_range)]
+    trace_range: Option<Range<usize>>,
+
+    /// Only trace the procedure at this code offset and what it calls
+    #[arg(long = "trace-proc", value_name = "OFFSET", value_parser = parse_offset)]
+    trace_proc: Option<usize>,
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    if let Some(dasm_path) = cli.emit_disassembly {
-        let rdr = BufReader::new(File::open(&cli.file).unwrap());
-        let mut dasm_file = File::create(dasm_path).unwrap();
-        morpheus::disassemble(rdr.bytes().map(Result::unwrap), &mut dasm_file).unwrap();
+    match cli.command {
+        Command::Run(args) => run(args),
+        Command::Disasm {
+            file,
+            output,
+            raw_bytes,
+        } => disasm(&file, output, DisassemblyOptions { raw_bytes }),
+        Command::Asm { file, output } => asm(&file, output),
+        Command::Info { file } => info(&file),
+        Command::Debug {
+            file,
+            debug_heap,
+            stack_size,
+        } => debug(&file, debug_heap, stack_size),
     }
+}
+
+fn fail(msg: impl std::fmt::Display) -> ! {
+    eprintln!("ERROR: {msg}");
+    std::process::exit(1);
+}
+
+fn read(file: &Path) -> Vec<u8> {
+    std::fs::read(file).unwrap_or_else(|err| fail(format!("Couldn't read {file:?}: {err}")))
+}
+
+fn read_image(file: &Path) -> DreamImage {
+    DreamImage::parse(&read(file))
+        .unwrap_or_else(|err| fail(format!("{file:?} is not a valid dream file: {err:?}")))
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal code offset.
+fn parse_offset(s: &str) -> Result<usize, String> {
+    let offset = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    offset.map_err(|_| format!("invalid offset {s:?}"))
+}
 
-    println!("The file you want to run is {:?}.", cli.file);
+/// Parses a size in bytes, optionally in KiB or MiB, e.g. `512`, `64K` or `1M`.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, unit) = match s.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1024),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (s, 1),
+        },
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size {s:?}"))
+}
+
+fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected START..END, found {s:?}"))?;
+    Ok(parse_offset(start)?..parse_offset(end)?)
+}
+
+fn run(args: RunArgs) {
+    let file = &args.file;
+    let dream = read(file);
+
+    let mut dvm = vm::VM::builder()
+        .stack_size(args.stack_size)
+        .debug_heap(args.debug_heap)
+        .build();
+
+    if let Some(format) = args.trace {
+        let out: Box<dyn Write> = match &args.trace_output {
+            Some(path) => {
+                let file = File::create(path)
+                    .unwrap_or_else(|err| fail(format!("Couldn't create {path:?}: {err}")));
+                Box::new(BufWriter::new(file))
+            }
+            None => Box::new(std::io::stderr()),
+        };
+        let filter = TraceFilter {
+            range: args.trace_range,
+            procedure: args.trace_proc,
+        };
+        dvm.trace = Some(Tracer::new(format, filter, out));
+    }
+
+    if let Err(err) = dvm.load(&dream) {
+        fail(format!("Failed to load {file:?}: {err}"));
+    }
+
+    let result = dvm.run();
+    if let Some(trace) = &mut dvm.trace {
+        if let Err(err) = trace.flush() {
+            fail(format!("Failed to write the trace: {err}"));
+        }
+    }
+
+    match result {
+        Ok(exit_code) => {
+            let leaked = dvm.heap.live_allocations();
+            if args.debug_heap && leaked > 0 {
+                eprintln!("WARNING: {leaked} heap allocations were never freed");
+            }
+            std::process::exit(exit_code as i32)
+        }
+        Err(err) => fail(err),
+    }
+}
+
+fn disasm(file: &Path, output: Option<PathBuf>, options: DisassemblyOptions) {
+    let image = read_image(file);
+
+    let mut listing = String::new();
+    match morpheus::disassemble_image_with(&image, options, &mut listing) {
+        Ok(()) => {}
+        Err(morpheus::Error::DisassembleFailure(quicksand::Error::UnexpectedEndOfCode)) => {
+            fail(format!("Unexpected end of the CODE section in {file:?}"))
+        }
+        Err(morpheus::Error::DisassembleFailure(_)) => {
+            fail(format!("Invalid instruction in {file:?}"))
+        }
+        Err(err) => fail(format!("Failed to disassemble {file:?}: {err:?}")),
+    }
+
+    match output {
+        Some(path) => std::fs::write(&path, listing)
+            .unwrap_or_else(|err| fail(format!("Couldn't write {path:?}: {err}"))),
+        None => print!("{listing}"),
+    }
+}
+
+fn asm(file: &Path, output: Option<PathBuf>) {
+    let source = String::from_utf8(read(file))
+        .unwrap_or_else(|_| fail(format!("{file:?} is not valid UTF-8")));
+
+    let image = match morpheus::assemble(&source) {
+        Ok(image) => image,
+        Err(morpheus::Error::Syntax(err)) => fail(format!("{}:{err}", file.display())),
+        Err(err) => fail(format!("Failed to assemble {file:?}: {err:?}")),
+    };
+
+    let path = output.unwrap_or_else(|| file.with_extension("dream"));
+    let mut dream =
+        File::create(&path).unwrap_or_else(|err| fail(format!("Couldn't create {path:?}: {err}")));
+    if let Err(err) = image.write(&mut dream) {
+        fail(format!("Couldn't write {path:?}: {err:?}"));
+    }
+}
+
+fn info(file: &Path) {
+    let image = read_image(file);
+    let code_start = image.code_offset() + DreamImage::CODE_HEADER_SIZE;
+
+    println!("File:         {}", file.display());
+    println!(
+        "Version:      {} ({})",
+        String::from_utf8_lossy(&image.version.as_bytes()),
+        image.version.as_u32()
+    );
+    println!("Output type:  {:?}", image.output_type);
+    println!(
+        "TEXT section: {:#010X}, {} bytes, {} strings",
+        image.text_offset(),
+        image.text_size(),
+        image.strings.len()
+    );
+    println!(
+        "CODE section: {:#010X}, {} bytes",
+        image.code_offset(),
+        image.code.len()
+    );
+    println!(
+        "Entry point:  {} ({:#010X})",
+        image.entry_point,
+        code_start + image.entry_point
+    );
+}
+
+fn debug(file: &Path, debug_heap: bool, stack_size: usize) {
+    let image = read_image(file);
+
+    let dvm = vm::VM::builder()
+        .stack_size(stack_size)
+        .debug_heap(debug_heap)
+        .build();
+    let mut debugger = debugger::Debugger::new(dvm, &image);
+    let stdin = std::io::stdin();
+    if let Err(err) = debugger.repl(stdin.lock(), &mut std::io::stdout()) {
+        fail(err);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::sys::{FileID, OpenFlags, STDOUT};
+    use quicksand::abi::Syscall;
+    use quicksand::Register;
+
+    use crate::sys::{self, FileID, OpenFlags, STDOUT};
     use crate::syscalls::*;
     use crate::vm::*;
 
+    /// Copies `bytes` into the data segment and returns their guest address.
+    fn put(dvm: &mut VM, offset: usize, bytes: &[u8]) -> u64 {
+        dvm.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        DATA_BASE + offset as u64
+    }
+
     #[test]
     fn file_operations() {
         let mut dvm = VM::default();
         let dvm = &mut dvm;
 
         {
-            dvm.reg.rsi = Syscall::Write as u16;
-            dvm.reg.rs[0] = STDOUT;
-
             let msg = "Hello from the dream machine\n";
             let bytes = msg.as_bytes();
 
-            dvm.reg.rs[1] = bytes.as_ptr() as u64;
-            dvm.reg.rs[2] = bytes.len() as u64;
+            dvm.reg.r.set(Register::RSI, Syscall::Write as u64);
+            dvm.reg.r.set(Register::RS0, STDOUT);
+            let addr = put(dvm, 0, bytes);
+            dvm.reg.r.set(Register::RS1, addr);
+            dvm.reg.r.set(Register::RS2, bytes.len() as u64);
 
-            syscall3(dvm);
+            syscall(dvm, 0, 3).unwrap();
         }
 
         {
             let path = "tests/test.txt";
             let path_bytes = path.as_bytes();
 
-            dvm.reg.rsi = Syscall::Open as u16;
-            dvm.reg.rs[0] = path_bytes.as_ptr() as u64;
-            dvm.reg.rs[1] = path_bytes.len() as u64;
-            dvm.reg.rs[2] = (OpenFlags::Create | OpenFlags::Write) as u64;
-            syscall3(dvm);
+            dvm.reg.r.set(Register::RSI, Syscall::Open as u64);
+            let addr = put(dvm, 0, path_bytes);
+            dvm.reg.r.set(Register::RS0, addr);
+            dvm.reg.r.set(Register::RS1, path_bytes.len() as u64);
+            dvm.reg.r.set(Register::RS2, (OpenFlags::CREATE | OpenFlags::WRITE).0);
+            syscall(dvm, 0, 3).unwrap();
 
-            let fid: FileID = dvm.reg.rsr;
+            let fid: FileID = dvm.reg.r.get(Register::RSR);
 
             let msg = "Hello, test.txt!\nThis was done using Dream Machine syscalls.\n";
             let msg_bytes = msg.as_bytes();
 
-            dvm.reg.rsi = Syscall::Write as u16;
-            dvm.reg.rs[0] = fid;
-            dvm.reg.rs[1] = msg_bytes.as_ptr() as u64;
-            dvm.reg.rs[2] = msg_bytes.len() as u64;
-            syscall3(dvm);
+            dvm.reg.r.set(Register::RSI, Syscall::Write as u64);
+            dvm.reg.r.set(Register::RS0, fid);
+            let addr = put(dvm, 0, msg_bytes);
+            dvm.reg.r.set(Register::RS1, addr);
+            dvm.reg.r.set(Register::RS2, msg_bytes.len() as u64);
+            syscall(dvm, 0, 3).unwrap();
 
-            dvm.reg.rsi = Syscall::Close as u16;
-            syscall1(dvm);
+            dvm.reg.r.set(Register::RSI, Syscall::Close as u64);
+            syscall(dvm, 0, 1).unwrap();
         }
 
         {
             let path = "tests/test.txt";
             let path_bytes = path.as_bytes();
 
-            dvm.reg.rsi = Syscall::Open as u16;
-            dvm.reg.rs[0] = path_bytes.as_ptr() as u64;
-            dvm.reg.rs[1] = path_bytes.len() as u64;
-            dvm.reg.rs[2] = OpenFlags::Read as u64;
-            syscall3(dvm);
+            dvm.reg.r.set(Register::RSI, Syscall::Open as u64);
+            let addr = put(dvm, 0, path_bytes);
+            dvm.reg.r.set(Register::RS0, addr);
+            dvm.reg.r.set(Register::RS1, path_bytes.len() as u64);
+            dvm.reg.r.set(Register::RS2, OpenFlags::READ.0);
+            syscall(dvm, 0, 3).unwrap();
 
-            let fid: FileID = dvm.reg.rsr;
+            let fid: FileID = dvm.reg.r.get(Register::RSR);
 
-            let buf = &mut [0u8; 80];
+            dvm.reg.r.set(Register::RSI, Syscall::Read as u64);
+            dvm.reg.r.set(Register::RS0, fid);
+            dvm.reg.r.set(Register::RS1, DATA_BASE);
+            dvm.reg.r.set(Register::RS2, 80);
+            syscall(dvm, 0, 3).unwrap();
 
-            dvm.reg.rsi = Syscall::Read as u16;
-            dvm.reg.rs[0] = fid;
-            dvm.reg.rs[1] = buf.as_mut_ptr() as u64;
-            dvm.reg.rs[2] = buf.len() as u64;
-            syscall3(dvm);
+            let len = dvm.reg.r.get(Register::RSR);
 
-            let len = dvm.reg.rsr;
+            dvm.reg.r.set(Register::RSI, Syscall::Write as u64);
+            dvm.reg.r.set(Register::RS0, STDOUT);
+            dvm.reg.r.set(Register::RS1, DATA_BASE);
+            dvm.reg.r.set(Register::RS2, len);
+            syscall(dvm, 0, 3).unwrap();
 
-            dvm.reg.rsi = Syscall::Write as u16;
-            dvm.reg.rs[0] = STDOUT;
-            dvm.reg.rs[1] = buf.as_ptr() as u64;
-            dvm.reg.rs[2] = len;
-            syscall3(dvm);
+            dvm.reg.r.set(Register::RSI, Syscall::Close as u64);
+            dvm.reg.r.set(Register::RS0, fid);
+            syscall(dvm, 0, 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn host_errors_use_the_guest_numbering() {
+        use std::io::{Error, ErrorKind};
 
-            dvm.reg.rsi = Syscall::Close as u16;
-            dvm.reg.rs[0] = fid;
-            syscall1(dvm);
+        for (kind, errno) in [
+            (ErrorKind::NotFound, sys::ENOENT),
+            (ErrorKind::WouldBlock, sys::EAGAIN),
+            (ErrorKind::BrokenPipe, sys::EPIPE),
+            (ErrorKind::OutOfMemory, sys::ENOMEM),
+            (ErrorKind::Other, sys::EIO),
+        ] {
+            assert_eq!(sys::io::errno(&Error::from(kind)), errno, "{kind:?}");
         }
+        #[cfg(target_family = "unix")]
+        assert_eq!(sys::io::errno(&Error::from_raw_os_error(9)), sys::EBADF);
+    }
+
+    #[test]
+    fn io_errors_are_reported_to_the_guest() {
+        let mut dvm = VM::default();
+
+        let path = "tests/does/not/exist.txt";
+        dvm.reg.r.set(Register::RSI, Syscall::Open as u64);
+        let addr = put(&mut dvm, 0, path.as_bytes());
+        dvm.reg.r.set(Register::RS0, addr);
+        dvm.reg.r.set(Register::RS1, path.len() as u64);
+        dvm.reg.r.set(Register::RS2, OpenFlags::READ.0);
+        syscall(&mut dvm, 0, 3).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::ENOENT));
+
+        dvm.reg.r.set(Register::RS2, 0x100);
+        syscall(&mut dvm, 0, 3).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::EINVAL));
+
+        dvm.reg.r.set(Register::RSI, Syscall::Close as u64);
+        dvm.reg.r.set(Register::RS0, sys::BADFID);
+        syscall(&mut dvm, 0, 1).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::EBADF));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn host_files_are_out_of_reach() {
+        use std::io::Write;
+        use std::os::fd::AsRawFd;
+
+        let mut dvm = VM::default();
+        let mut host_file = std::fs::File::create("tests/host.txt").unwrap();
+        let fid = host_file.as_raw_fd() as FileID + 1;
+
+        let msg = b"Hello from the guest\n";
+        dvm.reg.r.set(Register::RSI, Syscall::Write as u64);
+        dvm.reg.r.set(Register::RS0, fid);
+        let addr = put(&mut dvm, 0, msg);
+        dvm.reg.r.set(Register::RS1, addr);
+        dvm.reg.r.set(Register::RS2, msg.len() as u64);
+        syscall(&mut dvm, 0, 3).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::EBADF));
+
+        dvm.reg.r.set(Register::RSI, Syscall::Close as u64);
+        syscall(&mut dvm, 0, 1).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::EBADF));
+
+        // The host's file is still open.
+        host_file.write_all(b"Hello from the host\n").unwrap();
+        assert_eq!(
+            std::fs::read("tests/host.txt").unwrap(),
+            b"Hello from the host\n"
+        );
+    }
+
+    #[test]
+    fn closed_files_are_forgotten() {
+        let mut dvm = VM::default();
+
+        let path = "tests/closed.txt";
+        dvm.reg.r.set(Register::RSI, Syscall::Open as u64);
+        let addr = put(&mut dvm, 0, path.as_bytes());
+        dvm.reg.r.set(Register::RS0, addr);
+        dvm.reg.r.set(Register::RS1, path.len() as u64);
+        dvm.reg.r.set(Register::RS2, (OpenFlags::CREATE | OpenFlags::WRITE).0);
+        syscall(&mut dvm, 0, 3).unwrap();
+        let fid: FileID = dvm.reg.r.get(Register::RSR);
+        assert!(dvm.files.contains(&fid));
+
+        dvm.reg.r.set(Register::RSI, Syscall::Close as u64);
+        dvm.reg.r.set(Register::RS0, fid);
+        syscall(&mut dvm, 0, 1).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), 0);
+        syscall(&mut dvm, 0, 1).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::EBADF));
+
+        // Closing a standard stream only takes it away from the guest.
+        dvm.reg.r.set(Register::RS0, STDOUT);
+        syscall(&mut dvm, 0, 1).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), 0);
+        dvm.reg.r.set(Register::RSI, Syscall::Write as u64);
+        let addr = put(&mut dvm, 0, b"x");
+        dvm.reg.r.set(Register::RS1, addr);
+        dvm.reg.r.set(Register::RS2, 1);
+        syscall(&mut dvm, 0, 3).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::EBADF));
+    }
+
+    #[test]
+    fn syscall_buffers_are_bounds_checked() {
+        let mut dvm = VM::default();
+
+        // A host pointer is not a valid guest address.
+        let msg = "Hello from the host\n";
+        dvm.reg.r.set(Register::RSI, Syscall::Write as u64);
+        dvm.reg.r.set(Register::RS0, STDOUT);
+        dvm.reg.r.set(Register::RS1, msg.as_ptr() as u64);
+        dvm.reg.r.set(Register::RS2, msg.len() as u64);
+        syscall(&mut dvm, 0, 3).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::EFAULT));
+
+        // Neither is one that runs off the end of a segment.
+        dvm.reg.r.set(Register::RS1, DATA_BASE + dvm.data.len() as u64 - 4);
+        syscall(&mut dvm, 0, 3).unwrap();
+        assert_eq!(dvm.reg.r.get(Register::RSR), sys::error_code(sys::EFAULT));
     }
-}
 
+    #[test]
+    fn bad_syscalls_are_vm_errors() {
+        let mut dvm = VM::default();
+
+        dvm.reg.r.set(Register::RSI, 0xFFFF);
+        assert!(matches!(
+            syscall(&mut dvm, 7, 0),
+            Err(VMError::UnknownSyscall { pc: 7, number: 0xFFFF })
+        ));
+
+        dvm.reg.r.set(Register::RSI, Syscall::Close as u64);
+        assert!(matches!(
+            syscall(&mut dvm, 3, 2),
+            Err(VMError::WrongSyscallArity { pc: 3, expected: 1, found: 2, .. })
+        ));
+    }
+}
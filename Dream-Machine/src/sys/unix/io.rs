@@ -1,70 +1,72 @@
-use crate::sys::{FileID, OpenFlags};
+use crate::sys::{errno_from_kind, FileID, OpenFlags, BADFID, EBADF};
 use std::{
     fs::{File, OpenOptions},
-    io::{Read, Write},
+    io::{self, Read, Write},
     mem::ManuallyDrop,
     os::{
-        fd::{AsRawFd, RawFd},
+        fd::{IntoRawFd, RawFd},
         unix::io::FromRawFd,
     },
 };
 
-fn to_raw_fd(fid: FileID) -> RawFd {
-    fid.wrapping_sub(1) as RawFd
+const HOST_EBADF: i32 = 9;
+
+fn to_raw_fd(fid: FileID) -> io::Result<RawFd> {
+    if fid == BADFID {
+        return Err(io::Error::from_raw_os_error(HOST_EBADF));
+    }
+    RawFd::try_from(fid - 1).map_err(|_| io::Error::from_raw_os_error(HOST_EBADF))
 }
 
 fn from_raw_fd(fd: RawFd) -> FileID {
     fd.wrapping_add(1) as FileID
 }
 
-pub fn read(fid: FileID, buf: &mut [u8]) -> u64 {
-    let raw_fd = to_raw_fd(fid);
-    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(raw_fd) });
-
-    let n = file
-        .read(buf)
-        .map_err(|err| panic!("Failed to read from file: {fid}: {err}."))
-        .unwrap();
-
-    n as u64
+/// Borrows the file behind `fid` without taking ownership of it. Callers only
+/// pass fids from `VM::files`, which are open for as long as they are there.
+fn borrow_file(fid: FileID) -> io::Result<ManuallyDrop<File>> {
+    let raw_fd = to_raw_fd(fid)?;
+    Ok(ManuallyDrop::new(unsafe { File::from_raw_fd(raw_fd) }))
 }
 
-pub fn write(fid: FileID, bytes_to_write: &[u8]) {
-    let raw_fd = to_raw_fd(fid);
-    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(raw_fd) });
-
-    match file.write(bytes_to_write) {
-        Ok(bytes_written) => {
-            if bytes_written != bytes_to_write.len() {
-                panic!("Failed to write all bytes to file: {fid}");
-            }
-        }
-        Err(err) => panic!("Failed to write to file: {fid}: {err}"),
+/// The host's errno values differ between Unixes, e.g. EAGAIN is 11 on Linux
+/// and 35 on macOS, so they go through the error kind. EBADF has no kind of
+/// its own but is 9 on every Unix.
+pub fn errno(err: &io::Error) -> u64 {
+    match err.raw_os_error() {
+        Some(HOST_EBADF) => EBADF,
+        _ => errno_from_kind(err.kind()),
     }
 }
 
-pub fn open(path: &str, flags: OpenFlags) -> FileID {
-    let file = ManuallyDrop::new(
-        OpenOptions::new()
-            .create_new((flags & OpenFlags::CreateNew) == OpenFlags::CreateNew)
-            .create((flags & OpenFlags::Create) == OpenFlags::Create)
-            .read((flags & OpenFlags::Read) == OpenFlags::Read)
-            .write((flags & OpenFlags::Write) == OpenFlags::Write)
-            .append((flags & OpenFlags::Append) == OpenFlags::Append)
-            .truncate((flags & OpenFlags::Truncate) == OpenFlags::Truncate)
-            .open(path)
-            .map_err(|err| panic!("Cannot open file: {path}: {err}."))
-            .unwrap(),
-    );
+pub fn read(fid: FileID, buf: &mut [u8]) -> io::Result<u64> {
+    let mut file = borrow_file(fid)?;
+    let n = file.read(buf)?;
+    Ok(n as u64)
+}
+
+pub fn write(fid: FileID, bytes_to_write: &[u8]) -> io::Result<u64> {
+    let mut file = borrow_file(fid)?;
+    file.write_all(bytes_to_write)?;
+    Ok(bytes_to_write.len() as u64)
+}
 
-    let raw_fd = file.as_raw_fd();
-    let fid = from_raw_fd(raw_fd);
+pub fn open(path: &str, flags: OpenFlags) -> io::Result<FileID> {
+    let file = OpenOptions::new()
+        .create_new(flags.contains(OpenFlags::CREATE_NEW))
+        .create(flags.contains(OpenFlags::CREATE))
+        .read(flags.contains(OpenFlags::READ))
+        .write(flags.contains(OpenFlags::WRITE))
+        .append(flags.contains(OpenFlags::APPEND))
+        .truncate(flags.contains(OpenFlags::TRUNCATE))
+        .open(path)?;
 
-    fid
+    Ok(from_raw_fd(file.into_raw_fd()))
 }
 
-pub fn close(fid: FileID) {
-    let raw_fd = to_raw_fd(fid);
-    let file = unsafe { File::from_raw_fd(raw_fd) };
-    drop(file);
+/// Closes a file returned by `open`, which the guest owns.
+pub fn close(fid: FileID) -> io::Result<()> {
+    let file = borrow_file(fid)?;
+    drop(ManuallyDrop::into_inner(file));
+    Ok(())
 }
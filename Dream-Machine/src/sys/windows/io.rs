@@ -1,9 +1,9 @@
-use crate::sys::{FileID, OpenFlags, BADFID, STDERR, STDIN, STDOUT};
+use crate::sys::{errno_from_kind, FileID, OpenFlags, BADFID, EBADF, STDERR, STDIN, STDOUT};
 
-use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
+use std::os::windows::io::{FromRawHandle, IntoRawHandle, RawHandle};
 use std::{
     fs::{File, OpenOptions},
-    io::{Read, Write},
+    io::{self, Read, Write},
     mem::ManuallyDrop,
 };
 
@@ -12,14 +12,18 @@ use winapi::um::{
     winbase::{STD_ERROR_HANDLE, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE},
 };
 
-fn to_raw_handle(fid: FileID) -> RawHandle {
-    match fid {
-        BADFID => 0 as RawHandle,
+// winerror.h
+const ERROR_INVALID_HANDLE: i32 = 6;
+
+fn to_raw_handle(fid: FileID) -> io::Result<RawHandle> {
+    let raw_handle = match fid {
+        BADFID => return Err(io::Error::from_raw_os_error(ERROR_INVALID_HANDLE)),
         STDIN => unsafe { GetStdHandle(STD_INPUT_HANDLE) as RawHandle },
         STDOUT => unsafe { GetStdHandle(STD_OUTPUT_HANDLE) as RawHandle },
         STDERR => unsafe { GetStdHandle(STD_ERROR_HANDLE) as RawHandle },
         _ => fid as RawHandle,
-    }
+    };
+    Ok(raw_handle)
 }
 
 fn from_raw_handle(raw_handle: RawHandle) -> FileID {
@@ -38,54 +42,50 @@ fn from_raw_handle(raw_handle: RawHandle) -> FileID {
     }
 }
 
-pub fn read(fid: FileID, buf: &mut [u8]) -> u64 {
-    let raw_handle = to_raw_handle(fid);
-    let mut file = ManuallyDrop::new(unsafe { File::from_raw_handle(raw_handle) });
-
-    let n = file
-        .read(buf)
-        .map_err(|err| panic!("Failed to read from file: {fid}: {err}."))
-        .unwrap();
-
-    n as u64
+/// Borrows the file behind `fid` without taking ownership of it. Callers only
+/// pass fids from `VM::files`, which are open for as long as they are there.
+fn borrow_file(fid: FileID) -> io::Result<ManuallyDrop<File>> {
+    let raw_handle = to_raw_handle(fid)?;
+    Ok(ManuallyDrop::new(unsafe { File::from_raw_handle(raw_handle) }))
 }
 
-pub fn write(fid: FileID, bytes_to_write: &[u8]) {
-    let raw_handle = to_raw_handle(fid);
-    let mut file = ManuallyDrop::new(unsafe { File::from_raw_handle(raw_handle) });
-
-    match file.write(bytes_to_write) {
-        Ok(bytes_written) => {
-            if bytes_written != bytes_to_write.len() {
-                panic!("Failed to write all bytes to file: {fid}");
-            }
-        }
-        Err(err) => panic!("Failed to write to file: {fid}: {err}"),
+/// Windows error codes don't line up with errno, so apart from invalid
+/// handles only the error kind is used.
+pub fn errno(err: &io::Error) -> u64 {
+    match err.raw_os_error() {
+        Some(ERROR_INVALID_HANDLE) => EBADF,
+        _ => errno_from_kind(err.kind()),
     }
 }
 
-pub fn open(path: &str, flags: OpenFlags) -> FileID {
-    let file = ManuallyDrop::new(
-        OpenOptions::new()
-            .create_new((flags & OpenFlags::CreateNew) == OpenFlags::CreateNew)
-            .create((flags & OpenFlags::Create) == OpenFlags::Create)
-            .read((flags & OpenFlags::Read) == OpenFlags::Read)
-            .write((flags & OpenFlags::Write) == OpenFlags::Write)
-            .append((flags & OpenFlags::Append) == OpenFlags::Append)
-            .truncate((flags & OpenFlags::Truncate) == OpenFlags::Truncate)
-            .open(path)
-            .map_err(|err| panic!("Cannot open file: {path}: {err}."))
-            .unwrap(),
-    );
+pub fn read(fid: FileID, buf: &mut [u8]) -> io::Result<u64> {
+    let mut file = borrow_file(fid)?;
+    let n = file.read(buf)?;
+    Ok(n as u64)
+}
+
+pub fn write(fid: FileID, bytes_to_write: &[u8]) -> io::Result<u64> {
+    let mut file = borrow_file(fid)?;
+    file.write_all(bytes_to_write)?;
+    Ok(bytes_to_write.len() as u64)
+}
 
-    let raw_handle = file.as_raw_handle();
-    let fid = from_raw_handle(raw_handle);
+pub fn open(path: &str, flags: OpenFlags) -> io::Result<FileID> {
+    let file = OpenOptions::new()
+        .create_new(flags.contains(OpenFlags::CREATE_NEW))
+        .create(flags.contains(OpenFlags::CREATE))
+        .read(flags.contains(OpenFlags::READ))
+        .write(flags.contains(OpenFlags::WRITE))
+        .append(flags.contains(OpenFlags::APPEND))
+        .truncate(flags.contains(OpenFlags::TRUNCATE))
+        .open(path)?;
 
-    fid
+    Ok(from_raw_handle(file.into_raw_handle()))
 }
 
-pub fn close(fid: FileID) {
-    let raw_handle = to_raw_handle(fid);
-    let file = unsafe { File::from_raw_handle(raw_handle) };
-    drop(file);
+/// Closes a file returned by `open`, which the guest owns.
+pub fn close(fid: FileID) -> io::Result<()> {
+    let file = borrow_file(fid)?;
+    drop(ManuallyDrop::into_inner(file));
+    Ok(())
 }
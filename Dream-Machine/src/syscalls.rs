@@ -1,91 +1,142 @@
 // https://blog.rchapman.org/posts/Linux_System_Call_Table_for_x86_64/
 
-use crate::sys;
-use crate::vm::VM;
+use quicksand::abi::Syscall;
+use quicksand::Register;
 
-#[repr(u16)]
-#[derive(Debug)]
-pub enum Syscall {
-    Read = 0,  // fid:FileID, buf:ptr, size:u64 -> num_bytes_read:u64
-    Write = 1, // fid:FileID, buf:ptr, size:u64
-    Open = 2,  // path_ptr:ptr, path_len:u64, flags:FileFlags -> fid:FileID
-    Close = 3, // fid:FileID
-}
+use crate::sys::{self, OpenFlags};
+use crate::vm::{heap_fault, VMError, HEAP_BASE, OFFSET_MASK, VM};
 
-pub fn syscall0(vm: &mut VM) {
-    let syscall: Syscall = unsafe { std::mem::transmute(vm.reg.rsi) };
-    match syscall {
-        _ => panic!("Invalid syscall0: {syscall:?}"),
-    }
-}
+// Syscalls report failures to the guest by leaving a negated errno-style code
+// (see `sys::error_code`) in rsr. Buffers are guest addresses, one that isn't
+// mapped fails with EFAULT. File ids that aren't in `VM::files` fail with
+// EBADF before they reach the host. The numbers and arguments are in
+// `quicksand::abi`.
 
-pub fn syscall1(vm: &mut VM) {
-    let syscall: Syscall = unsafe { std::mem::transmute(vm.reg.rsi) };
-    match syscall {
-        Syscall::Close => {
-            let fid = vm.reg.rs[0] as sys::FileID;
-            sys::io::close(fid);
-        }
-        _ => panic!("Invalid syscall1: {syscall:?}"),
-    }
-}
+/// Performs the syscall selected by rsi, as issued by a `Syscall{nargs}`
+/// instruction at `pc`.
+pub fn syscall(vm: &mut VM, pc: usize, nargs: usize) -> Result<(), VMError> {
+    let number = vm.reg.r.get(Register::RSI) as u16;
+    let syscall =
+        Syscall::try_from(number).map_err(|number| VMError::UnknownSyscall { pc, number })?;
 
-pub fn syscall2(vm: &mut VM) {
-    let syscall: Syscall = unsafe { std::mem::transmute(vm.reg.rsi) };
-    match syscall {
-        _ => panic!("Invalid syscall2: {syscall:?}"),
+    if syscall.arity() != nargs {
+        return Err(VMError::WrongSyscallArity {
+            pc,
+            syscall,
+            expected: syscall.arity(),
+            found: nargs,
+        });
     }
-}
 
-pub fn syscall3(vm: &mut VM) {
-    let syscall: Syscall = unsafe { std::mem::transmute(vm.reg.rsi) };
-    match syscall {
+    let [rs0, rs1, rs2] =
+        [Register::RS0, Register::RS1, Register::RS2].map(|reg| vm.reg.r.get(reg));
+    let result = match syscall {
         Syscall::Read => {
-            let fid = vm.reg.rs[0] as sys::FileID;
-            let buf = unsafe {
-                std::slice::from_raw_parts_mut(vm.reg.rs[1] as *mut u8, vm.reg.rs[2] as usize)
-            };
-            vm.reg.rsr = sys::io::read(fid, buf);
+            let fid = rs0 as sys::FileID;
+            let (addr, size) = (rs1, rs2 as usize);
+            if !vm.files.contains(&fid) {
+                Err(sys::EBADF)
+            } else {
+                match vm.memory_mut(addr, size) {
+                    Ok(buf) => sys::io::read(fid, buf).map_err(|err| sys::io::errno(&err)),
+                    Err(err) => Err(efault(err)?),
+                }
+            }
         }
         Syscall::Write => {
-            let fid = vm.reg.rs[0] as sys::FileID;
-            let bytes_to_write = unsafe {
-                std::slice::from_raw_parts(vm.reg.rs[1] as *const u8, vm.reg.rs[2] as usize)
-            };
-            sys::io::write(fid, bytes_to_write);
+            let fid = rs0 as sys::FileID;
+            if !vm.files.contains(&fid) {
+                Err(sys::EBADF)
+            } else {
+                match vm.memory(rs1, rs2 as usize) {
+                    Ok(bytes_to_write) => {
+                        sys::io::write(fid, bytes_to_write).map_err(|err| sys::io::errno(&err))
+                    }
+                    Err(err) => Err(efault(err)?),
+                }
+            }
         }
         Syscall::Open => {
-            let path_ptr = vm.reg.rs[0] as *const u8;
-            let path_len = vm.reg.rs[1] as usize;
-            let flags = unsafe { std::mem::transmute(vm.reg.rs[2]) };
-
-            let path_slice = unsafe { std::slice::from_raw_parts(path_ptr, path_len) };
-            let path =
-                std::str::from_utf8(path_slice).expect("Non UTF8 data provided for open syscall.");
-
-            vm.reg.rsr = sys::io::open(path, flags);
+            let flags = OpenFlags::from_bits(rs2);
+            match vm.memory(rs0, rs1 as usize) {
+                Ok(path) => match (std::str::from_utf8(path), flags) {
+                    (Ok(path), Some(flags)) => match sys::io::open(path, flags) {
+                        Ok(fid) => {
+                            vm.files.insert(fid);
+                            Ok(fid)
+                        }
+                        Err(err) => Err(sys::io::errno(&err)),
+                    },
+                    _ => Err(sys::EINVAL),
+                },
+                Err(err) => Err(efault(err)?),
+            }
         }
-        _ => panic!("Invalid syscall3: {syscall:?}"),
-    }
-}
+        Syscall::Close => {
+            let fid = rs0 as sys::FileID;
+            if !vm.files.remove(&fid) {
+                Err(sys::EBADF)
+            } else if [sys::STDIN, sys::STDOUT, sys::STDERR].contains(&fid) {
+                // The host still uses its standard streams, closing one only
+                // takes it away from the guest.
+                Ok(0)
+            } else {
+                sys::io::close(fid)
+                    .map(|()| 0)
+                    .map_err(|err| sys::io::errno(&err))
+            }
+        }
+        Syscall::Alloc => {
+            let size = rs0 as usize;
+            vm.heap
+                .alloc(size)
+                .map(|offset| HEAP_BASE + offset as u64)
+                .ok_or(sys::ENOMEM)
+        }
+        Syscall::Realloc => {
+            let (addr, size) = (rs0, rs1 as usize);
+            if addr == 0 {
+                vm.heap
+                    .alloc(size)
+                    .map(|offset| HEAP_BASE + offset as u64)
+                    .ok_or(sys::ENOMEM)
+            } else {
+                vm.heap
+                    .realloc(heap_offset(addr)?, size)
+                    .map_err(|err| heap_fault(err, addr, size))?
+                    .map(|offset| HEAP_BASE + offset as u64)
+                    .ok_or(sys::ENOMEM)
+            }
+        }
+        Syscall::Free => {
+            let addr = rs0;
+            if addr != 0 {
+                vm.heap
+                    .free(heap_offset(addr)?)
+                    .map_err(|err| heap_fault(err, addr, 0))?;
+            }
+            Ok(0)
+        }
+    };
 
-pub fn syscall4(vm: &mut VM) {
-    let syscall: Syscall = unsafe { std::mem::transmute(vm.reg.rsi) };
-    match syscall {
-        _ => panic!("Invalid syscall0: {syscall:?}"),
-    }
+    let result = result.unwrap_or_else(sys::error_code);
+    vm.reg.r.set(Register::RSR, result);
+    Ok(())
 }
 
-pub fn syscall5(vm: &mut VM) {
-    let syscall: Syscall = unsafe { std::mem::transmute(vm.reg.rsi) };
-    match syscall {
-        _ => panic!("Invalid syscall5: {syscall:?}"),
+/// Only pointers returned by Alloc or Realloc may be passed back to the heap.
+fn heap_offset(addr: u64) -> Result<usize, VMError> {
+    if addr & !OFFSET_MASK != HEAP_BASE {
+        return Err(VMError::InvalidFree(addr));
     }
+    Ok((addr & OFFSET_MASK) as usize)
 }
 
-pub fn syscall6(vm: &mut VM) {
-    let syscall: Syscall = unsafe { std::mem::transmute(vm.reg.rsi) };
-    match syscall {
-        _ => panic!("Invalid syscall6: {syscall:?}"),
+/// Unmapped buffers are reported to the guest, anything the heap's debug mode
+/// caught stops the VM.
+fn efault(err: VMError) -> Result<u64, VMError> {
+    match err {
+        VMError::InvalidAddress { .. } => Ok(sys::EFAULT),
+        err => Err(err),
     }
 }
@@ -0,0 +1,317 @@
+use std::io::Write;
+use std::ops::Range;
+
+use quicksand::{Instruction, Register, RegisterType};
+
+use crate::vm::{Registers, VM};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TraceFormat {
+    /// One line per instruction, laid out like the disassembler's listing.
+    Text,
+    /// One JSON object per instruction.
+    Json,
+}
+
+/// Which instructions to trace. Everything is traced by default.
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    /// Only trace instructions at these code offsets.
+    pub range: Option<Range<usize>>,
+    /// Only trace while the procedure starting at this code offset, or one it
+    /// calls, is running.
+    pub procedure: Option<usize>,
+}
+
+/// Writes every executed instruction along with the registers it read and
+/// the registers it changed. Offsets are code offsets, which is what jump
+/// targets and the filters use.
+pub struct Tracer {
+    format: TraceFormat,
+    filter: TraceFilter,
+    out: Box<dyn Write>,
+    /// Call depth at which the filtered procedure was entered.
+    entered_at: Option<usize>,
+    /// Whether the current instruction is traced.
+    tracing: bool,
+    pc: usize,
+    before: Registers,
+    reads: Vec<(Register, u64)>,
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, filter: TraceFilter, out: Box<dyn Write>) -> Self {
+        Self {
+            format,
+            filter,
+            out,
+            entered_at: None,
+            tracing: false,
+            pc: 0,
+            before: Registers::default(),
+            reads: vec![],
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Called before the instruction at `pc` executes.
+    pub(crate) fn begin(&mut self, pc: usize, reg: &Registers, depth: usize, code: &[u8]) {
+        if let Some(procedure) = self.filter.procedure {
+            match self.entered_at {
+                Some(entered_at) if depth < entered_at => self.entered_at = None,
+                None if pc == procedure => self.entered_at = Some(depth),
+                _ => {}
+            }
+        }
+
+        let in_range = self
+            .filter
+            .range
+            .as_ref()
+            .is_none_or(|range| range.contains(&pc));
+        let in_procedure = self.filter.procedure.is_none() || self.entered_at.is_some();
+        self.tracing = in_range && in_procedure;
+        if !self.tracing {
+            return;
+        }
+
+        self.pc = pc;
+        self.before = reg.clone();
+        self.reads.clear();
+
+        // Syscalls read their registers directly.
+        let nargs = code
+            .get(pc)
+            .map(|&inst| inst.wrapping_sub(Instruction::Syscall0 as u8))
+            .filter(|&nargs| nargs <= Instruction::Syscall6 as u8 - Instruction::Syscall0 as u8);
+        if let Some(nargs) = nargs {
+            self.read(Register::RSI, reg.r.get(Register::RSI));
+            for x in 0..nargs {
+                let rs = Register::new(RegisterType::S, x).unwrap();
+                self.read(rs, reg.r.get(rs));
+            }
+        }
+    }
+
+    pub(crate) fn read(&mut self, reg: Register, value: u64) {
+        if self.tracing && !self.reads.iter().any(|&(read, _)| read == reg) {
+            self.reads.push((reg, value));
+        }
+    }
+
+    /// Called after the instruction executed, whether or not it failed.
+    pub(crate) fn end(&mut self, vm: &VM) -> std::io::Result<()> {
+        if !self.tracing {
+            return Ok(());
+        }
+
+        let inst = match morpheus::disassemble_instruction(&vm.code, self.pc) {
+            Ok((inst, _)) => inst,
+            Err(_) => "???".to_string(),
+        };
+        let mut writes: Vec<(String, u64)> = vm
+            .reg
+            .r
+            .iter()
+            .zip(self.before.r.iter())
+            .filter(|((_, after), (_, before))| after != before)
+            .map(|((reg, value), _)| (reg.to_string(), value))
+            .collect();
+        if vm.reg.flags != self.before.flags {
+            writes.push(("flags".to_string(), vm.reg.flags as u64));
+        }
+
+        match self.format {
+            TraceFormat::Text => self.write_text(&inst, &writes),
+            TraceFormat::Json => self.write_json(&inst, &writes),
+        }
+    }
+
+    fn write_text(&mut self, inst: &str, writes: &[(String, u64)]) -> std::io::Result<()> {
+        let effects: Vec<String> = self
+            .reads
+            .iter()
+            .map(|(reg, value)| format!("{reg} = {value}"))
+            .chain(
+                writes
+                    .iter()
+                    .map(|(reg, value)| format!("{reg} <- {value}")),
+            )
+            .collect();
+
+        if effects.is_empty() {
+            writeln!(self.out, "{:08X}      {inst}", self.pc)
+        } else {
+            let line = format!("{:08X}      {inst}", self.pc);
+            writeln!(self.out, "{line:<47} ; {}", effects.join(", "))
+        }
+    }
+
+    fn write_json(&mut self, inst: &str, writes: &[(String, u64)]) -> std::io::Result<()> {
+        let object = |pairs: &mut dyn Iterator<Item = (String, u64)>| {
+            let fields: Vec<String> = pairs
+                .map(|(reg, value)| format!("\"{reg}\":{value}"))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        };
+
+        // Collapse the padding between the mnemonic and its operands.
+        let inst = match inst.split_once(' ') {
+            Some((mnemonic, operands)) => format!("{mnemonic} {}", operands.trim_start()),
+            None => inst.to_string(),
+        };
+        let reads = object(
+            &mut self
+                .reads
+                .iter()
+                .map(|(reg, value)| (reg.to_string(), *value)),
+        );
+        let writes = object(&mut writes.iter().cloned());
+        writeln!(
+            self.out,
+            "{{\"pc\":{},\"inst\":\"{}\",\"reads\":{reads},\"writes\":{writes}}}",
+            self.pc,
+            json_escape(&inst)
+        )
+    }
+}
+
+/// Escapes `s` for a JSON string. Unlike `str::escape_default` this leaves
+/// `'` and non-ASCII characters alone and writes control characters as
+/// `\u00XX`.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use morpheus::{Builder, Operand, OutputType, Version};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn q(x: u8) -> Register {
+        Register::new(RegisterType::Q, x).unwrap()
+    }
+
+    /// Runs a program whose entry procedure at offset 5 calls a procedure at
+    /// offset 0 that adds rq1 to rq0.
+    fn trace(format: TraceFormat, filter: TraceFilter) -> String {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let add = builder
+            .procedure(|proc| proc.body(|block| block.emit_add(q(0), Operand::reg(q(1)))))
+            .unwrap();
+        let entry = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_move_imm(Operand::reg(q(1)), 2)?;
+                    block.emit_call(add);
+                    block.emit_cmp(q(0), Operand::lit64(2))?;
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+
+        let buffer = Buffer::default();
+        let mut vm = VM::default();
+        vm.load_image(&builder.link().unwrap());
+        vm.trace = Some(Tracer::new(format, filter, Box::new(buffer.clone())));
+        vm.run().unwrap();
+
+        let out = buffer.0.borrow();
+        String::from_utf8(out.clone()).unwrap()
+    }
+
+    #[test]
+    fn text() {
+        let expected = [
+            "00000005      MoveImm     rq1, $2               ; rq1 <- 2",
+            "0000000F      Call        $0",
+            "00000000      Add         rq0, rq1              ; rq1 = 2, rq0 = 0, rq0 <- 2",
+            "00000004      Ret",
+            "00000018      Cmp         rq0, $2               ; rq0 = 2, flags <- 1",
+            "00000023      Ret",
+        ];
+        assert_eq!(
+            trace(TraceFormat::Text, TraceFilter::default()),
+            expected.join("\n") + "\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let out = trace(TraceFormat::Json, TraceFilter::default());
+        assert_eq!(
+            out.lines().nth(2).unwrap(),
+            r#"{"pc":0,"inst":"Add rq0, rq1","reads":{"rq1":2,"rq0":0},"writes":{"rq0":2}}"#
+        );
+        assert_eq!(
+            out.lines().last().unwrap(),
+            r#"{"pc":35,"inst":"Ret","reads":{},"writes":{}}"#
+        );
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_escape("Add rq0, rq1"), "Add rq0, rq1");
+        assert_eq!(json_escape(r#"say "hi" \ bye"#), r#"say \"hi\" \\ bye"#);
+        assert_eq!(json_escape("a\nb\t\u{1}"), r"a\u000Ab\u0009\u0001");
+        assert_eq!(json_escape("naïve '"), "naïve '");
+    }
+
+    #[test]
+    fn filters() {
+        let procedure = TraceFilter {
+            procedure: Some(0),
+            ..TraceFilter::default()
+        };
+        let out = trace(TraceFormat::Text, procedure);
+        let offsets: Vec<&str> = out.lines().map(|line| &line[..8]).collect();
+        assert_eq!(offsets, ["00000000", "00000004"]);
+
+        let range = TraceFilter {
+            range: Some(0x0F..0x19),
+            ..TraceFilter::default()
+        };
+        let out = trace(TraceFormat::Text, range);
+        let offsets: Vec<&str> = out.lines().map(|line| &line[..8]).collect();
+        assert_eq!(offsets, ["0000000F", "00000018"]);
+    }
+}
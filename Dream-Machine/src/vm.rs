@@ -1,24 +1,135 @@
-const STACK_SIZE: usize = 4 * 1024;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+use morpheus::DreamImage;
+use quicksand::abi::Syscall;
+use quicksand::{DecodedOperand, Instruction, Register, RegisterType, SyscallRegisterPrefix};
+
+use crate::heap::{Heap, HeapError};
+use crate::sys::{FileID, STDERR, STDIN, STDOUT};
+use crate::syscalls;
+use crate::trace::Tracer;
+
+// The guest memory layout is part of the ABI shared with the compilers.
+pub(crate) use quicksand::abi::OFFSET_MASK;
+pub use quicksand::abi::{DATA_BASE, HEAP_BASE, STACK_BASE, TEXT_BASE};
+
+pub const DEFAULT_STACK_SIZE: usize = 4 * 1024;
 const NUM_RSX_REGISTERS: usize = 6;
 const NUM_REGISTERS_PER_SIZE: usize = 32;
+const MAX_CALL_DEPTH: usize = 1024;
+const DATA_SIZE: usize = 64 * 1024;
+
+/// Holds the program's exit code when the entry procedure returns.
+const EXIT_CODE: Register = match Register::new(RegisterType::Q, 0) {
+    Ok(reg) => reg,
+    Err(_) => unreachable!(),
+};
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct VM {
     pub reg: Registers,
-    pub stack: Stack<STACK_SIZE>,
+    pub stack: Stack,
+    pub text: Vec<u8>,
+    /// Offset into `text` and length of every string, sorted by offset.
+    pub strings: Vec<(usize, usize)>,
+    pub data: Vec<u8>,
+    pub heap: Heap,
+    /// The file ids the guest can use: the standard ones and those it opened
+    /// and hasn't closed. Syscalls fail with EBADF for any other id, so the
+    /// guest can't reach the host's own files.
+    pub files: BTreeSet<FileID>,
+    pub code: Vec<u8>,
+    pub pc: usize,
+    pub frames: Vec<Frame>,
+    pub trace: Option<Tracer>,
+}
+
+impl std::default::Default for VM {
+    fn default() -> Self {
+        VMBuilder::default().build()
+    }
+}
+
+/// Configures a VM before creating it, see `VM::builder`.
+#[derive(Clone, Copy, Debug)]
+pub struct VMBuilder {
+    stack_size: usize,
+    debug_heap: bool,
+}
+
+impl std::default::Default for VMBuilder {
+    fn default() -> Self {
+        Self {
+            stack_size: DEFAULT_STACK_SIZE,
+            debug_heap: false,
+        }
+    }
 }
 
-#[derive(Debug, Default)]
+impl VMBuilder {
+    /// Sets the number of bytes the stack can hold.
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = bytes;
+        self
+    }
+
+    /// Detects double frees and use after free of heap memory.
+    pub fn debug_heap(mut self, debug: bool) -> Self {
+        self.debug_heap = debug;
+        self
+    }
+
+    pub fn build(self) -> VM {
+        let mut heap = Heap::default();
+        heap.debug = self.debug_heap;
+
+        VM {
+            reg: Registers::default(),
+            stack: Stack::new(self.stack_size),
+            text: vec![],
+            strings: vec![],
+            data: vec![0; DATA_SIZE],
+            heap,
+            files: BTreeSet::from([STDIN, STDOUT, STDERR]),
+            code: vec![],
+            pc: 0,
+            frames: vec![],
+            trace: None,
+        }
+    }
+}
+
+/// Bookkeeping for a procedure call that has not returned yet.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    /// Offset of the instruction after the call.
+    pub return_pc: usize,
+    /// Stack depth on entry to the procedure. Everything the procedure pushes
+    /// above this is discarded when it returns.
+    pub fp: usize,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Registers {
-    pub z: u8,
-    pub rsi: u16,
-    pub rsr: u64,
-    pub rs: [u64; NUM_RSX_REGISTERS],
+    pub flags: u8,
     pub r: General,
 }
 
-#[repr(packed)]
+impl Registers {
+    pub const ZERO_FLAG: u8 = 0x01; // Operands were equal or the result was zero.
+    pub const LESS_FLAG: u8 = 0x02; // Left operand was less than the right (signed).
+    pub const BELOW_FLAG: u8 = 0x04; // Left operand was less than the right (unsigned).
+    pub const UNORDERED_FLAG: u8 = 0x08; // A float comparison involved NaN.
+}
+
+/// Every register a program can name. Use `get` and `set` to access them.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
 pub struct General {
+    rsi: u16,
+    rsr: u64,
+    rs: [u64; NUM_RSX_REGISTERS],
     b: [u8; NUM_REGISTERS_PER_SIZE],
     w: [u16; NUM_REGISTERS_PER_SIZE],
     d: [u32; NUM_REGISTERS_PER_SIZE],
@@ -28,6 +139,9 @@ pub struct General {
 impl std::default::Default for General {
     fn default() -> Self {
         Self {
+            rsi: 0,
+            rsr: 0,
+            rs: [0; NUM_RSX_REGISTERS],
             b: [0; NUM_REGISTERS_PER_SIZE],
             w: [0; NUM_REGISTERS_PER_SIZE],
             d: [0; NUM_REGISTERS_PER_SIZE],
@@ -36,61 +150,161 @@ impl std::default::Default for General {
     }
 }
 
+impl General {
+    /// Returns the value of `reg`, zero-extended. RXZ always reads as zero.
+    pub fn get(&self, reg: Register) -> u64 {
+        if reg.is_x() {
+            0
+        } else if reg == Register::RSI {
+            self.rsi as u64
+        } else if reg == Register::RSR {
+            self.rsr
+        } else if reg.is_rsx() {
+            self.rs[rsx_index(reg)]
+        } else if reg.is_b() {
+            self.b[general_index(reg)] as u64
+        } else if reg.is_w() {
+            self.w[general_index(reg)] as u64
+        } else if reg.is_d() {
+            self.d[general_index(reg)] as u64
+        } else {
+            self.q[general_index(reg)]
+        }
+    }
+
+    /// Sets `reg` to `value` truncated to the register's width. Writes to RXZ
+    /// are discarded.
+    pub fn set(&mut self, reg: Register, value: u64) {
+        if reg.is_x() {
+            // Writes to the Z register are discarded.
+        } else if reg == Register::RSI {
+            self.rsi = value as u16;
+        } else if reg == Register::RSR {
+            self.rsr = value;
+        } else if reg.is_rsx() {
+            self.rs[rsx_index(reg)] = value;
+        } else if reg.is_b() {
+            self.b[general_index(reg)] = value as u8;
+        } else if reg.is_w() {
+            self.w[general_index(reg)] = value as u16;
+        } else if reg.is_d() {
+            self.d[general_index(reg)] = value as u32;
+        } else {
+            self.q[general_index(reg)] = value;
+        }
+    }
+
+    /// Iterates over every register a program can change along with its
+    /// value: RSI, RSR, RS0-RS5, then the B, W, D and Q registers in order.
+    pub fn iter(&self) -> impl Iterator<Item = (Register, u64)> + '_ {
+        let rsx = (0..NUM_RSX_REGISTERS as u8).map(|x| Register::new(RegisterType::S, x).unwrap());
+        let general = [
+            RegisterType::B,
+            RegisterType::W,
+            RegisterType::D,
+            RegisterType::Q,
+        ]
+        .into_iter()
+        .flat_map(|t| (0..Register::MAX).map(move |x| Register::new(t, x).unwrap()));
+
+        [Register::RSI, Register::RSR]
+            .into_iter()
+            .chain(rsx)
+            .chain(general)
+            .map(|reg| (reg, self.get(reg)))
+    }
+}
+
 impl std::fmt::Debug for General {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[...]")
+        let mut map = f.debug_map();
+        for (reg, value) in self.iter().filter(|&(_, value)| value != 0) {
+            map.entry(&format_args!("{reg}"), &value);
+        }
+        map.finish()
+    }
+}
+
+/// Lists the registers that are not zero, one per line.
+impl std::fmt::Display for General {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (reg, value) in self.iter().filter(|&(_, value)| value != 0) {
+            writeln!(f, "{:<5} = {value} ({value:#X})", reg.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+fn general_index(reg: Register) -> usize {
+    (reg.to_u8() & !RegisterType::MASK) as usize
+}
+
+fn rsx_index(reg: Register) -> usize {
+    (reg.to_u8() & !(RegisterType::MASK | SyscallRegisterPrefix::MASK)) as usize
+}
+
+/// A push or pop that doesn't fit, along with the stack depth at the time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackError {
+    Overflow { depth: usize },
+    Underflow { depth: usize },
+}
+
+impl StackError {
+    /// Turns the error into a `VMError` for the instruction at `pc`.
+    fn at(self, pc: usize) -> VMError {
+        match self {
+            StackError::Overflow { depth } => VMError::StackOverflow { pc, depth },
+            StackError::Underflow { depth } => VMError::StackUnderflow { pc, depth },
+        }
     }
 }
 
 #[derive(Debug)]
-pub struct Stack<const N: usize> {
+pub struct Stack {
     allocated: usize,
-    bytes: [u8; N],
+    bytes: Vec<u8>,
 }
 
-impl<const N: usize> std::default::Default for Stack<N> {
+impl std::default::Default for Stack {
     fn default() -> Self {
-        Self::new()
+        Self::new(DEFAULT_STACK_SIZE)
     }
 }
 
-impl<const N: usize> Stack<N> {
-    pub fn new() -> Self {
+impl Stack {
+    /// Creates a stack that holds up to `size` bytes.
+    pub fn new(size: usize) -> Self {
         Self {
             allocated: 0,
-            bytes: [0; N],
+            bytes: vec![0; size],
         }
     }
 
-    pub fn push<T: Copy>(&mut self, value: T) -> Result<(), VMError> {
+    pub fn push<T: Copy>(&mut self, value: T) -> Result<(), StackError> {
         let ptr = &value as *const T as *const () as *const u8;
         let bytes = unsafe { std::slice::from_raw_parts(ptr, std::mem::size_of::<T>()) };
-        self.push_bytes(bytes)?;
-        Ok(())
+        self.push_bytes(bytes)
     }
 
-    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<(), VMError> {
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<(), StackError> {
         let begin = self.allocated;
         let end = begin + bytes.len();
 
-        if end >= N {
-            return Err(VMError::StackOverflow);
+        if end > self.bytes.len() {
+            return Err(StackError::Overflow { depth: begin });
         }
 
         self.bytes[begin..end].copy_from_slice(bytes);
-        self.allocated += bytes.len();
+        self.allocated = end;
         Ok(())
     }
 
-    pub fn pop<T: Copy>(&mut self) -> Result<T, VMError> {
-        let bytes = self.pop_bytes(std::mem::size_of::<T>())?;
-        let ptr = bytes.as_ptr() as *const T;
-        Ok(unsafe { *ptr })
-    }
-
-    pub fn pop_bytes(&mut self, n: usize) -> Result<&[u8], VMError> {
-        if n >= self.allocated {
-            return Err(VMError::StackUnderflow);
+    pub fn pop_bytes(&mut self, n: usize) -> Result<&[u8], StackError> {
+        if n > self.allocated {
+            return Err(StackError::Underflow {
+                depth: self.allocated,
+            });
         }
 
         let end = self.allocated;
@@ -101,9 +315,1473 @@ impl<const N: usize> Stack<N> {
 
         Ok(bytes)
     }
+
+    pub fn depth(&self) -> usize {
+        self.allocated
+    }
+
+    /// Grows the stack by `n` zeroed bytes.
+    pub fn reserve(&mut self, n: usize) -> Result<(), StackError> {
+        let begin = self.allocated;
+        let end = match begin.checked_add(n) {
+            Some(end) if end <= self.bytes.len() => end,
+            _ => return Err(StackError::Overflow { depth: begin }),
+        };
+
+        self.bytes[begin..end].fill(0);
+        self.allocated = end;
+        Ok(())
+    }
+
+    /// Discards the top `n` bytes.
+    pub fn release(&mut self, n: usize) -> Result<(), StackError> {
+        self.pop_bytes(n).map(|_| ())
+    }
+
+    /// Returns the number of bytes the stack can hold.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Discards everything above `depth`.
+    pub fn truncate(&mut self, depth: usize) {
+        self.allocated = self.allocated.min(depth);
+    }
+
+    /// Reads `n` bytes starting at `offset` from the base of the stack.
+    pub fn load_bytes(&self, offset: usize, n: usize) -> Result<&[u8], VMError> {
+        match offset.checked_add(n) {
+            Some(end) if end <= self.allocated => Ok(&self.bytes[offset..end]),
+            _ => Err(VMError::StackOutOfBounds),
+        }
+    }
+
+    pub fn load_bytes_mut(&mut self, offset: usize, n: usize) -> Result<&mut [u8], VMError> {
+        match offset.checked_add(n) {
+            Some(end) if end <= self.allocated => Ok(&mut self.bytes[offset..end]),
+            _ => Err(VMError::StackOutOfBounds),
+        }
+    }
 }
 
+#[derive(Debug)]
 pub enum VMError {
-    StackOverflow,
-    StackUnderflow,
+    StackOverflow {
+        pc: usize,
+        depth: usize,
+    },
+    StackUnderflow {
+        pc: usize,
+        depth: usize,
+    },
+    StackOutOfBounds,
+    InvalidDreamFile(morpheus::Error),
+    InvalidInstruction,
+    InvalidRegister,
+    UnexpectedEndOfCode,
+    DivisionByZero,
+    CallStackOverflow,
+    InvalidAddress {
+        addr: u64,
+        size: usize,
+    },
+    InvalidStringOffset(u64),
+    UseAfterFree {
+        addr: u64,
+        size: usize,
+    },
+    InvalidFree(u64),
+    DoubleFree(u64),
+    UnknownSyscall {
+        pc: usize,
+        number: u16,
+    },
+    WrongSyscallArity {
+        pc: usize,
+        syscall: Syscall,
+        expected: usize,
+        found: usize,
+    },
+    TraceFailed(std::io::Error),
+}
+
+impl std::fmt::Display for VMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VMError::StackOverflow { pc, depth } => {
+                write!(f, "stack overflow at {pc:08X} with {depth} bytes on the stack")
+            }
+            VMError::StackUnderflow { pc, depth } => {
+                write!(f, "stack underflow at {pc:08X} with {depth} bytes on the stack")
+            }
+            VMError::StackOutOfBounds => write!(f, "stack access out of bounds"),
+            VMError::InvalidDreamFile(err) => write!(f, "invalid dream file: {err:?}"),
+            VMError::InvalidInstruction => write!(f, "invalid instruction"),
+            VMError::InvalidRegister => write!(f, "invalid register"),
+            VMError::UnexpectedEndOfCode => write!(f, "unexpected end of code"),
+            VMError::DivisionByZero => write!(f, "division by zero"),
+            VMError::CallStackOverflow => write!(f, "call stack overflow"),
+            VMError::InvalidAddress { addr, size } => {
+                write!(f, "invalid access of {size} bytes at address {addr:#X}")
+            }
+            VMError::UseAfterFree { addr, size } => {
+                write!(f, "access of {size} bytes at address {addr:#X} after it was freed")
+            }
+            VMError::InvalidFree(addr) => {
+                write!(f, "freeing address {addr:#X} which was not allocated")
+            }
+            VMError::DoubleFree(addr) => write!(f, "address {addr:#X} freed twice"),
+            VMError::InvalidStringOffset(offset) => {
+                write!(f, "no string starts at offset {offset} of the TEXT section")
+            }
+            VMError::UnknownSyscall { pc, number } => {
+                write!(f, "unknown syscall {number} at {pc:08X}")
+            }
+            VMError::WrongSyscallArity {
+                pc,
+                syscall,
+                expected,
+                found,
+            } => write!(
+                f,
+                "syscall {syscall:?} takes {expected} arguments but was issued with {found} at {pc:08X}"
+            ),
+            VMError::TraceFailed(err) => write!(f, "failed to write the trace: {err}"),
+        }
+    }
+}
+
+impl VM {
+    pub fn builder() -> VMBuilder {
+        VMBuilder::default()
+    }
+
+    /// Loads a dream file as written by `morpheus::Builder::write_dream` and
+    /// points the program counter at its entry point.
+    pub fn load(&mut self, dream: &[u8]) -> Result<(), VMError> {
+        let image = DreamImage::parse(dream).map_err(VMError::InvalidDreamFile)?;
+        self.load_image(&image);
+        Ok(())
+    }
+
+    pub fn load_image(&mut self, image: &DreamImage) {
+        self.text = image.text();
+        self.strings = image
+            .string_offsets()
+            .zip(image.strings.iter().map(|s| s.len()))
+            .collect();
+        self.code = image.code.clone();
+        self.data.fill(0);
+        self.heap.reset();
+        self.pc = image.entry_point;
+        self.frames.clear();
+    }
+
+    /// Resolves `size` bytes of guest memory starting at `addr`.
+    pub fn memory(&self, addr: u64, size: usize) -> Result<&[u8], VMError> {
+        let fault = VMError::InvalidAddress { addr, size };
+        let offset = (addr & OFFSET_MASK) as usize;
+        let region = match addr & !OFFSET_MASK {
+            TEXT_BASE => &self.text[..],
+            DATA_BASE => &self.data[..],
+            STACK_BASE => return self.stack.load_bytes(offset, size).map_err(|_| fault),
+            HEAP_BASE => {
+                return self
+                    .heap
+                    .bytes(offset, size)
+                    .map_err(|err| heap_fault(err, addr, size))
+            }
+            _ => return Err(fault),
+        };
+        match offset.checked_add(size) {
+            Some(end) if end <= region.len() => Ok(&region[offset..end]),
+            _ => Err(fault),
+        }
+    }
+
+    /// Like `memory` but for writing, which excludes the TEXT segment.
+    pub fn memory_mut(&mut self, addr: u64, size: usize) -> Result<&mut [u8], VMError> {
+        let fault = VMError::InvalidAddress { addr, size };
+        let offset = (addr & OFFSET_MASK) as usize;
+        let region = match addr & !OFFSET_MASK {
+            DATA_BASE => &mut self.data[..],
+            STACK_BASE => return self.stack.load_bytes_mut(offset, size).map_err(|_| fault),
+            HEAP_BASE => {
+                return self
+                    .heap
+                    .bytes_mut(offset, size)
+                    .map_err(|err| heap_fault(err, addr, size))
+            }
+            _ => return Err(fault),
+        };
+        match offset.checked_add(size) {
+            Some(end) if end <= region.len() => Ok(&mut region[offset..end]),
+            _ => Err(fault),
+        }
+    }
+
+    fn load_memory(&self, addr: u64, size: usize) -> Result<u64, VMError> {
+        Ok(le_u64(self.memory(addr, size)?))
+    }
+
+    fn store_memory(&mut self, addr: u64, value: u64, size: usize) -> Result<(), VMError> {
+        self.memory_mut(addr, size)?
+            .copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+
+    /// Executes instructions from the current program counter until the
+    /// entry procedure returns, producing the exit code left in rq0.
+    pub fn run(&mut self) -> Result<u64, VMError> {
+        loop {
+            if let Some(exit_code) = self.step()? {
+                return Ok(exit_code);
+            }
+        }
+    }
+
+    /// Executes the instruction at the program counter, producing the exit
+    /// code if it returned from the entry procedure.
+    pub fn step(&mut self) -> Result<Option<u64>, VMError> {
+        let Some(trace) = &mut self.trace else {
+            return self.execute();
+        };
+
+        trace.begin(self.pc, &self.reg, self.frames.len(), &self.code);
+        let result = self.execute();
+        if let Some(mut trace) = self.trace.take() {
+            let traced = trace.end(self);
+            self.trace = Some(trace);
+            traced.map_err(VMError::TraceFailed)?;
+        }
+        result
+    }
+
+    /// Reads a register, letting the tracer know about it.
+    fn read(&mut self, reg: Register) -> u64 {
+        let value = self.reg.r.get(reg);
+        if let Some(trace) = &mut self.trace {
+            trace.read(reg, value);
+        }
+        value
+    }
+
+    fn execute(&mut self) -> Result<Option<u64>, VMError> {
+        use DecodedOperand as Op;
+
+        let inst_pc = self.pc;
+        let code = self.code.get(inst_pc..).unwrap_or_default();
+        let (decoded, size) = quicksand::decode(code).map_err(|err| match err {
+            quicksand::Error::UnexpectedEndOfCode => VMError::UnexpectedEndOfCode,
+            quicksand::Error::InvalidRegister => VMError::InvalidRegister,
+            _ => VMError::InvalidInstruction,
+        })?;
+        self.pc += size;
+
+        let inst = decoded.inst;
+        match (inst, decoded.operands()) {
+            (Instruction::NoOp, []) => {}
+            (Instruction::Move, &[Op::Address(dst), Op::Register(src)]) => {
+                let value = self.read(src);
+                self.store_memory(dst, value, src.width())?;
+            }
+            (Instruction::Move, &[Op::Register(dst), Op::Register(src)]) => {
+                let value = self.read(src);
+                self.reg.r.set(dst, value);
+            }
+            (Instruction::MoveImm, &[Op::Address(dst), Op::Lit64(value)]) => {
+                self.store_memory(dst, value, std::mem::size_of::<u64>())?;
+            }
+            (Instruction::MoveImm, &[Op::Register(dst), Op::Lit64(value)]) => {
+                self.reg.r.set(dst, value);
+            }
+            (Instruction::MoveAddr, &[Op::Address(dst), Op::Address(src), Op::Lit64(size)]) => {
+                let size = size as usize;
+                let bytes = self.memory(src, size)?.to_vec();
+                self.memory_mut(dst, size)?.copy_from_slice(&bytes);
+            }
+            (Instruction::MoveAddr, &[Op::Register(dst), Op::Address(src)]) => {
+                self.reg.r.set(dst, self.load_memory(src, dst.width())?);
+            }
+            (Instruction::MoveSx, &[Op::Register(dst), src, ref size @ ..]) => {
+                let (value, size) = match (src, size) {
+                    (Op::Address(src), &[Op::Lit64(size)]) => {
+                        let size = size as usize;
+                        if !matches!(size, 1 | 2 | 4 | 8) || size > dst.width() {
+                            return Err(VMError::InvalidInstruction);
+                        }
+                        (self.load_memory(src, size)?, size)
+                    }
+                    (Op::Register(src), []) => {
+                        if src.width() > dst.width() {
+                            return Err(VMError::InvalidInstruction);
+                        }
+                        (self.read(src), src.width())
+                    }
+                    _ => return Err(VMError::InvalidInstruction),
+                };
+                self.reg
+                    .r
+                    .set(dst, sign_extend(value, size as u32 * 8) as u64);
+            }
+            (Instruction::Clear, &[Op::Address(dst)]) => {
+                self.store_memory(dst, 0, std::mem::size_of::<u64>())?;
+            }
+            (Instruction::Clear, &[Op::Register(reg)]) => self.reg.r.set(reg, 0),
+            (Instruction::Set, &[Op::Address(dst)]) => {
+                self.store_memory(dst, 1, std::mem::size_of::<u64>())?;
+            }
+            (Instruction::Set, &[Op::Register(reg)]) => self.reg.r.set(reg, 1),
+            (Instruction::Push, &[Op::Address(src)]) => {
+                let value = self.load_memory(src, std::mem::size_of::<u64>())?;
+                self.stack.push(value).map_err(|err| err.at(inst_pc))?;
+            }
+            (Instruction::Push, &[Op::Register(src)]) => {
+                let bytes = self.read(src).to_le_bytes();
+                self.stack
+                    .push_bytes(&bytes[..src.width()])
+                    .map_err(|err| err.at(inst_pc))?;
+            }
+            (Instruction::PushImm, &[Op::Lit64(value)]) => {
+                self.stack.push(value).map_err(|err| err.at(inst_pc))?;
+            }
+            (Instruction::Pop, &[Op::Register(dst)]) => {
+                let bytes = self.stack.pop_bytes(dst.width());
+                let value = le_u64(bytes.map_err(|err| err.at(inst_pc))?);
+                self.reg.r.set(dst, value);
+            }
+            (Instruction::StackLoad, &[Op::Register(dst), slot]) => {
+                let offset = self.stack_offset(slot)?;
+                let value = le_u64(self.stack.load_bytes(offset, dst.width())?);
+                self.reg.r.set(dst, value);
+            }
+            (Instruction::StackStore, &[Op::Register(src), slot]) => {
+                let offset = self.stack_offset(slot)?;
+                let bytes = self.read(src).to_le_bytes();
+                self.stack
+                    .load_bytes_mut(offset, src.width())?
+                    .copy_from_slice(&bytes[..src.width()]);
+            }
+            (Instruction::Reserve, &[Op::Lit64(size)]) => {
+                self.stack
+                    .reserve(size as usize)
+                    .map_err(|err| err.at(inst_pc))?;
+            }
+            (Instruction::Release, &[Op::Lit64(size)]) => {
+                self.stack
+                    .release(size as usize)
+                    .map_err(|err| err.at(inst_pc))?;
+            }
+            (Instruction::Map, &[Op::Register(dst), Op::Lit64(offset)]) => {
+                // The address needs 64 bits and the length goes in the next
+                // register.
+                let len_reg = dst
+                    .next()
+                    .filter(|_| dst.is_q() || dst.is_rsx())
+                    .ok_or(VMError::InvalidRegister)?;
+                let len = self
+                    .strings
+                    .binary_search_by_key(&offset, |&(start, _)| start as u64)
+                    .map(|i| self.strings[i].1)
+                    .map_err(|_| VMError::InvalidStringOffset(offset))?;
+                self.reg.r.set(dst, TEXT_BASE + offset);
+                self.reg.r.set(len_reg, len as u64);
+            }
+            (Instruction::Syscall0, []) => syscalls::syscall(self, inst_pc, 0)?,
+            (Instruction::Syscall1, []) => syscalls::syscall(self, inst_pc, 1)?,
+            (Instruction::Syscall2, []) => syscalls::syscall(self, inst_pc, 2)?,
+            (Instruction::Syscall3, []) => syscalls::syscall(self, inst_pc, 3)?,
+            (Instruction::Syscall4, []) => syscalls::syscall(self, inst_pc, 4)?,
+            (Instruction::Syscall5, []) => syscalls::syscall(self, inst_pc, 5)?,
+            (Instruction::Syscall6, []) => syscalls::syscall(self, inst_pc, 6)?,
+            (Instruction::Ret, []) => match self.frames.pop() {
+                Some(frame) => {
+                    self.stack.truncate(frame.fp);
+                    self.pc = frame.return_pc;
+                }
+                None => return Ok(Some(self.reg.r.get(EXIT_CODE))),
+            },
+            (Instruction::Call, &[target]) => {
+                let target = match target {
+                    Op::Register(reg) if reg.is_q() => self.read(reg) as usize,
+                    Op::Lit64(target) => target as usize,
+                    _ => return Err(VMError::InvalidRegister),
+                };
+
+                if self.frames.len() >= MAX_CALL_DEPTH {
+                    return Err(VMError::CallStackOverflow);
+                }
+
+                self.frames.push(Frame {
+                    return_pc: self.pc,
+                    fp: self.stack.depth(),
+                });
+                self.pc = target;
+            }
+            (
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::IDiv
+                | Instruction::Mod
+                | Instruction::IMod
+                | Instruction::And
+                | Instruction::Or
+                | Instruction::Xor
+                | Instruction::Shl
+                | Instruction::Shr
+                | Instruction::Sar,
+                &[Op::Register(dst), src],
+            ) => self.execute_binary(inst, dst, src)?,
+            (Instruction::Cmp | Instruction::Test, &[Op::Register(lhs), rhs]) => {
+                self.execute_compare(inst, lhs, rhs)?
+            }
+            (
+                Instruction::FAdd
+                | Instruction::FSub
+                | Instruction::FMul
+                | Instruction::FDiv
+                | Instruction::FCmp,
+                &[Op::Register(dst), src],
+            ) => self.execute_float(inst, dst, src)?,
+            (Instruction::FNeg, &[Op::Register(reg)]) => {
+                if !reg.is_float() {
+                    return Err(VMError::InvalidRegister);
+                }
+                // Flipping the sign bit negates NaNs and zeroes too.
+                let value = self.read(reg);
+                self.reg.r.set(reg, value ^ (1 << (reg.width() * 8 - 1)));
+            }
+            (
+                Instruction::IToF | Instruction::FToI | Instruction::FToF,
+                &[Op::Register(dst), Op::Register(src)],
+            ) => self.execute_conversion(inst, dst, src)?,
+            (inst, &[Op::Lit64(target)]) if inst.is_jump() => {
+                if self.condition(inst) {
+                    self.pc = target as usize;
+                }
+            }
+            (Instruction::Not, &[Op::Register(reg)]) => {
+                let value = self.read(reg);
+                self.reg.r.set(reg, !value);
+            }
+            (Instruction::Neg, &[Op::Register(reg)]) => {
+                let value = self.read(reg);
+                self.reg.r.set(reg, value.wrapping_neg());
+            }
+            _ => unreachable!("{decoded:?} doesn't match the operands of {inst:?}"),
+        }
+
+        Ok(None)
+    }
+
+    /// Reads the value of a register or literal operand.
+    fn value(&mut self, operand: DecodedOperand) -> u64 {
+        match operand {
+            DecodedOperand::Register(reg) => self.read(reg),
+            DecodedOperand::Lit64(value) => value,
+            _ => unreachable!("{operand:?} is not a value"),
+        }
+    }
+
+    /// Executes `inst dst, src` at the width of `dst`. Results wrap on
+    /// overflow, shift amounts are taken modulo the width in bits and
+    /// dividing by zero is an error.
+    fn execute_binary(
+        &mut self,
+        inst: Instruction,
+        dst: Register,
+        src: DecodedOperand,
+    ) -> Result<(), VMError> {
+        let src = self.value(src);
+
+        let bits = dst.width() as u32 * 8;
+        let lhs = truncate(self.read(dst), bits);
+        let rhs = truncate(src, bits);
+
+        let result = match inst {
+            Instruction::Add => lhs.wrapping_add(rhs),
+            Instruction::Sub => lhs.wrapping_sub(rhs),
+            Instruction::Mul => lhs.wrapping_mul(rhs),
+            Instruction::Div => lhs.checked_div(rhs).ok_or(VMError::DivisionByZero)?,
+            Instruction::Mod => lhs.checked_rem(rhs).ok_or(VMError::DivisionByZero)?,
+            Instruction::IDiv | Instruction::IMod => {
+                if rhs == 0 {
+                    return Err(VMError::DivisionByZero);
+                }
+                let lhs = sign_extend(lhs, bits);
+                let rhs = sign_extend(rhs, bits);
+                if inst == Instruction::IDiv {
+                    lhs.wrapping_div(rhs) as u64
+                } else {
+                    lhs.wrapping_rem(rhs) as u64
+                }
+            }
+            Instruction::And => lhs & rhs,
+            Instruction::Or => lhs | rhs,
+            Instruction::Xor => lhs ^ rhs,
+            Instruction::Shl => lhs << (rhs % bits as u64),
+            Instruction::Shr => lhs >> (rhs % bits as u64),
+            Instruction::Sar => (sign_extend(lhs, bits) >> (rhs % bits as u64)) as u64,
+            _ => unreachable!("{inst:?} is not a binary operation"),
+        };
+
+        self.reg.r.set(dst, result);
+        Ok(())
+    }
+
+    /// Resolves a stack slot operand, an offset from the base of the stack or
+    /// a signed one from the frame pointer, to an offset from the base.
+    fn stack_offset(&self, slot: DecodedOperand) -> Result<usize, VMError> {
+        let offset = match slot {
+            DecodedOperand::Stack(offset) => return Ok(offset as usize),
+            DecodedOperand::Frame(offset) => offset,
+            _ => unreachable!("{slot:?} is not a stack slot"),
+        };
+
+        let fp = self.frames.last().map_or(0, |frame| frame.fp);
+        (fp as i64)
+            .checked_add(offset)
+            .and_then(|offset| usize::try_from(offset).ok())
+            .ok_or(VMError::StackOutOfBounds)
+    }
+
+    /// Sets the flags from comparing (`Cmp`) or and-ing (`Test`) a register
+    /// with a value at the width of the register.
+    fn execute_compare(
+        &mut self,
+        inst: Instruction,
+        lhs: Register,
+        rhs: DecodedOperand,
+    ) -> Result<(), VMError> {
+        let rhs = self.value(rhs);
+
+        let bits = lhs.width() as u32 * 8;
+        let lhs = truncate(self.read(lhs), bits);
+        let rhs = truncate(rhs, bits);
+
+        let mut flags = 0;
+        if inst == Instruction::Cmp {
+            if lhs == rhs {
+                flags |= Registers::ZERO_FLAG;
+            }
+            if sign_extend(lhs, bits) < sign_extend(rhs, bits) {
+                flags |= Registers::LESS_FLAG;
+            }
+            if lhs < rhs {
+                flags |= Registers::BELOW_FLAG;
+            }
+        } else {
+            let result = lhs & rhs;
+            if result == 0 {
+                flags |= Registers::ZERO_FLAG;
+            }
+            if sign_extend(result, bits) < 0 {
+                flags |= Registers::LESS_FLAG;
+            }
+        }
+
+        self.reg.flags = flags;
+        Ok(())
+    }
+
+    fn execute_float(
+        &mut self,
+        inst: Instruction,
+        dst: Register,
+        src: DecodedOperand,
+    ) -> Result<(), VMError> {
+        if matches!(src, DecodedOperand::Register(src) if src.width() != dst.width()) {
+            return Err(VMError::InvalidRegister);
+        }
+        let src = self.value(src);
+        if !dst.is_float() {
+            return Err(VMError::InvalidRegister);
+        }
+
+        let lhs = Float::from_bits(dst, self.read(dst));
+        let rhs = Float::from_bits(dst, src);
+        if inst == Instruction::FCmp {
+            self.reg.flags = match lhs.partial_cmp(&rhs) {
+                None => Registers::UNORDERED_FLAG,
+                Some(Ordering::Equal) => Registers::ZERO_FLAG,
+                Some(Ordering::Less) => Registers::LESS_FLAG | Registers::BELOW_FLAG,
+                Some(Ordering::Greater) => 0,
+            };
+            return Ok(());
+        }
+
+        let result = match (inst, lhs, rhs) {
+            (Instruction::FAdd, Float::F32(l), Float::F32(r)) => Float::F32(l + r),
+            (Instruction::FAdd, Float::F64(l), Float::F64(r)) => Float::F64(l + r),
+            (Instruction::FSub, Float::F32(l), Float::F32(r)) => Float::F32(l - r),
+            (Instruction::FSub, Float::F64(l), Float::F64(r)) => Float::F64(l - r),
+            (Instruction::FMul, Float::F32(l), Float::F32(r)) => Float::F32(l * r),
+            (Instruction::FMul, Float::F64(l), Float::F64(r)) => Float::F64(l * r),
+            (Instruction::FDiv, Float::F32(l), Float::F32(r)) => Float::F32(l / r),
+            (Instruction::FDiv, Float::F64(l), Float::F64(r)) => Float::F64(l / r),
+            _ => unreachable!("{inst:?} is not a float operation"),
+        };
+        self.reg.r.set(dst, result.to_bits());
+        Ok(())
+    }
+
+    fn execute_conversion(
+        &mut self,
+        inst: Instruction,
+        dst: Register,
+        src: Register,
+    ) -> Result<(), VMError> {
+        let value = self.read(src);
+
+        let result = match inst {
+            Instruction::IToF if dst.is_float() && !src.is_x() => {
+                let value = sign_extend(value, src.width() as u32 * 8);
+                Float::from_i64(dst, value).to_bits()
+            }
+            Instruction::FToI if src.is_float() && !dst.is_x() => {
+                // `as` rounds toward zero, saturates and turns NaN into zero.
+                let value = match Float::from_bits(src, value) {
+                    Float::F32(value) => value as f64,
+                    Float::F64(value) => value,
+                };
+                match dst.width() {
+                    1 => value as i8 as u64,
+                    2 => value as i16 as u64,
+                    4 => value as i32 as u64,
+                    _ => value as i64 as u64,
+                }
+            }
+            Instruction::FToF if dst.is_float() && src.is_float() => {
+                match (dst.is_d(), Float::from_bits(src, value)) {
+                    (true, Float::F64(value)) => Float::F32(value as f32).to_bits(),
+                    (false, Float::F32(value)) => Float::F64(value as f64).to_bits(),
+                    (_, value) => value.to_bits(),
+                }
+            }
+            _ => return Err(VMError::InvalidRegister),
+        };
+        self.reg.r.set(dst, result);
+        Ok(())
+    }
+
+    /// Evaluates the condition of a jump instruction against the flags.
+    fn condition(&self, inst: Instruction) -> bool {
+        let zero = self.reg.flags & Registers::ZERO_FLAG != 0;
+        let less = self.reg.flags & Registers::LESS_FLAG != 0;
+        let below = self.reg.flags & Registers::BELOW_FLAG != 0;
+        let ordered = self.reg.flags & Registers::UNORDERED_FLAG == 0;
+        match inst {
+            Instruction::Jmp => true,
+            Instruction::Jz => zero,
+            Instruction::Jnz => !zero,
+            Instruction::Jlt => less,
+            Instruction::Jle => less || zero,
+            Instruction::Jgt => ordered && !(less || zero),
+            Instruction::Jge => ordered && !less,
+            Instruction::Jb => below,
+            Instruction::Jbe => below || zero,
+            Instruction::Ja => ordered && !(below || zero),
+            Instruction::Jae => ordered && !below,
+            _ => unreachable!("{inst:?} is not a jump"),
+        }
+    }
+}
+
+/// The contents of a D or Q register viewed as a float.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Float {
+    F32(f32),
+    F64(f64),
+}
+
+impl Float {
+    fn from_bits(reg: Register, bits: u64) -> Self {
+        if reg.is_d() {
+            Float::F32(f32::from_bits(bits as u32))
+        } else {
+            Float::F64(f64::from_bits(bits))
+        }
+    }
+
+    fn from_i64(reg: Register, value: i64) -> Self {
+        if reg.is_d() {
+            Float::F32(value as f32)
+        } else {
+            Float::F64(value as f64)
+        }
+    }
+
+    fn to_bits(self) -> u64 {
+        match self {
+            Float::F32(value) => value.to_bits() as u64,
+            Float::F64(value) => value.to_bits(),
+        }
+    }
+}
+
+fn truncate(value: u64, bits: u32) -> u64 {
+    if bits >= u64::BITS {
+        value
+    } else {
+        value & ((1 << bits) - 1)
+    }
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = u64::BITS - bits;
+    ((value << shift) as i64) >> shift
+}
+
+pub(crate) fn heap_fault(err: HeapError, addr: u64, size: usize) -> VMError {
+    match err {
+        HeapError::OutOfBounds => VMError::InvalidAddress { addr, size },
+        HeapError::UseAfterFree => VMError::UseAfterFree { addr, size },
+        HeapError::InvalidFree => VMError::InvalidFree(addr),
+        HeapError::DoubleFree => VMError::DoubleFree(addr),
+    }
+}
+
+/// Zero-extends up to eight little-endian bytes into a `u64`.
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use morpheus::{Builder, Operand, OutputType, ProcId, Version};
+
+    use super::*;
+
+    fn q(x: u8) -> Register {
+        Register::new(RegisterType::Q, x).unwrap()
+    }
+
+    fn build(f: impl FnOnce(&mut morpheus::BlockBuilder) -> morpheus::Result<()>) -> VM {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let entry = builder.procedure(|proc| proc.body(f)).unwrap();
+        builder.set_entry(entry);
+
+        let mut dream = vec![];
+        builder
+            .write_dream(&mut std::io::Cursor::new(&mut dream))
+            .unwrap();
+
+        let mut vm = VM::default();
+        vm.load(&dream).unwrap();
+        vm
+    }
+
+    #[test]
+    fn register_file() {
+        let mut r = General::default();
+        let b3 = Register::new(RegisterType::B, 3).unwrap();
+        let w3 = Register::new(RegisterType::W, 3).unwrap();
+        let d3 = Register::new(RegisterType::D, 3).unwrap();
+
+        for reg in [b3, w3, d3, q(3)] {
+            r.set(reg, 0x1122_3344_5566_7788);
+        }
+        assert_eq!(r.get(b3), 0x88);
+        assert_eq!(r.get(w3), 0x7788);
+        assert_eq!(r.get(d3), 0x5566_7788);
+        assert_eq!(r.get(q(3)), 0x1122_3344_5566_7788);
+        assert_eq!(r.get(q(2)), 0);
+
+        r.set(Register::RXZ, 5);
+        assert_eq!(r.get(Register::RXZ), 0);
+        r.set(Register::RSI, 0x1_0002);
+        assert_eq!(r.get(Register::RSI), 2);
+        r.set(Register::RSR, u64::MAX);
+        r.set(Register::RS5, 9);
+        assert_eq!(r.get(Register::RSR), u64::MAX);
+        assert_eq!(r.get(Register::RS5), 9);
+        assert_eq!(r.get(Register::RS4), 0);
+
+        r.set(b3, 0);
+        r.set(w3, 0);
+        r.set(d3, 0);
+        r.set(Register::RSR, 0);
+        assert_eq!(
+            r.to_string(),
+            "rsi   = 2 (0x2)\nrs5   = 9 (0x9)\nrq3   = 1234605616436508552 (0x1122334455667788)\n"
+        );
+        assert_eq!(
+            format!("{r:?}"),
+            "{rsi: 2, rs5: 9, rq3: 1234605616436508552}"
+        );
+    }
+
+    #[test]
+    fn moves_and_stack() {
+        let mut vm = build(|block| {
+            block.emit_move(Operand::reg(q(0)), Operand::lit64(10), None)?;
+            block.emit_push(Operand::reg(q(0)));
+            block.emit_push(Operand::lit64(0x1234));
+            block.emit_stack_load(q(1), 0);
+            block.emit_stack_load(q(2), 8);
+            block.emit_move(
+                Operand::reg(Register::new(RegisterType::B, 0).unwrap()),
+                Operand::reg(q(2)),
+                None,
+            )?;
+            block.emit_set(q(3));
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg.r.get(q(1)), 10);
+        assert_eq!(vm.reg.r.get(q(2)), 0x1234);
+        assert_eq!(
+            vm.reg.r.get(Register::new(RegisterType::B, 0).unwrap()),
+            0x34
+        );
+        assert_eq!(vm.reg.r.get(q(3)), 1);
+        assert_eq!(vm.stack.allocated, 16);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let b0 = Register::new(RegisterType::B, 0).unwrap();
+        let mut vm = build(|block| {
+            block.emit_move(Operand::reg(q(0)), Operand::lit64(7), None)?;
+            block.emit_mul(q(0), Operand::lit64(6))?;
+            block.emit_move(Operand::reg(q(1)), Operand::reg(q(0)), None)?;
+            block.emit_sub(q(1), Operand::lit64(50))?;
+            block.emit_move(Operand::reg(q(2)), Operand::reg(q(1)), None)?;
+            block.emit_idiv(q(2), Operand::lit64(3))?;
+            block.emit_move(Operand::reg(q(3)), Operand::reg(q(1)), None)?;
+            block.emit_div(q(3), Operand::lit64(1 << 62))?;
+            block.emit_move(Operand::reg(q(4)), Operand::reg(q(1)), None)?;
+            block.emit_imod(q(4), Operand::lit64(3))?;
+            block.emit_move(Operand::reg(b0), Operand::lit64(0xF0), None)?;
+            block.emit_add(b0, Operand::lit64(0x20))?;
+            block.emit_neg(q(0));
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg.r.get(q(0)) as i64, -42);
+        assert_eq!(vm.reg.r.get(q(1)) as i64, -8);
+        assert_eq!(vm.reg.r.get(q(2)) as i64, -2);
+        assert_eq!(vm.reg.r.get(q(3)), 3);
+        assert_eq!(vm.reg.r.get(q(4)) as i64, -2);
+        assert_eq!(vm.reg.r.get(b0), 0x10);
+    }
+
+    #[test]
+    fn bitwise() {
+        let b0 = Register::new(RegisterType::B, 0).unwrap();
+        let b1 = Register::new(RegisterType::B, 1).unwrap();
+        let mut vm = build(|block| {
+            block.emit_move(Operand::reg(q(0)), Operand::lit64(0b1100), None)?;
+            block.emit_move(Operand::reg(q(1)), Operand::lit64(0b1010), None)?;
+            block.emit_move(Operand::reg(q(2)), Operand::reg(q(0)), None)?;
+            block.emit_and(q(2), Operand::reg(q(1)))?;
+            block.emit_move(Operand::reg(q(3)), Operand::reg(q(0)), None)?;
+            block.emit_or(q(3), Operand::reg(q(1)))?;
+            block.emit_xor(q(0), Operand::reg(q(1)))?;
+            block.emit_not(q(1));
+            block.emit_move(Operand::reg(b0), Operand::lit64(0x81), None)?;
+            block.emit_sar(b0, Operand::lit64(1))?;
+            block.emit_move(Operand::reg(b1), Operand::lit64(0x81), None)?;
+            block.emit_shr(b1, Operand::lit64(9))?;
+            block.emit_shl(q(4), Operand::lit64(3))?;
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg.r.get(q(0)), 0b0110);
+        assert_eq!(vm.reg.r.get(q(1)), !0b1010);
+        assert_eq!(vm.reg.r.get(q(2)), 0b1000);
+        assert_eq!(vm.reg.r.get(q(3)), 0b1110);
+        assert_eq!(vm.reg.r.get(b0), 0xC0);
+        assert_eq!(vm.reg.r.get(b1), 0x40);
+        assert_eq!(vm.reg.r.get(q(4)), 0);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let mut vm = build(|block| {
+            block.emit_move(Operand::reg(q(0)), Operand::lit64(7), None)?;
+            block.emit_div(q(0), Operand::reg(q(1)))?;
+            Ok(())
+        });
+
+        assert!(matches!(vm.run(), Err(VMError::DivisionByZero)));
+    }
+
+    #[test]
+    fn loop_with_jumps() {
+        let mut vm = build(|block| {
+            let top = block.new_label();
+            let end = block.new_label();
+            block.bind_label(top)?;
+            block.emit_cmp(q(0), Operand::lit64(10))?;
+            block.emit_jge(end);
+            block.emit_add(q(0), Operand::lit64(1))?;
+            block.emit_add(q(1), Operand::lit64(2))?;
+            block.emit_jmp(top);
+            block.bind_label(end)?;
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg.r.get(q(0)), 10);
+        assert_eq!(vm.reg.r.get(q(1)), 20);
+    }
+
+    #[test]
+    fn signed_and_unsigned_conditions() {
+        let mut vm = build(|block| {
+            let not_less = block.new_label();
+            let not_below = block.new_label();
+            block.emit_move(Operand::reg(q(0)), Operand::lit64(-1i64 as u64), None)?;
+            block.emit_cmp(q(0), Operand::lit64(1))?;
+            block.emit_jge(not_less);
+            block.emit_set(q(1));
+            block.bind_label(not_less)?;
+            block.emit_jb(not_below);
+            block.emit_set(q(2));
+            block.bind_label(not_below)?;
+            block.emit_test(q(0), Operand::lit64(0))?;
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg.r.get(q(1)), 1);
+        assert_eq!(vm.reg.r.get(q(2)), 1);
+        assert_eq!(vm.reg.flags, Registers::ZERO_FLAG);
+    }
+
+    fn build_procs(f: impl FnOnce(&mut Builder) -> ProcId) -> VM {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let entry = f(&mut builder);
+        builder.set_entry(entry);
+
+        let mut vm = VM::default();
+        vm.load(&builder.link().unwrap().to_bytes()).unwrap();
+        vm
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let mut vm = build_procs(|builder| {
+            // square(rq0) -> rq0
+            let square = builder
+                .procedure(|proc| {
+                    proc.body(|block| {
+                        block.emit_push(Operand::lit64(0xDEAD));
+                        block.emit_mul(q(0), Operand::reg(q(0)))?;
+                        Ok(())
+                    })
+                })
+                .unwrap();
+
+            builder
+                .procedure(|proc| {
+                    proc.body(|block| {
+                        block.emit_move(Operand::reg(q(0)), Operand::lit64(3), None)?;
+                        block.emit_call(square);
+                        block.emit_proc_addr(q(5), square)?;
+                        block.emit_call_indirect(q(5))?;
+                        Ok(())
+                    })
+                })
+                .unwrap()
+        });
+
+        assert_eq!(vm.run().unwrap(), 81);
+        assert!(vm.frames.is_empty());
+        assert_eq!(vm.stack.depth(), 0);
+    }
+
+    #[test]
+    fn stack_frames() {
+        let mut vm = build_procs(|builder| {
+            // fact(n on the stack) -> rq0
+            let fact = builder.declare_procedure();
+            builder
+                .define_procedure(fact, |proc| {
+                    proc.body(|block| {
+                        let done = block.new_label();
+                        block.emit_frame_load(q(0), -8);
+                        block.emit_cmp(q(0), Operand::lit64(1))?;
+                        block.emit_jbe(done);
+                        block.emit_reserve(8);
+                        block.emit_frame_store(0, q(0));
+                        block.emit_sub(q(0), Operand::lit64(1))?;
+                        block.emit_push(Operand::reg(q(0)));
+                        block.emit_call(fact);
+                        block.emit_release(8);
+                        block.emit_frame_load(q(1), 0);
+                        block.emit_mul(q(0), Operand::reg(q(1)))?;
+                        block.bind_label(done)?;
+                        Ok(())
+                    })
+                })
+                .unwrap();
+            builder
+                .procedure(|proc| {
+                    proc.body(|block| {
+                        block.emit_reserve(8);
+                        block.emit_push(Operand::lit64(5));
+                        block.emit_call(fact);
+                        block.emit_release(8);
+                        block.emit_stack_store(0, q(0));
+                        block.emit_stack_load(q(2), 0);
+                        Ok(())
+                    })
+                })
+                .unwrap()
+        });
+
+        assert_eq!(vm.run().unwrap(), 120);
+        assert_eq!(vm.reg.r.get(q(2)), 120);
+        assert_eq!(vm.stack.depth(), 8);
+    }
+
+    #[test]
+    fn stack_frame_bounds() {
+        let mut vm = build(|block| {
+            block.emit_frame_load(q(0), -8);
+            Ok(())
+        });
+        assert!(matches!(vm.run(), Err(VMError::StackOutOfBounds)));
+
+        let mut vm = build(|block| {
+            block.emit_reserve(4);
+            block.emit_stack_store(0, q(0));
+            Ok(())
+        });
+        assert!(matches!(vm.run(), Err(VMError::StackOutOfBounds)));
+
+        let mut vm = build(|block| {
+            block.emit_reserve(4);
+            block.emit_release(8);
+            Ok(())
+        });
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::StackUnderflow { pc: 9, depth: 4 })
+        ));
+    }
+
+    #[test]
+    fn recursion_is_bounded() {
+        let mut vm = build_procs(|builder| {
+            let forever = builder.declare_procedure();
+            builder
+                .define_procedure(forever, |proc| {
+                    proc.body(|block| {
+                        block.emit_call(forever);
+                        Ok(())
+                    })
+                })
+                .unwrap();
+            forever
+        });
+
+        assert!(matches!(vm.run(), Err(VMError::CallStackOverflow)));
+    }
+
+    #[test]
+    fn width_conversions() {
+        let b = |x| Register::new(RegisterType::B, x).unwrap();
+        let w = |x| Register::new(RegisterType::W, x).unwrap();
+        let mut vm = build(|block| {
+            block.emit_move_imm(Operand::reg(b(0)), 0xF0)?;
+            block.emit_move_imm(Operand::reg(q(0)), 0x1_FFFF)?;
+            // Widening zero-extends, narrowing truncates.
+            block.emit_move(Operand::reg(q(1)), Operand::reg(b(0)), None)?;
+            block.emit_move(Operand::reg(w(0)), Operand::reg(q(0)), None)?;
+            block.emit_move_sx(q(2), b(0))?;
+            block.emit_move_sx(q(3), w(0))?;
+            // Stores write the source's width, loads read the destination's.
+            block.emit_move_imm(Operand::addr(DATA_BASE), u64::MAX)?;
+            block.emit_move(Operand::addr(DATA_BASE), Operand::reg(b(0)), None)?;
+            block.emit_move(Operand::reg(w(1)), Operand::addr(DATA_BASE), None)?;
+            block.emit_load_sx(q(4), DATA_BASE, 1)?;
+            block.emit_load_sx(q(5), DATA_BASE, 2)?;
+            Ok(())
+        });
+
+        vm.run().unwrap();
+        assert_eq!(vm.reg.r.get(q(1)), 0xF0);
+        assert_eq!(vm.reg.r.get(w(0)), 0xFFFF);
+        assert_eq!(vm.reg.r.get(q(2)) as i64, -0x10);
+        assert_eq!(vm.reg.r.get(q(3)) as i64, -1);
+        assert_eq!(vm.reg.r.get(w(1)), 0xFFF0);
+        assert_eq!(vm.reg.r.get(q(4)) as i64, -0x10);
+        assert_eq!(vm.reg.r.get(q(5)) as i64, -0x10);
+    }
+
+    #[test]
+    fn floats() {
+        let d = |x| Register::new(RegisterType::D, x).unwrap();
+        let b0 = Register::new(RegisterType::B, 0).unwrap();
+        let mut vm = build(|block| {
+            block.emit_move_imm(Operand::reg(q(0)), 7)?;
+            block.emit_itof(q(1), q(0))?;
+            block.emit_fdiv(q(1), Operand::lit_f64(2.0))?;
+            block.emit_fneg(q(1))?;
+            block.emit_ftoi(q(2), q(1))?;
+            block.emit_ftof(d(0), q(1))?;
+            block.emit_fmul(d(0), Operand::lit_f32(1e30))?;
+            block.emit_fmul(d(0), Operand::lit_f32(1e30))?;
+            block.emit_ftoi(b0, d(0))?;
+            block.emit_move_imm(Operand::reg(q(3)), 0)?;
+            block.emit_fdiv(q(3), Operand::lit_f64(0.0))?;
+            block.emit_fcmp(q(3), Operand::lit_f64(1.0))?;
+            Ok(())
+        });
+
+        vm.run().unwrap();
+        assert_eq!(f64::from_bits(vm.reg.r.get(q(1))), -3.5);
+        assert_eq!(vm.reg.r.get(q(2)) as i64, -3);
+        assert_eq!(f32::from_bits(vm.reg.r.get(d(0)) as u32), f32::NEG_INFINITY);
+        assert_eq!(vm.reg.r.get(b0), 0x80);
+        assert!(f64::from_bits(vm.reg.r.get(q(3))).is_nan());
+        assert_eq!(vm.reg.flags, Registers::UNORDERED_FLAG);
+    }
+
+    #[test]
+    fn float_comparisons() {
+        use morpheus::BlockBuilder;
+
+        // Sets bit N of the exit code when the Nth jump is taken.
+        let jumps_taken = |lhs: f64, rhs: f64| {
+            let mut vm = build(|block| {
+                let jumps = [
+                    BlockBuilder::emit_jz,
+                    BlockBuilder::emit_jnz,
+                    BlockBuilder::emit_jlt,
+                    BlockBuilder::emit_jge,
+                    BlockBuilder::emit_jbe,
+                    BlockBuilder::emit_ja,
+                ];
+                block.emit_move_imm(Operand::reg(q(1)), lhs.to_bits())?;
+                block.emit_fcmp(q(1), Operand::lit_f64(rhs))?;
+                for (bit, jump) in jumps.into_iter().enumerate() {
+                    let (taken, next) = (block.new_label(), block.new_label());
+                    jump(block, taken);
+                    block.emit_jmp(next);
+                    block.bind_label(taken)?;
+                    block.emit_or(q(0), Operand::lit64(1 << bit))?;
+                    block.bind_label(next)?;
+                }
+                Ok(())
+            });
+            vm.run().unwrap()
+        };
+
+        assert_eq!(jumps_taken(1.0, 2.0), 0b010110);
+        assert_eq!(jumps_taken(2.0, 2.0), 0b011001);
+        assert_eq!(jumps_taken(3.0, 2.0), 0b101010);
+        assert_eq!(jumps_taken(f64::NAN, 2.0), 0b000010);
+        assert_eq!(jumps_taken(-0.0, 0.0), 0b011001);
+    }
+
+    #[test]
+    fn memory_clear_and_set() {
+        let mut vm = build(|block| {
+            for (addr, value) in [(DATA_BASE, u64::MAX), (DATA_BASE + 8, u64::MAX)] {
+                block.emit_move(Operand::addr(addr), Operand::lit64(value), None)?;
+            }
+            block.emit_move(Operand::addr(DATA_BASE), Operand::lit64(0), None)?;
+            block.emit_move(Operand::addr(DATA_BASE + 8), Operand::lit64(1), None)?;
+            Ok(())
+        });
+
+        vm.run().unwrap();
+        assert_eq!(vm.load_memory(DATA_BASE, 8).unwrap(), 0);
+        assert_eq!(vm.load_memory(DATA_BASE + 8, 8).unwrap(), 1);
+    }
+
+    #[test]
+    fn malformed_code() {
+        for (code, expected) in [
+            (
+                vec![Instruction::Pop as u8 | Instruction::ALT_MODE, 0x20],
+                VMError::InvalidInstruction,
+            ),
+            (vec![0x0F], VMError::InvalidInstruction),
+            (
+                vec![Instruction::Jmp as u8, 0x00],
+                VMError::UnexpectedEndOfCode,
+            ),
+            (vec![Instruction::Not as u8, 0x1F], VMError::InvalidRegister),
+            (vec![Instruction::NoOp as u8], VMError::UnexpectedEndOfCode),
+        ] {
+            let mut vm = VM {
+                code,
+                ..VM::default()
+            };
+            let err = vm.run().unwrap_err();
+            assert_eq!(
+                std::mem::discriminant(&err),
+                std::mem::discriminant(&expected)
+            );
+        }
+    }
+
+    #[test]
+    fn stack_boundaries() {
+        let mut stack = Stack::new(16);
+        stack.push(1u64).unwrap();
+        stack.push(2u64).unwrap();
+        assert_eq!(stack.push(3u8), Err(StackError::Overflow { depth: 16 }));
+        assert_eq!(stack.pop_bytes(16).unwrap().len(), 16);
+        assert_eq!(stack.pop_bytes(1), Err(StackError::Underflow { depth: 0 }));
+    }
+
+    #[test]
+    fn stack_size_is_configurable() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let entry = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_push(Operand::lit64(1));
+                    block.emit_push(Operand::lit64(2));
+                    block.emit_push(Operand::lit64(3));
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+        let image = builder.link().unwrap();
+
+        let mut vm = VM::builder().stack_size(16).build();
+        vm.load_image(&image);
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::StackOverflow { pc: 18, depth: 16 })
+        ));
+
+        let mut vm = VM::builder().stack_size(24).build();
+        vm.load_image(&image);
+        assert_eq!(vm.run().unwrap(), 0);
+    }
+
+    #[test]
+    fn load_rejects_garbage() {
+        let mut vm = VM::default();
+        assert!(matches!(
+            vm.load(b"NOT A DREAM FILE"),
+            Err(VMError::InvalidDreamFile(morpheus::Error::NotADreamFile))
+        ));
+    }
+
+    #[test]
+    fn syscall_errors_report_pc() {
+        let mut vm = build(|b| {
+            b.emit_move(Operand::reg(Register::RSI), Operand::lit64(0x7FFF), None)?;
+            b.emit_syscall(0)?;
+            Ok(())
+        });
+        // MoveImm rsi, $0x7FFF is 10 bytes long.
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::UnknownSyscall {
+                pc: 10,
+                number: 0x7FFF
+            })
+        ));
+    }
+
+    #[test]
+    fn guest_memory() {
+        let mut vm = build(|b| {
+            b.emit_move(Operand::reg(q(0)), Operand::lit64(0xDEAD_BEEF), None)?;
+            b.emit_move(Operand::addr(DATA_BASE + 8), Operand::reg(q(0)), None)?;
+            b.emit_move(Operand::reg(q(1)), Operand::addr(DATA_BASE + 8), None)?;
+            b.emit_push(Operand::lit64(0x1234));
+            b.emit_move(
+                Operand::addr(DATA_BASE + 16),
+                Operand::addr(STACK_BASE),
+                None,
+            )?;
+            b.emit_move(Operand::reg(q(2)), Operand::addr(DATA_BASE + 16), None)?;
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg.r.get(q(1)), 0xDEAD_BEEF);
+        assert_eq!(vm.reg.r.get(q(2)), 0x1234);
+    }
+
+    #[test]
+    fn invalid_addresses_fault() {
+        let faults = |addr: u64, write: bool| {
+            let mut vm = build(|b| {
+                if write {
+                    b.emit_move(Operand::addr(addr), Operand::reg(q(0)), None)?;
+                } else {
+                    b.emit_move(Operand::reg(q(0)), Operand::addr(addr), None)?;
+                }
+                Ok(())
+            });
+            matches!(vm.run(), Err(VMError::InvalidAddress { addr: a, size: 8 }) if a == addr)
+        };
+
+        // Host-looking pointers, null, and accesses past the end of a segment.
+        assert!(faults(0, false));
+        assert!(faults(0x7FFE_1234_5678, false));
+        assert!(faults(DATA_BASE + DATA_SIZE as u64 - 4, true));
+        // Nothing has been pushed yet.
+        assert!(faults(STACK_BASE, false));
+        // TEXT is read-only.
+        assert!(faults(TEXT_BASE, true));
+    }
+
+    #[test]
+    fn map_resolves_strings() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        builder.add_string("unused");
+        let hello = builder.add_string("Hello world!\n") as u64;
+        let entry = builder
+            .procedure(|proc| {
+                proc.body(|b| {
+                    b.emit_map(q(0), hello)?;
+                    b.emit_map(q(2), hello + 1)?;
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+
+        let mut vm = VM::default();
+        vm.load(&builder.link().unwrap().to_bytes()).unwrap();
+        assert!(matches!(vm.run(), Err(VMError::InvalidStringOffset(o)) if o == hello + 1));
+
+        let (addr, len) = (vm.reg.r.get(q(0)), vm.reg.r.get(q(1)));
+        assert_eq!(vm.memory(addr, len as usize).unwrap(), b"Hello world!\n");
+    }
+
+    #[test]
+    fn map_needs_a_wide_register() {
+        let b0 = Register::new(RegisterType::B, 0).unwrap();
+        for dst in [b0, Register::RSI, Register::RS5, q(31)] {
+            let mut code = vec![Instruction::Map as u8, dst.to_u8()];
+            code.extend_from_slice(&8u64.to_le_bytes());
+            let mut vm = VM {
+                code,
+                ..VM::default()
+            };
+            assert!(
+                matches!(vm.run(), Err(VMError::InvalidRegister)),
+                "Map {dst}, $8"
+            );
+        }
+    }
+
+    #[test]
+    fn hello_world() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let hello = builder.add_string("Hello world!\n") as u64;
+        let entry = builder
+            .procedure(|proc| {
+                proc.body(|b| {
+                    b.emit_move(Operand::reg(Register::RSI), Operand::lit64(1), None)?;
+                    b.emit_move(Operand::reg(Register::RS0), Operand::lit64(2), None)?;
+                    b.emit_map(Register::RS1, hello)?;
+                    b.emit_syscall(3)?;
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+
+        let mut vm = VM::default();
+        vm.load(&builder.link().unwrap().to_bytes()).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.reg.r.get(Register::RSR), 13);
+    }
+
+    fn syscall(b: &mut morpheus::BlockBuilder, syscall: Syscall, args: Vec<Operand>) {
+        b.emit_syscall_call(syscall, &args, None).unwrap();
+    }
+
+    #[test]
+    fn heap() {
+        let mut vm = build(|b| {
+            syscall(b, Syscall::Alloc, vec![Operand::lit64(24)]);
+            b.emit_move(Operand::reg(q(0)), Operand::reg(Register::RSR), None)?;
+            syscall(
+                b,
+                Syscall::Realloc,
+                vec![Operand::reg(q(0)), Operand::lit64(100)],
+            );
+            b.emit_move(Operand::reg(q(1)), Operand::reg(Register::RSR), None)?;
+            syscall(b, Syscall::Alloc, vec![Operand::lit64(1 << 40)]);
+            b.emit_move(Operand::reg(q(2)), Operand::reg(Register::RSR), None)?;
+            syscall(b, Syscall::Free, vec![Operand::reg(q(1))]);
+            Ok(())
+        });
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.reg.r.get(q(0)), HEAP_BASE);
+        assert_eq!(vm.reg.r.get(q(1)), HEAP_BASE + 32);
+        assert_eq!(
+            vm.reg.r.get(q(2)),
+            crate::sys::error_code(crate::sys::ENOMEM)
+        );
+        assert_eq!(vm.heap.live_allocations(), 0);
+    }
+
+    #[test]
+    fn heap_debug_mode() {
+        let run = |debug: bool, f: fn(&mut morpheus::BlockBuilder)| {
+            let mut vm = build(|b| {
+                syscall(b, Syscall::Alloc, vec![Operand::lit64(8)]);
+                b.emit_move(Operand::reg(q(0)), Operand::reg(Register::RSR), None)?;
+                syscall(b, Syscall::Free, vec![Operand::reg(q(0))]);
+                f(b);
+                Ok(())
+            });
+            vm.heap.debug = debug;
+            vm.run()
+        };
+
+        let double_free = |b: &mut morpheus::BlockBuilder| {
+            syscall(b, Syscall::Free, vec![Operand::reg(q(0))]);
+        };
+        let use_after_free = |b: &mut morpheus::BlockBuilder| {
+            syscall(
+                b,
+                Syscall::Write,
+                vec![
+                    Operand::lit64(crate::sys::STDOUT),
+                    Operand::reg(q(0)),
+                    Operand::lit64(8),
+                ],
+            );
+        };
+
+        assert!(matches!(
+            run(true, double_free),
+            Err(VMError::DoubleFree(HEAP_BASE))
+        ));
+        assert!(matches!(
+            run(false, double_free),
+            Err(VMError::InvalidFree(HEAP_BASE))
+        ));
+        assert!(matches!(
+            run(true, use_after_free),
+            Err(VMError::UseAfterFree {
+                addr: HEAP_BASE,
+                size: 8
+            })
+        ));
+    }
 }
@@ -1,16 +1,26 @@
 use std::{collections::HashMap, fs::File};
 
 use morpheus::{
+    abi::{Syscall, STACK_BASE, STDOUT},
     BlockBuilder, Builder, Operand, OutputType, Register, RegisterAllocator, RegisterArena,
     RegisterType, Version,
 };
 
 use crate::ir::{Expr, Operator};
 
-// WARN: These have be the same as what is specified by the DreamVM.
-// TODO: Refactor so that we can use the DreamVM definitions directly.
-const STDOUT: u64 = 2;
-const SYS_WRITE: u64 = 1;
+#[derive(Debug)]
+enum CompileError {
+    /// A mistake in the program being compiled.
+    Source(&'static str),
+    /// Morpheus rejected what we emitted, which is a bug in the compiler.
+    Internal(morpheus::Error),
+}
+
+impl From<morpheus::Error> for CompileError {
+    fn from(err: morpheus::Error) -> Self {
+        CompileError::Internal(err)
+    }
+}
 
 #[derive(Debug, Default)]
 struct Generator {
@@ -23,20 +33,28 @@ pub fn compile(out: &mut File, exprs: &[Expr]) {
     let mut builder = Builder::new(Version::from(0), OutputType::Bin);
     let mut generator = Generator::default();
     let mut reg_ator = RegisterAllocator::new();
-    let func_id = builder.procedure(|proc| {
-        proc.body(|body| {
-            let mut registers = reg_ator.start_arena();
-            for expr in exprs {
-                if let Err(err) =
-                    compile_expression(body, &mut generator, registers.new_arena(), expr)
-                {
-                    println!("ERROR: {err}");
-                    generator.errored = true;
-                    break;
+    let func_id = builder
+        .procedure(|proc| {
+            proc.body(|body| {
+                let mut registers = reg_ator.start_arena();
+                for expr in exprs {
+                    match compile_expression(body, &mut generator, registers.new_arena(), expr) {
+                        Ok(_) => {}
+                        Err(CompileError::Source(err)) => {
+                            println!("ERROR: {err}");
+                            generator.errored = true;
+                            break;
+                        }
+                        Err(CompileError::Internal(err)) => return Err(err),
+                    }
                 }
-            }
-        });
-    });
+
+                // The entry procedure's rq0 is the program's exit code.
+                body.emit_clear(Register::new(RegisterType::Q, 0).unwrap());
+                Ok(())
+            })
+        })
+        .expect("INTERNAL ERROR: failed to build the entry procedure.");
 
     if generator.errored {
         return;
@@ -53,12 +71,11 @@ fn compile_expression(
     gen: &mut Generator,
     mut registers: RegisterArena,
     expr: &Expr,
-) -> Result<Register, &'static str> {
+) -> Result<Register, CompileError> {
     match expr {
         Expr::Int(value) => {
             let result = registers.next(RegisterType::Q);
-            b.emit_move(Operand::reg(result), Operand::lit64(*value as u64), None)
-                .expect("INTERNAL ERROR: failed to emit move instruction.");
+            b.emit_move(Operand::reg(result), Operand::lit64(*value as u64), None)?;
             Ok(result)
         }
         Expr::Ident(ident) => {
@@ -67,37 +84,74 @@ fn compile_expression(
                 b.emit_stack_load(result, var_offset);
                 Ok(result)
             } else {
-                Err("Unknown identifier")
+                Err(CompileError::Source("Unknown identifier"))
             }
         }
         Expr::Operation(Operator::Dollar, operands) => {
-            if operands.len() < 1 {
-                return Err("Not enough operands for operation");
+            if operands.is_empty() {
+                return Err(CompileError::Source("Not enough operands for operation"));
             }
 
-            b.emit_move(Operand::reg(Register::RSI), Operand::lit64(SYS_WRITE), None)
-                .expect("INTERNAL ERROR: failed to emit move instruction for dollar opeartor.");
-
-            b.emit_move(Operand::reg(Register::RS0), Operand::lit64(STDOUT), None)
-                .expect("INTERNAL ERROR: failed to emit move instruction for dollar operator.");
-
             // TODO: Implement this for multiple operands.
             let value = compile_expression(b, gen, registers.new_arena(), &operands[0])?;
-            b.emit_move(Operand::reg(Register::RS1), Operand::reg(value), None)
-                .expect("INTERNAL ERROR: failed to emit move instruction.");
 
-            b.emit_syscall(2)
-                .expect("INTERNAL ERROR: failed to emit syscall2.");
+            // Write takes a buffer, so the value's bytes go through the stack.
+            b.emit_push(Operand::reg(value));
+            let args = [
+                Operand::lit64(STDOUT),
+                Operand::lit64(STACK_BASE + gen.stack_pointer),
+                Operand::lit64(8),
+            ];
+            b.emit_syscall_call(Syscall::Write, &args, None)?;
+            b.emit_release(8);
 
             Ok(Register::RSR)
         }
-        Expr::Operation(_op, operands) => {
+        Expr::Operation(Operator::Eq, operands) => {
+            if operands.len() != 2 {
+                return Err(CompileError::Source("'=' requires exactly two operands"));
+            }
+
+            let result = registers.next(RegisterType::Q);
+
+            let lhs = compile_expression(b, gen, registers.new_arena(), &operands[0])?;
+            b.emit_move(Operand::reg(result), Operand::reg(lhs), None)?;
+
+            let rhs = compile_expression(b, gen, registers.new_arena(), &operands[1])?;
+            b.emit_cmp(result, Operand::reg(rhs))?;
+
+            let equal = b.new_label();
+            b.emit_set(result);
+            b.emit_jz(equal);
+            b.emit_clear(result);
+            b.bind_label(equal)?;
+
+            Ok(result)
+        }
+        Expr::Operation(op, operands) => {
             if operands.len() < 2 {
-                return Err("Not enough operands for operation");
+                return Err(CompileError::Source("Not enough operands for operation"));
+            }
+
+            let emit = match op {
+                Operator::Plus => BlockBuilder::emit_add,
+                Operator::Dash => BlockBuilder::emit_sub,
+                Operator::Star => BlockBuilder::emit_mul,
+                Operator::Slash => BlockBuilder::emit_idiv,
+                _ => return Err(CompileError::Source("Unsupported operator")),
+            };
+
+            let result = registers.next(RegisterType::Q);
+
+            let lhs = compile_expression(b, gen, registers.new_arena(), &operands[0])?;
+            b.emit_move(Operand::reg(result), Operand::reg(lhs), None)?;
+
+            for operand in &operands[1..] {
+                let rhs = compile_expression(b, gen, registers.new_arena(), operand)?;
+                emit(b, result, Operand::reg(rhs))?;
             }
 
-            let _result = registers.next(RegisterType::Q);
-            todo!()
+            Ok(result)
         }
         Expr::Let(ident, init) => {
             let value = compile_expression(b, gen, registers.new_arena(), init)?;
@@ -5,7 +5,7 @@ mod parser;
 mod codegen;
 
 fn main() {
-    let Some(path) = std::env::args().skip(1).next() else {
+    let Some(path) = std::env::args().nth(1) else {
         println!("ERROR: No source file given.");
         return;
     };
@@ -49,12 +49,8 @@ fn main() {
     let dream_file = BufReader::new(File::open(out_path).unwrap());
     let mut dasm_file = File::create(dasm_path).unwrap();
 
-    match morpheus::disassemble(dream_file.bytes().map(Result::unwrap), &mut dasm_file) {
-        Ok(_) => {},
-        Err(err) => {
-            println!("ERROR: {err:?}");
-            return;
-        }
+    if let Err(err) = morpheus::disassemble(dream_file.bytes().map(Result::unwrap), &mut dasm_file) {
+        println!("ERROR: {err:?}");
     }
 }
 
@@ -116,7 +116,7 @@ where
             Some(ptok) => ptok,
             None => return false,
         };
-        tok.discriminants_eq(&ptok)
+        tok.discriminants_eq(ptok)
     }
 
     fn parse_expr(&mut self, allow_decls: bool, parens_required: bool) -> Result<Expr, &'static str> {
@@ -124,7 +124,10 @@ where
             self.expect(Token::OpenParen, "Expected '(' to begin expression.")?;
         }
 
-        let parens = self.eat(Token::OpenParen);
+        let parens = !parens_required && self.eat(Token::OpenParen);
+        if parens {
+            self.next_token();
+        }
 
         let expr = match self.next_token().ok_or("Expected operator.")? {
             Token::Int(value) => Expr::Int(value),
@@ -178,7 +181,7 @@ where
             Token::CloseParen => unreachable!(),
         };
 
-        if parens_required {
+        if parens_required || parens {
             self.expect(Token::CloseParen, "Expected ')' to end expression")?;
         }
 
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+
+use quicksand::{parse_float_literal, DecodedOperand, Instruction, OperandEncoding, Register};
+
+use super::lexer::{Token, TokenKind};
+use crate::{BlockBuilder, DreamImage, Error, Label, Result};
+
+/// An operand as written in the source, e.g. `[fp-8]`, and the index of its
+/// first token. It is parsed once the slot it goes in is known.
+struct Arg {
+    text: String,
+    at: usize,
+}
+
+/// Where the program starts: `ENTRY:` in front of an instruction or an
+/// `.entry` directive naming a label.
+enum Entry {
+    At(u64),
+    Label(String, usize),
+}
+
+pub struct Assembler<'tok, 'out> {
+    tokens: &'tok [Token],
+    pos: usize,
+    block: BlockBuilder<'out>,
+    image: DreamImage,
+    /// Every label that was bound or used, and the token that first used it.
+    labels: HashMap<String, (Label, Option<u64>, Option<usize>)>,
+    strings: HashMap<String, u64>,
+    text_size: u64,
+    entry: Option<Entry>,
+}
+
+impl<'tok, 'out> Assembler<'tok, 'out> {
+    pub fn new(tokens: &'tok [Token], block: BlockBuilder<'out>, image: DreamImage) -> Self {
+        Self {
+            tokens,
+            pos: 0,
+            block,
+            image,
+            labels: HashMap::new(),
+            strings: HashMap::new(),
+            text_size: 0,
+            entry: None,
+        }
+    }
+
+    /// Assembles every line, returning the image without its code and the
+    /// offset of the entry point.
+    pub fn assemble(mut self) -> Result<(DreamImage, u64)> {
+        while self.peek().kind != TokenKind::Eof {
+            self.line()?;
+        }
+
+        let undefined = self
+            .labels
+            .iter()
+            .filter(|(_, (_, offset, _))| offset.is_none())
+            .filter_map(|(name, (_, _, used))| Some(((*used)?, name)))
+            .min();
+        if let Some((used, name)) = undefined {
+            return Err(self.tokens[used].error(format!("Label '{name}' is never defined.")));
+        }
+
+        let end = self.block.position();
+        let entry = match self.entry.take() {
+            None => 0,
+            Some(Entry::At(offset)) => offset,
+            Some(Entry::Label(name, at)) => match self.labels.get(&name) {
+                Some(&(_, Some(offset), _)) => offset,
+                _ => return Err(self.tokens[at].error(format!("Label '{name}' is never defined."))),
+            },
+        };
+        if entry > 0 && entry >= end {
+            let at = self.pos.min(self.tokens.len() - 1);
+            return Err(self.tokens[at].error("The entry point must be an instruction."));
+        }
+
+        self.block.finish_exact()?;
+        Ok((self.image, entry))
+    }
+
+    fn peek(&self) -> &'tok Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek_at(&self, n: usize) -> &'tok Token {
+        &self.tokens[(self.pos + n).min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> &'tok Token {
+        let token = &self.tokens[self.pos];
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn expect_punct(&mut self, c: char) -> Result<()> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Punct(p) if p == c => Ok(()),
+            _ => Err(token.error(format!("Expected '{c}'."))),
+        }
+    }
+
+    fn expect_end_of_line(&mut self) -> Result<()> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Newline | TokenKind::Eof => Ok(()),
+            _ => Err(token.error("Expected the end of the line.")),
+        }
+    }
+
+    fn line(&mut self) -> Result<()> {
+        // The disassembler prints file offsets in front of most lines.
+        if let TokenKind::Word(word) = &self.peek().kind {
+            let is_offset = word.len() == 8 && word.chars().all(|c| c.is_ascii_hexdigit());
+            if is_offset && self.peek_at(1).kind != TokenKind::Punct(':') {
+                self.next();
+            }
+        }
+
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Newline | TokenKind::Eof => return Ok(()),
+            TokenKind::Header(name, value) => self.header(token, name, value)?,
+            TokenKind::Str(s) => {
+                self.add_string(s);
+            }
+            TokenKind::Word(word) if self.peek().kind == TokenKind::Punct(':') => {
+                self.next();
+                match word.as_str() {
+                    "TEXT" | "CODE" => {}
+                    "ENTRY" => self.set_entry(Entry::At(self.block.position()), token)?,
+                    _ => self.bind_label(word, token)?,
+                }
+                // An instruction may follow a label on the same line.
+                return match self.peek().kind {
+                    TokenKind::Newline | TokenKind::Eof => self.expect_end_of_line(),
+                    _ => self.line(),
+                };
+            }
+            TokenKind::Word(word) if word == ".entry" => {
+                let name = self.name()?;
+                self.set_entry(Entry::Label(name, self.pos - 1), token)?;
+            }
+            TokenKind::Word(word) if word == ".string" => {
+                let name = self.name()?;
+                let value = self.next();
+                let TokenKind::Str(s) = &value.kind else {
+                    return Err(value.error("Expected a string."));
+                };
+                let offset = self.add_string(s);
+                if self.strings.insert(name.clone(), offset).is_some() {
+                    return Err(token.error(format!("String '{name}' is already defined.")));
+                }
+            }
+            TokenKind::Word(mnemonic) => self.instruction(token, mnemonic)?,
+            TokenKind::Punct(_) => return Err(token.error("Expected an instruction.")),
+        }
+
+        self.expect_end_of_line()
+    }
+
+    fn header(&mut self, token: &Token, name: &str, value: &str) -> Result<()> {
+        match name {
+            "Version" => {
+                self.image.version = value
+                    .parse()
+                    .map_err(|_| token.error(format!("Invalid version '{value}'.")))?;
+            }
+            "OutputType" => {
+                self.image.output_type = value
+                    .parse()
+                    .map_err(|_| token.error(format!("Invalid output type '{value}'.")))?;
+            }
+            _ => return Err(token.error(format!("Unknown header '#{name}'."))),
+        }
+        Ok(())
+    }
+
+    fn set_entry(&mut self, entry: Entry, token: &Token) -> Result<()> {
+        if self.entry.is_some() {
+            return Err(token.error("The entry point is already set."));
+        }
+        self.entry = Some(entry);
+        Ok(())
+    }
+
+    /// Adds a string to the TEXT section as is, returning the offset `Map`
+    /// takes.
+    fn add_string(&mut self, s: &[u8]) -> u64 {
+        let offset = self.text_size + std::mem::size_of::<u64>() as u64;
+        self.text_size = offset + (s.len() + DreamImage::STRING_PADDING) as u64;
+        self.image.strings.push(Box::from(s));
+        offset
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Word(word) if !word.starts_with(|c: char| c.is_ascii_digit()) => {
+                Ok(word.clone())
+            }
+            _ => Err(token.error("Expected a name.")),
+        }
+    }
+
+    /// Looks up a label referenced by the token at `at`.
+    fn label(&mut self, name: &str, at: usize) -> Label {
+        match self.labels.get_mut(name) {
+            Some(&mut (label, _, ref mut used)) => {
+                used.get_or_insert(at);
+                label
+            }
+            None => {
+                let label = self.block.new_label();
+                self.labels
+                    .insert(name.to_string(), (label, None, Some(at)));
+                label
+            }
+        }
+    }
+
+    fn bind_label(&mut self, name: &str, token: &Token) -> Result<()> {
+        let position = self.block.position();
+        let label = match self.labels.get_mut(name) {
+            Some((_, Some(_), _)) => {
+                return Err(token.error(format!("Label '{name}' is already defined.")))
+            }
+            Some((label, offset, _)) => {
+                *offset = Some(position);
+                *label
+            }
+            None => {
+                let label = self.block.new_label();
+                self.labels
+                    .insert(name.to_string(), (label, Some(position), None));
+                label
+            }
+        };
+        self.block
+            .bind_label(label)
+            .map_err(|err| token.error(format!("Cannot bind '{name}': {err}.")))
+    }
+
+    /// Reads the operands of an instruction.
+    fn args(&mut self) -> Result<Vec<Arg>> {
+        let mut args = vec![];
+        if matches!(self.peek().kind, TokenKind::Newline | TokenKind::Eof) {
+            return Ok(args);
+        }
+
+        loop {
+            args.push(self.arg()?);
+            if self.peek().kind != TokenKind::Punct(',') {
+                return Ok(args);
+            }
+            self.next();
+        }
+    }
+
+    /// Reads the tokens of one operand: a word, a `$` literal or a slot in
+    /// square brackets.
+    fn arg(&mut self) -> Result<Arg> {
+        let at = self.pos;
+        let token = self.next();
+        let mut text = match &token.kind {
+            TokenKind::Word(word) => {
+                let text = word.clone();
+                return Ok(Arg { text, at });
+            }
+            TokenKind::Punct(c @ ('$' | '[')) => c.to_string(),
+            _ => return Err(token.error("Expected an operand.")),
+        };
+
+        // The lexer splits e.g. `$-1e-7` and `[fp-8]` at each sign.
+        let mut after_word = false;
+        loop {
+            match &self.peek().kind {
+                TokenKind::Word(word) if !after_word => text.push_str(word),
+                TokenKind::Punct(c @ ('-' | '+')) => text.push(*c),
+                _ => break,
+            }
+            after_word = matches!(self.peek().kind, TokenKind::Word(_));
+            self.next();
+        }
+        if text.starts_with('[') {
+            self.expect_punct(']')?;
+            text.push(']');
+        }
+        Ok(Arg { text, at })
+    }
+
+    fn instruction(&mut self, token: &Token, mnemonic: &str) -> Result<()> {
+        let inst: Instruction = mnemonic
+            .parse()
+            .map_err(|_| token.error(format!("Unknown instruction '{mnemonic}'.")))?;
+        let mut args = self.args()?;
+        if inst.is_reversed() {
+            args.reverse();
+        }
+
+        // The plain form goes first as it is the shorter one. If neither fits,
+        // the error is from the one that got further.
+        let mut best: Option<(usize, Error)> = None;
+        for is_alt in [false, true] {
+            let Some(encodings) = inst.operand_encodings(is_alt) else {
+                continue;
+            };
+            let slots = slots(encodings);
+            if slots.len() != args.len() {
+                continue;
+            }
+            match self.operands(inst, &slots, &args) {
+                Ok(operands) => return self.emit(token, mnemonic, inst, is_alt, &args, operands),
+                Err((i, err)) => {
+                    if best.as_ref().is_none_or(|&(j, _)| i > j) {
+                        best = Some((i, err));
+                    }
+                }
+            }
+        }
+        Err(match best {
+            Some((_, err)) => err,
+            None => token.error(format!("Invalid operands for {mnemonic}.")),
+        })
+    }
+
+    /// Parses `args` for `slots`, leaving out names in `Lit64` slots, which are
+    /// labels or strings. Fails with the index of the first one that doesn't
+    /// fit.
+    fn operands(
+        &self,
+        inst: Instruction,
+        slots: &[OperandEncoding],
+        args: &[Arg],
+    ) -> std::result::Result<Vec<Option<DecodedOperand>>, (usize, Error)> {
+        let mut operands = vec![];
+        for (i, (&slot, arg)) in slots.iter().zip(args).enumerate() {
+            let operand = match (slot, operands.first()) {
+                (OperandEncoding::Lit64, _) if is_name(&arg.text) => None,
+                // Float literals are as wide as the register they go with.
+                (OperandEncoding::Binary, Some(&Some(DecodedOperand::Register(dst))))
+                    if inst.is_float() && arg.text.starts_with('$') =>
+                {
+                    let operand = parse_float_literal(&arg.text, dst).map_err(|err| match err {
+                        quicksand::Error::WrongOperandKind => (
+                            0,
+                            self.tokens[args[0].at].error("Expected a D or Q register."),
+                        ),
+                        _ => (i, self.operand_error(arg, slot, err)),
+                    })?;
+                    Some(operand)
+                }
+                _ => {
+                    let operand = slot
+                        .parse(&arg.text)
+                        .map_err(|err| (i, self.operand_error(arg, slot, err)))?;
+                    Some(operand)
+                }
+            };
+            operands.push(operand);
+        }
+        Ok(operands)
+    }
+
+    fn operand_error(&self, arg: &Arg, slot: OperandEncoding, err: quicksand::Error) -> Error {
+        let text = &arg.text;
+        let message = match err {
+            quicksand::Error::InvalidRegister if text.starts_with(|c: char| c.is_ascii_digit()) => {
+                "Expected '$' in front of an immediate value.".to_string()
+            }
+            quicksand::Error::InvalidRegister => format!("Invalid register '{text}'."),
+            quicksand::Error::WrongOperandKind => format!("Expected {}.", describe(slot)),
+            quicksand::Error::LiteralOutOfRange => {
+                format!("'{text}' doesn't fit in {}.", describe(slot))
+            }
+            _ => format!("Invalid operand '{text}'."),
+        };
+        self.tokens[arg.at].error(message)
+    }
+
+    /// Emits an instruction once `operands` fit its form, looking up the names
+    /// they left out.
+    fn emit(
+        &mut self,
+        token: &Token,
+        mnemonic: &str,
+        inst: Instruction,
+        is_alt: bool,
+        args: &[Arg],
+        operands: Vec<Option<DecodedOperand>>,
+    ) -> Result<()> {
+        let rejected = |err| token.error(format!("Cannot emit {mnemonic}: {err}."));
+
+        // Jumps and calls target a label or a raw code offset.
+        if (inst.is_jump() || inst.is_call()) && !is_alt {
+            let target = match operands[0] {
+                Some(DecodedOperand::Lit64(offset)) => self.block.label_at(offset),
+                _ => self.label(&args[0].text, args[0].at),
+            };
+            return self.block.emit_branch(inst, target).map_err(rejected);
+        }
+
+        let operands = operands
+            .into_iter()
+            .zip(args)
+            .map(|(operand, arg)| match operand {
+                Some(operand) => Ok(operand),
+                None => match self.strings.get(&arg.text) {
+                    Some(&offset) => Ok(DecodedOperand::Lit64(offset)),
+                    None => {
+                        Err(self.tokens[arg.at].error(format!("Unknown string '{}'.", arg.text)))
+                    }
+                },
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.block
+            .emit_instruction(inst, is_alt, &operands)
+            .map_err(rejected)
+    }
+}
+
+/// The slot each operand goes in, where a `Binary` is a register followed by
+/// a slot for a register or a literal.
+fn slots(encodings: &[OperandEncoding]) -> Vec<OperandEncoding> {
+    encodings
+        .iter()
+        .flat_map(|&encoding| match encoding {
+            OperandEncoding::Binary => vec![OperandEncoding::Register, OperandEncoding::Binary],
+            _ => vec![encoding],
+        })
+        .collect()
+}
+
+fn describe(slot: OperandEncoding) -> &'static str {
+    match slot {
+        OperandEncoding::Register => "a register",
+        OperandEncoding::Address => "an address",
+        OperandEncoding::Lit64 => "a 64-bit immediate value",
+        OperandEncoding::Lit8 => "an 8-bit immediate value",
+        OperandEncoding::Stack => "a stack slot",
+        OperandEncoding::Frame => "a frame slot",
+        OperandEncoding::Binary => "a register or an immediate value",
+    }
+}
+
+/// Whether an operand is a label or string name rather than e.g. a misspelled
+/// register.
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && text.parse::<Register>().is_err()
+        && !looks_like_register(text)
+}
+
+/// Whether `name` has the shape of a register, so that e.g. `rq99` is reported
+/// as a bad register instead of an unknown label.
+fn looks_like_register(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next() == Some('r')
+        && chars.next().is_some_and(|c| "sbwdq".contains(c))
+        && !chars.as_str().is_empty()
+        && chars.all(|c| c.is_ascii_digit())
+}
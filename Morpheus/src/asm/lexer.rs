@@ -0,0 +1,228 @@
+use std::{iter::Peekable, str::Chars};
+
+use crate::{Error, Result, SyntaxError};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Identifiers, mnemonics, registers, numbers and `.directives`.
+    Word(String),
+    /// A `#Directive` and the rest of its line.
+    Header(String, String),
+    Str(Vec<u8>),
+    Punct(char),
+    Newline,
+    Eof,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Token {
+    pub fn error(&self, message: impl Into<String>) -> Error {
+        Error::Syntax(SyntaxError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        })
+    }
+}
+
+pub struct Lexer<'src> {
+    chars: Peekable<Chars<'src>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'src> Lexer<'src> {
+    pub fn new(source: &'src str) -> Self {
+        Self {
+            chars: source.chars().peekable(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    pub fn tokenize(mut self) -> Result<Vec<Token>> {
+        let mut tokens = vec![];
+        loop {
+            let token = self.next_token()?;
+            let eof = token.kind == TokenKind::Eof;
+            tokens.push(token);
+            if eof {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: impl Into<String>) -> Error {
+        Error::Syntax(SyntaxError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        })
+    }
+
+    fn next_token(&mut self) -> Result<Token> {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                ';' => {
+                    while self.chars.peek().is_some_and(|&c| c != '\n') {
+                        self.bump();
+                    }
+                }
+                '\n' => break,
+                c if c.is_whitespace() => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+
+        let (line, column) = (self.line, self.column);
+        let kind = match self.chars.peek() {
+            None => TokenKind::Eof,
+            Some('\n') => {
+                self.bump();
+                TokenKind::Newline
+            }
+            Some('"') => TokenKind::Str(self.string()?),
+            Some('#') => {
+                self.bump();
+                let name = self.word();
+                let mut rest = String::new();
+                while self.chars.peek().is_some_and(|&c| c != '\n' && c != ';') {
+                    rest.extend(self.bump());
+                }
+                TokenKind::Header(name, rest.trim().to_string())
+            }
+            Some(&c) if is_word_char(c) => TokenKind::Word(self.word()),
+            Some(&c) if "$[]+-,:".contains(c) => {
+                self.bump();
+                TokenKind::Punct(c)
+            }
+            Some(&c) => return Err(self.error(format!("Unexpected character {c:?}."))),
+        };
+
+        Ok(Token { kind, line, column })
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while self.chars.peek().is_some_and(|&c| is_word_char(c)) {
+            word.extend(self.bump());
+        }
+        word
+    }
+
+    /// Reads a string literal with the escapes `std::ascii::escape_default`
+    /// produces, which is how the disassembler prints them.
+    fn string(&mut self) -> Result<Vec<u8>> {
+        self.bump();
+        let mut bytes = vec![];
+        loop {
+            let c = match self.bump() {
+                Some('"') => return Ok(bytes),
+                Some('\n') | None => return Err(self.error("Unterminated string.")),
+                Some('\\') => match self.bump() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some(c @ ('\\' | '"' | '\'')) => c,
+                    Some('x') => {
+                        let hex: String =
+                            [self.bump(), self.bump()].into_iter().flatten().collect();
+                        let byte = u8::from_str_radix(&hex, 16)
+                            .map_err(|_| self.error("Expected two hex digits after '\\x'."))?;
+                        bytes.push(byte);
+                        continue;
+                    }
+                    _ => return Err(self.error("Unknown escape sequence.")),
+                },
+                Some(c) => c,
+            };
+            let mut buf = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        Lexer::new(source)
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.kind)
+            .collect()
+    }
+
+    fn word(s: &str) -> TokenKind {
+        TokenKind::Word(s.to_string())
+    }
+
+    #[test]
+    fn instruction() {
+        assert_eq!(
+            kinds("00000038      StackLoad   rq0, [stk+8] ; comment\n"),
+            vec![
+                word("00000038"),
+                word("StackLoad"),
+                word("rq0"),
+                TokenKind::Punct(','),
+                TokenKind::Punct('['),
+                word("stk"),
+                TokenKind::Punct('+'),
+                word("8"),
+                TokenKind::Punct(']'),
+                TokenKind::Newline,
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn headers_and_strings() {
+        assert_eq!(
+            kinds("#Version 0+/\n\"a\\\"\\x7f\\n\""),
+            vec![
+                TokenKind::Header("Version".to_string(), "0+/".to_string()),
+                TokenKind::Newline,
+                TokenKind::Str(b"a\"\x7f\n".to_vec()),
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn positions() {
+        let tokens = Lexer::new("Ret\n  Push rq0").tokenize().unwrap();
+        let push = &tokens[2];
+        assert_eq!((push.line, push.column), (2, 3));
+
+        let err = Lexer::new("Ret\n  \"abc").tokenize().unwrap_err();
+        assert!(matches!(err, Error::Syntax(SyntaxError { line: 2, .. })));
+    }
+}
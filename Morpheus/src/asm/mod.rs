@@ -0,0 +1,256 @@
+mod assembler;
+mod lexer;
+
+use crate::{BlockBuilder, DreamImage, OutputType, Result, Version};
+
+use assembler::Assembler;
+use lexer::Lexer;
+
+/// Assembles the textual form of a dream file, as printed by the
+/// disassembler.
+pub fn assemble(source: &str) -> Result<DreamImage> {
+    let tokens = Lexer::new(source).tokenize()?;
+
+    let mut code = vec![];
+    let mut relocations = vec![];
+    let block = BlockBuilder::new(&mut code, &mut relocations);
+    let image = DreamImage::new(Version::from(0), OutputType::Bin);
+    let (mut image, entry) = Assembler::new(&tokens, block, image).assemble()?;
+
+    image.code = code;
+    image.entry_point = entry as usize;
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use quicksand::{Register, RegisterType};
+
+    use crate::{disassemble_image, Builder, Error, Operand, SyntaxError};
+
+    use super::*;
+
+    fn q(x: u8) -> Register {
+        Register::new(RegisterType::Q, x).unwrap()
+    }
+
+    fn round_trip(image: &DreamImage) {
+        let mut listing = String::new();
+        disassemble_image(image, &mut listing).unwrap();
+        assert_eq!(&assemble(&listing).unwrap(), image, "{listing}");
+    }
+
+    fn syntax_error(source: &str) -> (usize, usize) {
+        match assemble(source) {
+            Err(Error::Syntax(SyntaxError { line, column, .. })) => (line, column),
+            result => panic!("expected a syntax error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn disassembly_round_trips() {
+        let mut builder = Builder::new(Version::from(7), OutputType::Bin);
+        builder.add_string(b"Hello \"world\"!\n\x01");
+        builder.add_string(b"");
+        let callee = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    let top = block.new_label();
+                    let end = block.new_label();
+                    block.bind_label(top)?;
+                    block.emit_cmp(q(0), Operand::lit64(10))?;
+                    block.emit_jge(end);
+                    for jump in [
+                        BlockBuilder::emit_jz,
+                        BlockBuilder::emit_jnz,
+                        BlockBuilder::emit_jlt,
+                        BlockBuilder::emit_jle,
+                        BlockBuilder::emit_jgt,
+                        BlockBuilder::emit_jb,
+                        BlockBuilder::emit_jbe,
+                        BlockBuilder::emit_ja,
+                        BlockBuilder::emit_jae,
+                    ] {
+                        jump(block, end);
+                    }
+                    block.emit_test(q(0), Operand::reg(q(1)))?;
+                    block.emit_jmp(top);
+                    block.bind_label(end)?;
+                    Ok(())
+                })
+            })
+            .unwrap();
+        let entry = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_noop();
+                    block.emit_move(Operand::reg(q(0)), Operand::reg(q(1)), None)?;
+                    block.emit_move(Operand::addr(0x20), Operand::reg(q(1)), None)?;
+                    block.emit_move_imm(Operand::reg(q(2)), u64::MAX)?;
+                    block.emit_move_imm(Operand::addr(0x28), 3)?;
+                    block.emit_move(Operand::reg(q(3)), Operand::addr(0x30), None)?;
+                    block.emit_move(Operand::addr(0x38), Operand::addr(0x30), Some(8))?;
+                    block.emit_move_sx(q(3), Register::RSI)?;
+                    block.emit_load_sx(q(3), 0x30, 4)?;
+                    for dst in [Operand::reg(q(3)), Operand::addr(0x48)] {
+                        for src in [
+                            Operand::reg(q(4)),
+                            Operand::addr(0x50),
+                            Operand::lit64(0),
+                            Operand::lit64(1),
+                            Operand::lit64(0x1234_5678_9ABC),
+                        ] {
+                            if let Err(err) = block.emit_move(dst, src, None) {
+                                panic!("emit_move({dst:?}, {src:?}) failed: {err:?}");
+                            }
+                        }
+                    }
+                    block.emit_clear(Register::RSI);
+                    block.emit_set(q(4));
+                    block.emit_push(Operand::reg(q(5)));
+                    block.emit_push(Operand::addr(0x40));
+                    block.emit_push(Operand::lit64(99));
+                    block.emit_pop(q(5));
+                    block.emit_stack_load(q(6), 16);
+                    block.emit_reserve(24);
+                    block.emit_stack_store(8, q(6));
+                    block.emit_frame_load(q(6), -16);
+                    block.emit_frame_store(8, q(6));
+                    block.emit_release(24);
+                    block.emit_map(Register::RS1, 8)?;
+                    block.emit_syscall(3)?;
+                    block.emit_call(callee);
+                    block.emit_proc_addr(q(7), callee)?;
+                    block.emit_call_indirect(q(7))?;
+                    let d = |x| Register::new(RegisterType::D, x).unwrap();
+                    block.emit_fadd(q(1), Operand::lit_f64(-2.5e-9))?;
+                    block.emit_fsub(d(1), Operand::lit_f32(0.1))?;
+                    block.emit_fmul(q(1), Operand::reg(q(2)))?;
+                    block.emit_fdiv(d(1), Operand::lit_f32(f32::NEG_INFINITY))?;
+                    block.emit_fcmp(q(1), Operand::lit_f64(f64::NAN))?;
+                    for float in [-0.0, 1e-7, f32::MIN_POSITIVE, f32::MAX, f32::INFINITY] {
+                        block.emit_fadd(d(2), Operand::lit_f32(float))?;
+                    }
+                    for float in [-0.0, 1e300, f64::from_bits(1), f64::MIN, f64::NEG_INFINITY] {
+                        block.emit_fsub(q(2), Operand::lit_f64(float))?;
+                    }
+                    block.emit_fneg(d(1))?;
+                    block.emit_itof(q(1), d(2))?;
+                    block.emit_ftoi(Register::RS0, d(1))?;
+                    block.emit_ftof(d(1), q(1))?;
+                    block.emit_not(q(0));
+                    block.emit_neg(q(0));
+                    for binary in [
+                        BlockBuilder::emit_add,
+                        BlockBuilder::emit_sub,
+                        BlockBuilder::emit_mul,
+                        BlockBuilder::emit_div,
+                        BlockBuilder::emit_idiv,
+                        BlockBuilder::emit_mod,
+                        BlockBuilder::emit_imod,
+                        BlockBuilder::emit_and,
+                        BlockBuilder::emit_or,
+                        BlockBuilder::emit_xor,
+                        BlockBuilder::emit_shl,
+                        BlockBuilder::emit_shr,
+                        BlockBuilder::emit_sar,
+                    ] {
+                        binary(block, q(8), Operand::lit64(2))?;
+                        binary(block, q(8), Operand::reg(q(9)))?;
+                    }
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+
+        round_trip(&builder.link().unwrap());
+    }
+
+    #[test]
+    fn example_listing() {
+        let image = assemble(include_str!("../../../Example-Lang/test.dasm")).unwrap();
+        let expected = include_bytes!("../../../Example-Lang/test.dream");
+        assert_eq!(image.to_bytes(), expected);
+    }
+
+    #[test]
+    fn labels_and_directives() {
+        let source = r#"
+            #OutputType Lib
+            .string hello "Hello world!"
+            .entry main
+
+            ; Prints the greeting `rq0` times.
+            loop:   Map         rs1, hello
+                    Set         rsi
+                    Syscall3
+                    Sub         rq0, $1
+                    Jnz         loop
+                    Ret
+            main:   MoveImm     rq0, $0x3
+                    Call        loop
+                    Ret
+        "#;
+        let image = assemble(source).unwrap();
+        assert_eq!(image.output_type, OutputType::Lib);
+        assert_eq!(image.strings, vec![Box::from(&b"Hello world!"[..])]);
+
+        let mut listing = String::new();
+        disassemble_image(&image, &mut listing).unwrap();
+        assert!(listing.contains("ENTRY:\n"));
+        assert!(listing.contains("Map         rs1, $8\n"));
+        assert!(listing.contains("Call        L0\n"));
+        round_trip(&image);
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        assert_eq!(syntax_error("Ret\n  Frob rq0"), (2, 3));
+        assert_eq!(syntax_error("Push rq0, rq1"), (1, 1));
+        assert_eq!(syntax_error("Add rq0, 42"), (1, 10));
+        assert_eq!(syntax_error("Add rq9000, $1"), (1, 5));
+        assert_eq!(syntax_error("Ret\nJmp nowhere\nJmp nowhere"), (2, 5));
+        assert_eq!(syntax_error("Map rs1, greeting"), (1, 10));
+        assert_eq!(syntax_error("a: Ret\na: Ret"), (2, 1));
+        assert_eq!(syntax_error("Ret\n#Version ???"), (2, 1));
+    }
+
+    #[test]
+    fn operands_are_checked_against_their_slot() {
+        let image = assemble("Add rq0, $-1\nFSub rd0, $-1e-7\nMoveSx rq0, [16], $4").unwrap();
+        let mut code = vec![];
+        let mut relocations = vec![];
+        let mut block = BlockBuilder::new(&mut code, &mut relocations);
+        let d0 = Register::new(RegisterType::D, 0).unwrap();
+        block.emit_add(q(0), Operand::lit64(u64::MAX)).unwrap();
+        block.emit_fsub(d0, Operand::lit_f32(-1e-7)).unwrap();
+        block.emit_load_sx(q(0), 16, 4).unwrap();
+        block.finish_exact().unwrap();
+        assert_eq!(image.code, code);
+
+        for (source, expected) in [
+            (
+                "MoveSx rq0, [16], $300",
+                (19, "'$300' doesn't fit in an 8-bit immediate value."),
+            ),
+            ("Push [stk+8]", (6, "Expected a register.")),
+            ("Move [16], $1", (12, "Expected a register.")),
+            ("StackStore [16], rq0", (12, "Expected a stack slot.")),
+            ("FAdd rs0, $1.5", (6, "Expected a D or Q register.")),
+            ("Jmp [16]", (5, "Expected a 64-bit immediate value.")),
+            ("PushImm $0x", (9, "Invalid operand '$0x'.")),
+            (
+                "MoveSx rb0, rq1",
+                (1, "Cannot emit MoveSx: operand not allowed here."),
+            ),
+        ] {
+            match assemble(source) {
+                Err(Error::Syntax(err)) => {
+                    assert_eq!((err.column, err.message.as_str()), expected, "{source}")
+                }
+                result => panic!("expected a syntax error for {source:?}, got {result:?}"),
+            }
+        }
+    }
+}
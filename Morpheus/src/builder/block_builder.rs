@@ -1,153 +1,830 @@
+use super::dream_builder::{ProcId, Relocation};
 use crate::{Error, Operand, Result};
-use quicksand::{Instruction, OperandType, Register};
+use quicksand::abi::Syscall;
+use quicksand::{DecodedOperand, Instruction, OperandType, Register};
+
+/// A position in a block that jumps can target before it is bound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label(usize);
 
 pub struct BlockBuilder<'out> {
     out: &'out mut Vec<u8>,
+    relocations: &'out mut Vec<Relocation>,
+    labels: Vec<Option<u64>>,
+    fixups: Vec<(usize, Label)>,
+    ret_end: Option<usize>,
 }
 
 impl<'out> BlockBuilder<'out> {
-    pub fn new(out: &'out mut Vec<u8>) -> Self {
-        Self { out }
+    pub fn new(out: &'out mut Vec<u8>, relocations: &'out mut Vec<Relocation>) -> Self {
+        Self {
+            out,
+            relocations,
+            labels: vec![],
+            fixups: vec![],
+            ret_end: None,
+        }
+    }
+
+    /// Appends a `Ret` unless the block already ends in one and back-patches
+    /// every jump to a label that was bound after the jump was emitted.
+    pub fn finish(mut self) -> Result<()> {
+        let end = self.out.len() as u64;
+        let label_at_end = self.labels.contains(&Some(end));
+        if self.ret_end != Some(self.out.len()) || label_at_end {
+            self.emit_ret();
+        }
+
+        self.finish_exact()
+    }
+
+    /// Like `finish` but never appends a `Ret`, for code that has to come out
+    /// exactly as it was emitted.
+    pub fn finish_exact(self) -> Result<()> {
+        for &(at, label) in self.fixups.iter() {
+            let target = self.labels[label.0].ok_or(Error::UnboundLabel)?;
+            self.out[at..at + 8].copy_from_slice(&target.to_le_bytes());
+        }
+
+        Ok(())
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// A label that is already bound to a code offset, which doesn't have to
+    /// be in this block.
+    pub fn label_at(&mut self, offset: u64) -> Label {
+        self.labels.push(Some(offset));
+        Label(self.labels.len() - 1)
+    }
+
+    /// The code offset of the next instruction emitted.
+    pub fn position(&self) -> u64 {
+        self.out.len() as u64
+    }
+
+    /// Binds `label` to the offset of the next instruction emitted.
+    pub fn bind_label(&mut self, label: Label) -> Result<()> {
+        match self.labels.get_mut(label.0) {
+            Some(slot @ None) => {
+                *slot = Some(self.out.len() as u64);
+                Ok(())
+            }
+            Some(Some(_)) => Err(Error::LabelAlreadyBound),
+            None => Err(Error::UnboundLabel),
+        }
     }
 
     pub fn emit_move(&mut self, dst: Operand, src: Operand, size: Option<u64>) -> Result<()> {
-        match dst.kind {
-            OperandType::Register => match src.kind {
-                OperandType::Register => {
-                    self.out.push(Instruction::Move as u8);
-                    self.out.push(dst.value as u8);
-                    self.out.push(src.value as u8);
-                }
-                OperandType::Address => {
-                    self.out.push(Instruction::MoveAddr as u8);
-                    self.out.push(dst.value as u8);
-                    self.out.extend(src.value.to_le_bytes());
-                }
-                OperandType::Lit64 => match src.value {
-                    0 => {
-                        self.out.push(Instruction::Clear as u8);
-                        self.out.push(dst.value as u8);
-                    }
-                    1 => {
-                        self.out.push(Instruction::Set as u8);
-                        self.out.push(dst.value as u8);
-                    }
-                    _ => {
-                        self.out.push(Instruction::MoveImm as u8);
-                        self.out.push(dst.value as u8);
-                        self.out.extend(src.value.to_le_bytes());
-                    }
-                },
-            },
-            OperandType::Address => match src.kind {
-                OperandType::Register => {
-                    self.out
-                        .push(Instruction::Move as u8 | Instruction::ALT_MODE);
-                    self.out.extend(dst.value.to_le_bytes());
-                    self.out.push(src.value as u8);
-                }
-                OperandType::Address => {
-                    self.out
-                        .push(Instruction::MoveAddr as u8 | Instruction::ALT_MODE);
-                    self.out.extend(dst.value.to_le_bytes());
-                    self.out.extend(src.value.to_le_bytes());
-                    if let Some(size) = size {
-                        self.out.extend(size.to_le_bytes());
-                    } else {
-                        self.out
-                            .extend((std::mem::size_of::<u64>() as u64).to_le_bytes());
-                    }
-                }
-                OperandType::Lit64 => match src.value {
-                    0 => {
-                        self.out
-                            .push(Instruction::Clear as u8 | Instruction::ALT_MODE);
-                        self.out.push(dst.value as u8);
-                    }
-                    1 => {
-                        self.out
-                            .push(Instruction::Set as u8 | Instruction::ALT_MODE);
-                        self.out.push(dst.value as u8);
-                    }
-                    _ => {
-                        self.out
-                            .push(Instruction::MoveImm as u8 | Instruction::ALT_MODE);
-                        self.out.extend(dst.value.to_le_bytes());
-                        self.out.extend(src.value.to_le_bytes());
-                    }
-                },
+        let is_alt = match dst.kind {
+            OperandType::Register => false,
+            OperandType::Address => true,
+            OperandType::Lit64 => return Err(Error::BadOperandType),
+        };
+        match src.kind {
+            OperandType::Register => self.emit_inst(
+                Instruction::Move,
+                is_alt,
+                &[dst.to_decoded(), src.to_decoded()],
+            ),
+            OperandType::Address if is_alt => {
+                let size = size.unwrap_or(std::mem::size_of::<u64>() as u64);
+                self.emit_inst(
+                    Instruction::MoveAddr,
+                    true,
+                    &[
+                        dst.to_decoded(),
+                        src.to_decoded(),
+                        DecodedOperand::Lit64(size),
+                    ],
+                )
+            }
+            OperandType::Address => self.emit_inst(
+                Instruction::MoveAddr,
+                false,
+                &[dst.to_decoded(), src.to_decoded()],
+            ),
+            OperandType::Lit64 => match src.value {
+                0 => self.emit_inst(Instruction::Clear, is_alt, &[dst.to_decoded()]),
+                1 => self.emit_inst(Instruction::Set, is_alt, &[dst.to_decoded()]),
+                _ => self.emit_move_imm(dst, src.value)?,
             },
-            _ => return Err(Error::BadOperandType),
         }
 
         Ok(())
     }
 
+    /// Always emits `MoveImm`, unlike `emit_move` which uses `Clear` and `Set`
+    /// for 0 and 1.
+    pub fn emit_move_imm(&mut self, dst: Operand, value: u64) -> Result<()> {
+        let is_alt = match dst.kind {
+            OperandType::Register => false,
+            OperandType::Address => true,
+            OperandType::Lit64 => return Err(Error::BadOperandType),
+        };
+        self.emit_inst(
+            Instruction::MoveImm,
+            is_alt,
+            &[dst.to_decoded(), DecodedOperand::Lit64(value)],
+        );
+        Ok(())
+    }
+
+    /// Moves `src` into `dst`, sign-extending it. `dst` has to be at least as
+    /// wide as `src`.
+    pub fn emit_move_sx(&mut self, dst: Register, src: Register) -> Result<()> {
+        self.emit_instruction(
+            Instruction::MoveSx,
+            false,
+            &[DecodedOperand::Register(dst), DecodedOperand::Register(src)],
+        )
+    }
+
+    /// Loads `size` bytes from `addr` into `dst`, sign-extending them. `size`
+    /// is 1, 2, 4 or 8 and no more than the width of `dst`.
+    pub fn emit_load_sx(&mut self, dst: Register, addr: u64, size: u8) -> Result<()> {
+        self.emit_instruction(
+            Instruction::MoveSx,
+            true,
+            &[
+                DecodedOperand::Register(dst),
+                DecodedOperand::Address(addr),
+                DecodedOperand::Lit64(size as u64),
+            ],
+        )
+    }
+
+    pub fn emit_noop(&mut self) {
+        self.emit_inst(Instruction::NoOp, false, &[]);
+    }
+
     pub fn emit_clear(&mut self, reg: Register) {
-        self.out.push(Instruction::Clear as u8);
-        self.out.push(reg.to_u8());
+        self.emit_inst(Instruction::Clear, false, &[DecodedOperand::Register(reg)]);
     }
 
     pub fn emit_set(&mut self, reg: Register) {
-        self.out.push(Instruction::Set as u8);
-        self.out.push(reg.to_u8());
+        self.emit_inst(Instruction::Set, false, &[DecodedOperand::Register(reg)]);
     }
 
     pub fn emit_push(&mut self, value: Operand) {
         match value.kind {
             OperandType::Register => {
-                self.out.push(Instruction::Push as u8);
-                self.out.push(value.value as u8);
-            }
-            OperandType::Address => {
-                self.out
-                    .push(Instruction::Push as u8 | Instruction::ALT_MODE);
-                self.out.extend(value.value.to_le_bytes());
+                self.emit_inst(Instruction::Push, false, &[value.to_decoded()])
             }
+            OperandType::Address => self.emit_inst(Instruction::Push, true, &[value.to_decoded()]),
             OperandType::Lit64 => {
-                self.out.push(Instruction::PushImm as u8);
-                self.out.extend(value.value.to_le_bytes());
+                self.emit_inst(Instruction::PushImm, false, &[value.to_decoded()])
             }
         }
     }
 
     pub fn emit_pop(&mut self, reg: Register) {
-        self.out.push(Instruction::Pop as u8);
-        self.out.push(reg.to_u8());
+        self.emit_inst(Instruction::Pop, false, &[DecodedOperand::Register(reg)]);
     }
 
     pub fn emit_stack_load(&mut self, reg: Register, offset: u64) {
-        self.out.push(Instruction::StackLoad as u8);
-        self.out.push(reg.to_u8());
-        self.out.extend(offset.to_le_bytes());
+        self.emit_inst(
+            Instruction::StackLoad,
+            false,
+            &[DecodedOperand::Register(reg), DecodedOperand::Stack(offset)],
+        );
+    }
+
+    pub fn emit_stack_store(&mut self, offset: u64, reg: Register) {
+        self.emit_inst(
+            Instruction::StackStore,
+            false,
+            &[DecodedOperand::Register(reg), DecodedOperand::Stack(offset)],
+        );
     }
 
+    /// Loads `reg` from `offset` bytes away from the frame pointer, negative
+    /// offsets reach into the caller's frame.
+    pub fn emit_frame_load(&mut self, reg: Register, offset: i64) {
+        self.emit_inst(
+            Instruction::StackLoad,
+            true,
+            &[DecodedOperand::Register(reg), DecodedOperand::Frame(offset)],
+        );
+    }
+
+    /// Stores `reg` at `offset` bytes away from the frame pointer.
+    pub fn emit_frame_store(&mut self, offset: i64, reg: Register) {
+        self.emit_inst(
+            Instruction::StackStore,
+            true,
+            &[DecodedOperand::Register(reg), DecodedOperand::Frame(offset)],
+        );
+    }
+
+    /// Grows the stack by `size` zeroed bytes.
+    pub fn emit_reserve(&mut self, size: u64) {
+        self.emit_inst(Instruction::Reserve, false, &[DecodedOperand::Lit64(size)]);
+    }
+
+    /// Shrinks the stack by `size` bytes.
+    pub fn emit_release(&mut self, size: u64) {
+        self.emit_inst(Instruction::Release, false, &[DecodedOperand::Lit64(size)]);
+    }
+
+    /// Loads the address of the string at `index`, as returned by
+    /// `Builder::add_string`, into `dst` and its length into the register
+    /// after it.
     pub fn emit_map(&mut self, dst: Register, index: u64) -> Result<()> {
-        if dst.is_q() || dst.is_rsx() {
-            self.out.push(Instruction::Map as u8);
-            self.out.push(dst.to_u8());
-            self.out.extend(index.to_le_bytes());
-            Ok(())
-        } else {
-            Err(Error::BadOperandValue)
-        }
+        self.emit_instruction(
+            Instruction::Map,
+            false,
+            &[DecodedOperand::Register(dst), DecodedOperand::Lit64(index)],
+        )
     }
 
     pub fn emit_syscall(&mut self, nargs: u8) -> Result<()> {
-        match nargs {
-            0 => self.out.push(Instruction::Syscall0 as u8),
-            1 => self.out.push(Instruction::Syscall1 as u8),
-            2 => self.out.push(Instruction::Syscall2 as u8),
-            3 => self.out.push(Instruction::Syscall3 as u8),
-            4 => self.out.push(Instruction::Syscall4 as u8),
-            5 => self.out.push(Instruction::Syscall5 as u8),
-            6 => self.out.push(Instruction::Syscall6 as u8),
+        let inst = match nargs {
+            0 => Instruction::Syscall0,
+            1 => Instruction::Syscall1,
+            2 => Instruction::Syscall2,
+            3 => Instruction::Syscall3,
+            4 => Instruction::Syscall4,
+            5 => Instruction::Syscall5,
+            6 => Instruction::Syscall6,
             _ => return Err(Error::TooManyArgsForSyscall),
+        };
+        self.emit_inst(inst, false, &[]);
+        Ok(())
+    }
+
+    /// Moves `args` into rs0 onwards and the syscall number into rsi, then emits
+    /// the `Syscall{N}` matching the syscall's arity. With a `dst` the result
+    /// is moved there from rsr.
+    ///
+    /// An argument can't read an argument register that an earlier one has
+    /// already overwritten.
+    pub fn emit_syscall_call(
+        &mut self,
+        syscall: Syscall,
+        args: &[Operand],
+        dst: Option<Register>,
+    ) -> Result<()> {
+        if args.len() != syscall.arity() {
+            return Err(Error::WrongNumberOfSyscallArgs);
         }
+
+        // The argument registers are numbered consecutively from rs0.
+        let regs = std::iter::successors(Some(Register::RS0), |reg| reg.next());
+        for (reg, arg) in regs.zip(args) {
+            if arg.kind == OperandType::Register {
+                let src =
+                    Register::try_from(arg.value as u8).map_err(|_| Error::InvalidRegister)?;
+                if src.is_rsx() && src.to_u8() < reg.to_u8() {
+                    return Err(Error::BadOperandValue);
+                }
+            }
+            if *arg != Operand::reg(reg) {
+                self.emit_move(Operand::reg(reg), *arg, None)?;
+            }
+        }
+
+        self.emit_move(
+            Operand::reg(Register::RSI),
+            Operand::lit64(syscall.number() as u64),
+            None,
+        )?;
+        self.emit_syscall(syscall.arity() as u8)?;
+
+        match dst {
+            Some(dst) if dst != Register::RSR => {
+                self.emit_move(Operand::reg(dst), Operand::reg(Register::RSR), None)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn emit_call(&mut self, proc: ProcId) {
+        self.emit_inst(Instruction::Call, false, &[DecodedOperand::Lit64(0)]);
+        self.emit_relocation(proc);
+    }
+
+    /// Loads the code offset of `proc` into `dst` e.g. for `emit_call_indirect`.
+    pub fn emit_proc_addr(&mut self, dst: Register, proc: ProcId) -> Result<()> {
+        if !(dst.is_q() || dst.is_rsx()) {
+            return Err(Error::BadOperandValue);
+        }
+        self.emit_inst(
+            Instruction::MoveImm,
+            false,
+            &[DecodedOperand::Register(dst), DecodedOperand::Lit64(0)],
+        );
+        self.emit_relocation(proc);
         Ok(())
     }
 
+    /// Records that the 64-bit placeholder the last instruction ended with is
+    /// the code offset of `proc`.
+    fn emit_relocation(&mut self, proc: ProcId) {
+        let at = self.out.len() - std::mem::size_of::<u64>();
+        self.relocations.push(Relocation::new(at, proc));
+    }
+
+    /// Calls code at a label instead of a procedure, e.g. in assembly where
+    /// there are no procedures.
+    pub fn emit_call_label(&mut self, target: Label) {
+        self.emit_jump(Instruction::Call, target);
+    }
+
+    /// Calls the procedure whose offset is stored in the Q register `reg`.
+    pub fn emit_call_indirect(&mut self, reg: Register) -> Result<()> {
+        self.emit_instruction(Instruction::Call, true, &[DecodedOperand::Register(reg)])
+    }
+
     pub fn emit_ret(&mut self) {
-        self.out.push(Instruction::Ret as u8);
+        self.emit_inst(Instruction::Ret, false, &[]);
+        self.ret_end = Some(self.out.len());
+    }
+
+    pub fn emit_add(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Add, dst, src)
+    }
+
+    pub fn emit_sub(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Sub, dst, src)
+    }
+
+    pub fn emit_mul(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Mul, dst, src)
+    }
+
+    pub fn emit_div(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Div, dst, src)
+    }
+
+    pub fn emit_idiv(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::IDiv, dst, src)
+    }
+
+    pub fn emit_mod(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Mod, dst, src)
+    }
+
+    pub fn emit_imod(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::IMod, dst, src)
+    }
+
+    pub fn emit_and(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::And, dst, src)
+    }
+
+    pub fn emit_or(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Or, dst, src)
+    }
+
+    pub fn emit_xor(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Xor, dst, src)
+    }
+
+    pub fn emit_shl(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Shl, dst, src)
+    }
+
+    pub fn emit_shr(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Shr, dst, src)
+    }
+
+    pub fn emit_sar(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Sar, dst, src)
+    }
+
+    pub fn emit_not(&mut self, reg: Register) {
+        self.emit_inst(Instruction::Not, false, &[DecodedOperand::Register(reg)]);
+    }
+
+    pub fn emit_neg(&mut self, reg: Register) {
+        self.emit_inst(Instruction::Neg, false, &[DecodedOperand::Register(reg)]);
+    }
+
+    /// Emits `inst sig dst src` where `src` is either a register or a 64-bit literal.
+    fn emit_binary(&mut self, inst: Instruction, dst: Register, src: Operand) -> Result<()> {
+        if src.kind == OperandType::Address {
+            return Err(Error::BadOperandType);
+        }
+        self.emit_instruction(
+            inst,
+            false,
+            &[DecodedOperand::Register(dst), src.to_decoded()],
+        )
+    }
+
+    pub fn emit_cmp(&mut self, lhs: Register, rhs: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Cmp, lhs, rhs)
+    }
+
+    pub fn emit_test(&mut self, lhs: Register, rhs: Operand) -> Result<()> {
+        self.emit_binary(Instruction::Test, lhs, rhs)
+    }
+
+    pub fn emit_fadd(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::FAdd, dst, src)
+    }
+
+    pub fn emit_fsub(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::FSub, dst, src)
+    }
+
+    pub fn emit_fmul(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::FMul, dst, src)
+    }
+
+    pub fn emit_fdiv(&mut self, dst: Register, src: Operand) -> Result<()> {
+        self.emit_binary(Instruction::FDiv, dst, src)
+    }
+
+    pub fn emit_fcmp(&mut self, lhs: Register, rhs: Operand) -> Result<()> {
+        self.emit_binary(Instruction::FCmp, lhs, rhs)
+    }
+
+    pub fn emit_fneg(&mut self, reg: Register) -> Result<()> {
+        self.emit_instruction(Instruction::FNeg, false, &[DecodedOperand::Register(reg)])
+    }
+
+    /// Converts the signed integer in `src` to a float in `dst`.
+    pub fn emit_itof(&mut self, dst: Register, src: Register) -> Result<()> {
+        self.emit_conversion(Instruction::IToF, dst, src)
+    }
+
+    /// Converts the float in `src` to a signed integer in `dst`, rounding
+    /// toward zero and saturating at the limits of `dst`.
+    pub fn emit_ftoi(&mut self, dst: Register, src: Register) -> Result<()> {
+        self.emit_conversion(Instruction::FToI, dst, src)
+    }
+
+    /// Converts between an f32 in a D register and an f64 in a Q register.
+    pub fn emit_ftof(&mut self, dst: Register, src: Register) -> Result<()> {
+        self.emit_conversion(Instruction::FToF, dst, src)
+    }
+
+    fn emit_conversion(&mut self, inst: Instruction, dst: Register, src: Register) -> Result<()> {
+        self.emit_instruction(
+            inst,
+            false,
+            &[DecodedOperand::Register(dst), DecodedOperand::Register(src)],
+        )
+    }
+
+    pub fn emit_jmp(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jmp, target);
+    }
+
+    pub fn emit_jz(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jz, target);
+    }
+
+    pub fn emit_jnz(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jnz, target);
+    }
+
+    pub fn emit_jlt(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jlt, target);
+    }
+
+    pub fn emit_jle(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jle, target);
+    }
+
+    pub fn emit_jgt(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jgt, target);
+    }
+
+    pub fn emit_jge(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jge, target);
+    }
+
+    pub fn emit_jb(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jb, target);
+    }
+
+    pub fn emit_jbe(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jbe, target);
+    }
+
+    pub fn emit_ja(&mut self, target: Label) {
+        self.emit_jump(Instruction::Ja, target);
+    }
+
+    pub fn emit_jae(&mut self, target: Label) {
+        self.emit_jump(Instruction::Jae, target);
+    }
+
+    /// Emits any jump, or `Call`, to `target`.
+    pub fn emit_branch(&mut self, inst: Instruction, target: Label) -> Result<()> {
+        if !inst.is_jump() && !inst.is_call() {
+            return Err(Error::BadOperandType);
+        }
+        self.emit_jump(inst, target);
+        Ok(())
+    }
+
+    fn emit_jump(&mut self, inst: Instruction, target: Label) {
+        let offset = self.labels.get(target.0).copied().flatten();
+        self.emit_inst(inst, false, &[DecodedOperand::Lit64(offset.unwrap_or(0))]);
+        if offset.is_none() {
+            let at = self.out.len() - std::mem::size_of::<u64>();
+            self.fixups.push((at, target));
+        }
+    }
+
+    /// Appends any instruction, with its operands in the order they are
+    /// encoded. Fails if they don't fit the instruction's encoding or break
+    /// one of the rules the `emit_*` methods check, e.g. `FAdd` only takes D
+    /// and Q registers.
+    pub fn emit_instruction(
+        &mut self,
+        inst: Instruction,
+        is_alt: bool,
+        operands: &[DecodedOperand],
+    ) -> Result<()> {
+        check_operands(inst, is_alt, operands)?;
+        quicksand::encode(inst, is_alt, operands, self.out).map_err(|err| match err {
+            quicksand::Error::InvalidInstruction => Error::InvalidInstruction,
+            _ => Error::BadOperandType,
+        })?;
+        if inst == Instruction::Ret {
+            self.ret_end = Some(self.out.len());
+        }
+        Ok(())
+    }
+
+    /// Appends an instruction whose operands the caller has already checked
+    /// against its encoding.
+    fn emit_inst(&mut self, inst: Instruction, is_alt: bool, operands: &[DecodedOperand]) {
+        quicksand::encode(inst, is_alt, operands, self.out)
+            .expect("operands should match the instruction's encoding");
+    }
+}
+
+/// The rules for operands that their encoding alone doesn't enforce.
+fn check_operands(inst: Instruction, is_alt: bool, operands: &[DecodedOperand]) -> Result<()> {
+    use DecodedOperand::{Address, Lit64, Register as Reg};
+
+    let valid = match (inst, is_alt, operands) {
+        // Sign-extension only ever widens.
+        (Instruction::MoveSx, false, &[Reg(dst), Reg(src)]) => {
+            !dst.is_x() && dst.width() >= src.width()
+        }
+        (Instruction::MoveSx, true, &[Reg(dst), Address(_), Lit64(size)]) => {
+            !dst.is_x() && matches!(size, 1 | 2 | 4 | 8) && dst.width() >= size as usize
+        }
+        // The string's length goes in the register after `dst`.
+        (Instruction::Map, false, &[Reg(dst), _]) => {
+            (dst.is_q() || dst.is_rsx()) && dst.next().is_some()
+        }
+        (Instruction::Call, true, &[Reg(reg)]) => reg.is_q(),
+        (Instruction::IToF, false, &[Reg(dst), Reg(src)]) => dst.is_float() && !src.is_x(),
+        (Instruction::FToI, false, &[Reg(dst), Reg(src)]) => !dst.is_x() && src.is_float(),
+        (Instruction::FToF, false, &[Reg(dst), Reg(src)]) => dst.is_float() && src.is_float(),
+        // Float arithmetic takes D and Q registers and an operand as wide.
+        (inst, false, &[Reg(dst), Reg(src)]) if inst.is_float() => {
+            dst.is_float() && src.width() == dst.width()
+        }
+        (inst, false, &[Reg(dst), Lit64(bits)]) if inst.is_float() => {
+            dst.is_float() && (dst.is_q() || bits <= u32::MAX as u64)
+        }
+        (inst, false, &[Reg(reg)]) if inst.is_float() => reg.is_float(),
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::BadOperandValue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands `f` a block over empty code and returns the code it emitted.
+    fn build(f: impl FnOnce(BlockBuilder)) -> Vec<u8> {
+        let mut code = vec![];
+        let mut relocations = vec![];
+        f(BlockBuilder::new(&mut code, &mut relocations));
+        code
+    }
+
+    #[test]
+    fn backward_jump() {
+        let code = build(|mut block| {
+            for _ in 0..4 {
+                block.emit_noop();
+            }
+            let top = block.new_label();
+            block.bind_label(top).unwrap();
+            block.emit_jmp(top);
+            block.finish().unwrap();
+        });
+
+        assert_eq!(code[4], Instruction::Jmp as u8);
+        assert_eq!(&code[5..13], &4u64.to_le_bytes());
+        assert_eq!(code[13..], [Instruction::Ret as u8]);
+    }
+
+    #[test]
+    fn forward_jump() {
+        let code = build(|mut block| {
+            let end = block.new_label();
+            block.emit_jz(end);
+            block.emit_ret();
+            block.bind_label(end).unwrap();
+            block.finish().unwrap();
+        });
+
+        assert_eq!(code[0], Instruction::Jz as u8);
+        assert_eq!(&code[1..9], &10u64.to_le_bytes());
+        assert_eq!(code[9..], [Instruction::Ret as u8, Instruction::Ret as u8]);
+    }
+
+    #[test]
+    fn no_duplicate_ret() {
+        let code = build(|mut block| {
+            block
+                .emit_move(Operand::reg(Register::RS0), Operand::lit64(0x20), None)
+                .unwrap();
+            block.emit_ret();
+            block.finish().unwrap();
+        });
+
+        assert_eq!(code.len(), 11);
+        assert_eq!(code[10], Instruction::Ret as u8);
+    }
+
+    #[test]
+    fn unbound_label() {
+        build(|mut block| {
+            let nowhere = block.new_label();
+            block.emit_jmp(nowhere);
+            assert!(matches!(block.finish(), Err(Error::UnboundLabel)));
+        });
+    }
+
+    #[test]
+    fn rebind_label() {
+        build(|mut block| {
+            let label = block.new_label();
+            block.bind_label(label).unwrap();
+            assert!(matches!(
+                block.bind_label(label),
+                Err(Error::LabelAlreadyBound)
+            ));
+        });
+    }
+
+    #[test]
+    fn map_needs_a_length_register() {
+        build(|mut block| {
+            assert!(block.emit_map(Register::RS4, 8).is_ok());
+            assert!(matches!(
+                block.emit_map(Register::RS5, 8),
+                Err(Error::BadOperandValue)
+            ));
+        });
+    }
+
+    #[test]
+    fn syscall_call() {
+        let q0 = Register::new(quicksand::RegisterType::Q, 0).unwrap();
+        let code = build(|mut block| {
+            let args = [
+                Operand::lit64(quicksand::abi::STDOUT),
+                Operand::reg(Register::RS1),
+                Operand::lit64(8),
+            ];
+            block
+                .emit_syscall_call(Syscall::Write, &args, Some(q0))
+                .unwrap();
+            assert!(matches!(
+                block.emit_syscall_call(Syscall::Write, &args[..2], None),
+                Err(Error::WrongNumberOfSyscallArgs)
+            ));
+            assert!(matches!(
+                block.emit_syscall_call(Syscall::Free, &[Operand::reg(Register::RS1)], None),
+                Ok(())
+            ));
+            let swapped = [Operand::reg(Register::RS1), Operand::reg(Register::RS0)];
+            assert!(matches!(
+                block.emit_syscall_call(Syscall::Realloc, &swapped, None),
+                Err(Error::BadOperandValue)
+            ));
+        });
+
+        // rs1 already holds its argument so only rs0 and rs2 are loaded.
+        assert_eq!(code[0], Instruction::MoveImm as u8);
+        assert_eq!(code[1], Register::RS0.to_u8());
+        assert_eq!(code[10], Instruction::MoveImm as u8);
+        assert_eq!(code[11], Register::RS2.to_u8());
+        assert_eq!(
+            code[20..22],
+            [Instruction::Set as u8, Register::RSI.to_u8()]
+        );
+        assert_eq!(code[22], Instruction::Syscall3 as u8);
+        assert_eq!(
+            code[23..26],
+            [Instruction::Move as u8, q0.to_u8(), Register::RSR.to_u8()]
+        );
+    }
+
+    #[test]
+    fn sign_extension_widens() {
+        let d0 = Register::new(quicksand::RegisterType::D, 0).unwrap();
+        build(|mut block| {
+            assert!(block.emit_move_sx(Register::RS0, d0).is_ok());
+            assert!(block.emit_move_sx(d0, d0).is_ok());
+            assert!(matches!(
+                block.emit_move_sx(d0, Register::RS0),
+                Err(Error::BadOperandValue)
+            ));
+            assert!(matches!(
+                block.emit_move_sx(Register::RXZ, d0),
+                Err(Error::BadOperandValue)
+            ));
+            assert!(block.emit_load_sx(d0, 0x20, 4).is_ok());
+            assert!(matches!(
+                block.emit_load_sx(d0, 0x20, 8),
+                Err(Error::BadOperandValue)
+            ));
+            assert!(matches!(
+                block.emit_load_sx(Register::RS0, 0x20, 3),
+                Err(Error::BadOperandValue)
+            ));
+        });
+    }
+
+    #[test]
+    fn floats_need_d_or_q_registers() {
+        let d0 = Register::new(quicksand::RegisterType::D, 0).unwrap();
+        let w0 = Register::new(quicksand::RegisterType::W, 0).unwrap();
+        let q0 = Register::new(quicksand::RegisterType::Q, 0).unwrap();
+        build(|mut block| {
+            assert!(block.emit_fadd(d0, Operand::lit_f32(1.0)).is_ok());
+            assert!(block.emit_fadd(q0, Operand::lit_f64(1.0)).is_ok());
+            for (dst, src) in [
+                (w0, Operand::lit_f32(1.0)),
+                (d0, Operand::lit_f64(1.0)),
+                (d0, Operand::reg(q0)),
+                (Register::RS0, Operand::lit_f64(1.0)),
+            ] {
+                assert!(matches!(
+                    block.emit_fadd(dst, src),
+                    Err(Error::BadOperandValue)
+                ));
+            }
+            assert!(block.emit_itof(d0, w0).is_ok());
+            assert!(block.emit_itof(w0, d0).is_err());
+            assert!(block.emit_ftoi(w0, d0).is_ok());
+            assert!(block.emit_ftoi(d0, w0).is_err());
+        });
+    }
+
+    #[test]
+    fn any_instruction() {
+        let d0 = Register::new(quicksand::RegisterType::D, 0).unwrap();
+        let code = build(|mut block| {
+            let top = block.new_label();
+            block.bind_label(top).unwrap();
+            block
+                .emit_instruction(Instruction::Push, true, &[DecodedOperand::Address(0x20)])
+                .unwrap();
+            block.emit_branch(Instruction::Jle, top).unwrap();
+            assert!(matches!(
+                block.emit_branch(Instruction::Push, top),
+                Err(Error::BadOperandType)
+            ));
+            assert!(matches!(
+                block.emit_instruction(Instruction::Pop, true, &[DecodedOperand::Register(d0)]),
+                Err(Error::InvalidInstruction)
+            ));
+            assert!(matches!(
+                block.emit_instruction(Instruction::Pop, false, &[DecodedOperand::Lit64(1)]),
+                Err(Error::BadOperandType)
+            ));
+            assert!(matches!(
+                block.emit_instruction(
+                    Instruction::FNeg,
+                    false,
+                    &[DecodedOperand::Register(Register::RS0)]
+                ),
+                Err(Error::BadOperandValue)
+            ));
+            block
+                .emit_instruction(Instruction::Ret, false, &[])
+                .unwrap();
+            block.finish().unwrap();
+        });
+
+        assert_eq!(code[0], Instruction::Push as u8 | Instruction::ALT_MODE);
+        assert_eq!(&code[1..9], &0x20u64.to_le_bytes());
+        assert_eq!(code[9], Instruction::Jle as u8);
+        assert_eq!(&code[10..18], &0u64.to_le_bytes());
+        assert_eq!(code[18..], [Instruction::Ret as u8]);
     }
 }
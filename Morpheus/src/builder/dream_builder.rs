@@ -1,102 +1,146 @@
 use super::{proc_builder::ProcedureBuilder, Write};
-use crate::{errors::Result, version::Version, OutputType};
+use crate::{
+    errors::{Error, Result},
+    image::DreamImage,
+    version::Version,
+    OutputType,
+};
+
+/// Opaque handle to a procedure that may be referenced before it is defined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcId(usize);
+
+/// A 64-bit slot in the code that is filled with a procedure's offset when
+/// the builder is linked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    at: usize,
+    proc: ProcId,
+}
+
+impl Relocation {
+    pub(crate) fn new(at: usize, proc: ProcId) -> Self {
+        Self { at, proc }
+    }
+}
 
 pub struct Builder {
-    version: Version,
-    output_type: OutputType,
-    entry_point: usize,
-    strings: Vec<Box<[u8]>>,
-    code: Vec<u8>,
+    image: DreamImage,
+    procs: Vec<Option<usize>>,
+    relocations: Vec<Relocation>,
+    entry: Option<ProcId>,
 }
 
 impl Builder {
     pub fn new(version: Version, output: OutputType) -> Self {
         Self {
-            version,
-            output_type: output,
-            entry_point: 0,
-            strings: vec![],
-            code: vec![],
+            image: DreamImage::new(version, output),
+            procs: vec![],
+            relocations: vec![],
+            entry: None,
         }
     }
 
-    pub fn set_entry(&mut self, entry: usize) {
-        self.entry_point = entry;
+    pub fn set_entry(&mut self, entry: ProcId) {
+        self.entry = Some(entry);
     }
 
+    /// Adds a string to the TEXT section, returning the offset to use with
+    /// `BlockBuilder::emit_map`. Identical strings are only stored once.
     pub fn add_string(&mut self, new: impl AsRef<[u8]>) -> usize {
-        let mut offset = 0;
-        for s in self.strings.iter() {
-            if s.as_ref() == new.as_ref() {
-                return offset;
-            }
-            offset += std::mem::size_of::<usize>() + s.len() + Self::PADDING;
+        let existing = self
+            .image
+            .strings
+            .iter()
+            .zip(self.image.string_offsets())
+            .find(|(s, _)| s.as_ref() == new.as_ref());
+        if let Some((_, offset)) = existing {
+            return offset;
         }
-        self.strings.push(Box::from(new.as_ref()));
-        offset + std::mem::size_of::<u64>()
+
+        self.image.strings.push(Box::from(new.as_ref()));
+        self.image
+            .string_offsets()
+            .last()
+            .expect("A string was just added.")
     }
 
-    pub fn procedure(&mut self, f: impl FnOnce(&mut ProcedureBuilder)) -> usize {
-        let proc_begin = self.code.len();
+    /// Creates a handle for a procedure whose body is given later with
+    /// `define_procedure`.
+    pub fn declare_procedure(&mut self) -> ProcId {
+        self.procs.push(None);
+        ProcId(self.procs.len() - 1)
+    }
 
-        let mut proc = ProcedureBuilder::new(&mut self.code);
-        f(&mut proc);
+    /// Defines the body of a declared procedure. If `f` fails the code it
+    /// emitted is discarded and the procedure stays undefined.
+    pub fn define_procedure(
+        &mut self,
+        id: ProcId,
+        f: impl FnOnce(&mut ProcedureBuilder) -> Result<()>,
+    ) -> Result<()> {
+        if self.procs[id.0].is_some() {
+            return Err(Error::ProcedureAlreadyDefined);
+        }
 
-        proc_begin
-    }
-}
+        let code_len = self.image.code.len();
+        let relocations_len = self.relocations.len();
 
-impl Builder {
-    const PADDING: usize = 8;
+        let mut proc = ProcedureBuilder::new(&mut self.image.code, &mut self.relocations);
+        if let Err(err) = f(&mut proc) {
+            self.image.code.truncate(code_len);
+            self.relocations.truncate(relocations_len);
+            return Err(err);
+        }
 
-    pub fn write_dream(&self, f: &mut dyn Write) -> Result<()> {
-        self.write_header(f)?;
-        self.write_text_section(f)?;
-        self.write_code_section(f)?;
+        self.procs[id.0] = Some(code_len);
         Ok(())
     }
 
-    fn write_header(&self, f: &mut dyn Write) -> Result<()> {
-        f.write_str("DREAM")?;
-        f.write_bytes(&self.version.as_bytes())?;
-        f.write_str("OUTT")?;
-        f.write_bytes(&self.output_type.as_bytes())?;
-        Ok(())
+    pub fn procedure(
+        &mut self,
+        f: impl FnOnce(&mut ProcedureBuilder) -> Result<()>,
+    ) -> Result<ProcId> {
+        let id = self.declare_procedure();
+        self.define_procedure(id, f)?;
+        Ok(id)
     }
 
-    fn write_text_section(&self, f: &mut dyn Write) -> Result<usize> {
-        let mut section_size = 0;
-
-        let strings_size: u64 = self
-            .strings
+    /// Resolves every procedure reference into a code offset. Fails with the
+    /// list of procedures that were referenced or used as the entry point but
+    /// never defined.
+    pub fn link(&self) -> Result<DreamImage> {
+        let mut undefined: Vec<ProcId> = self
+            .relocations
             .iter()
-            .map(|s| std::mem::size_of::<u64>() + s.len() + Self::PADDING)
-            .sum::<usize>() as u64;
+            .map(|reloc| reloc.proc)
+            .chain(self.entry)
+            .filter(|id| self.procs[id.0].is_none())
+            .collect();
+
+        if !undefined.is_empty() {
+            undefined.sort();
+            undefined.dedup();
+            return Err(Error::UndefinedProcedures(undefined));
+        }
 
-        section_size += f.write_str("TEXT")?;
-        section_size += f.pad(4)?;
-        section_size += f.write_bytes(&strings_size.to_le_bytes())?;
+        let mut image = self.image.clone();
+        for reloc in self.relocations.iter() {
+            let offset = self.procs[reloc.proc.0].unwrap() as u64;
+            image.code[reloc.at..reloc.at + 8].copy_from_slice(&offset.to_le_bytes());
+        }
 
-        for s in self.strings.iter() {
-            section_size += f.write_bytes(&s.len().to_le_bytes())?;
-            section_size += f.write_bytes(s.as_ref())?;
-            section_size += f.write_bytes(&[0; Self::PADDING])?;
+        if let Some(entry) = self.entry {
+            image.entry_point = self.procs[entry.0].unwrap();
         }
 
-        Ok(section_size)
+        Ok(image)
     }
+}
 
-    fn write_code_section(&self, f: &mut dyn Write) -> Result<usize> {
-        let mut section_size = 0;
-
-        section_size += f.write_str("CODE")?;
-        section_size += f.pad(4)?;
-        section_size += f.write_bytes(&self.code.len().to_le_bytes())?;
-        section_size += f.write_bytes(&self.entry_point.to_le_bytes())?;
-
-        section_size += f.write_bytes(&self.code)?;
-
-        Ok(section_size)
+impl Builder {
+    pub fn write_dream(&self, f: &mut dyn Write) -> Result<()> {
+        self.link()?.write(f)
     }
 }
 
@@ -104,7 +148,7 @@ impl Builder {
 mod tests {
     use std::fs::File;
 
-    use quicksand::{Register, RegisterType};
+    use quicksand::{Instruction, Register, RegisterType};
 
     use crate::Operand;
 
@@ -114,7 +158,7 @@ mod tests {
     fn write_header_bin() {
         let builder = Builder::new(Version::from(0), OutputType::Bin);
         let mut output = String::new();
-        let result = builder.write_header(&mut output);
+        let result = builder.image.write_header(&mut output);
         assert!(result.is_ok());
         assert_eq!(output.as_str(), "DREAM000OUTT\x00\x00\x00\x00");
     }
@@ -123,7 +167,7 @@ mod tests {
     fn write_header_lib() {
         let builder = Builder::new(Version::from(0), OutputType::Lib);
         let mut output = String::new();
-        let result = builder.write_header(&mut output);
+        let result = builder.image.write_header(&mut output);
         assert!(result.is_ok());
         assert_eq!(output.as_str(), "DREAM000OUTT\x01\x00\x00\x00");
     }
@@ -132,7 +176,7 @@ mod tests {
     fn write_header_to_file() {
         let builder = Builder::new(Version::from(87), OutputType::Bin);
         let mut file = File::create("tests/test_write_header.bin").unwrap();
-        let result = builder.write_header(&mut file);
+        let result = builder.image.write_header(&mut file);
         assert!(result.is_ok());
     }
 
@@ -145,7 +189,7 @@ mod tests {
         builder.add_string("world!");
         builder.add_string("");
 
-        let result = builder.write_text_section(&mut output);
+        let result = builder.image.write_text_section(&mut output);
         assert!(result.is_ok());
     }
 
@@ -154,23 +198,22 @@ mod tests {
         let mut builder = Builder::new(Version::from(0), OutputType::Bin);
         let mut output = File::create("tests/test_write_procedure.bin").unwrap();
 
-        builder.procedure(|proc| {
-            proc.body(|block| {
-                block
-                    .emit_move(
+        builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_move(
                         Operand::reg(Register::new(RegisterType::Q, 0).unwrap()),
                         Operand::lit64(69),
                         None,
-                    )
-                    .unwrap();
+                    )?;
 
-                block
-                    .emit_move(Operand::reg(Register::RSI), Operand::lit64(1), None)
-                    .unwrap();
+                    block.emit_move(Operand::reg(Register::RSI), Operand::lit64(1), None)?;
+                    Ok(())
+                })
             })
-        });
+            .unwrap();
 
-        output.write_bytes(&builder.code).unwrap();
+        output.write_bytes(&builder.image.code).unwrap();
     }
 
     #[test]
@@ -178,32 +221,27 @@ mod tests {
         let mut builder = Builder::new(Version::from(0), OutputType::Bin);
         let mut output = File::create("tests/test_write_hello_world_procedure.bin").unwrap();
 
-        builder.procedure(|proc| {
-            proc.body(|block| {
-                block
-                    .emit_move(Operand::reg(Register::RSI), Operand::lit64(1), None)
-                    .unwrap();
-                block
-                    .emit_move(
+        builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_move(Operand::reg(Register::RSI), Operand::lit64(1), None)?;
+                    block.emit_move(
                         Operand::reg(Register::new(RegisterType::S, 0).unwrap()),
                         Operand::lit64(2),
                         None,
-                    )
-                    .unwrap();
-                block
-                    .emit_map(Register::new(RegisterType::S, 1).unwrap(), 0)
-                    .unwrap();
-                block
-                    .emit_move(
+                    )?;
+                    block.emit_map(Register::new(RegisterType::S, 1).unwrap(), 0)?;
+                    block.emit_move(
                         Operand::reg(Register::new(RegisterType::S, 2).unwrap()),
                         Operand::lit64(11),
                         None,
-                    )
-                    .unwrap();
+                    )?;
+                    Ok(())
+                })
             })
-        });
+            .unwrap();
 
-        output.write_bytes(&builder.code).unwrap();
+        output.write_bytes(&builder.image.code).unwrap();
     }
 
     #[test]
@@ -213,35 +251,160 @@ mod tests {
 
         let str_idx = builder.add_string("Hello world!\n");
 
-        let proc_idx = builder.procedure(|proc| {
-            proc.body(|block| {
-                block
-                    .emit_move(Operand::reg(Register::RSI), Operand::lit64(1), None)
-                    .unwrap();
-                block
-                    .emit_move(
+        let proc_idx = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_move(Operand::reg(Register::RSI), Operand::lit64(1), None)?;
+                    block.emit_move(
                         Operand::reg(Register::new(RegisterType::S, 0).unwrap()),
                         Operand::lit64(2),
                         None,
-                    )
-                    .unwrap();
-                block
-                    .emit_map(Register::new(RegisterType::S, 1).unwrap(), str_idx as u64)
-                    .unwrap();
-                block
-                    .emit_move(
-                        Operand::reg(Register::new(RegisterType::S, 2).unwrap()),
-                        Operand::lit64(11),
-                        None,
-                    )
-                    .unwrap();
-                block.emit_syscall(3).unwrap();
+                    )?;
+                    // Puts the address in rs1 and the length in rs2.
+                    block.emit_map(Register::new(RegisterType::S, 1).unwrap(), str_idx as u64)?;
+                    block.emit_syscall(3)?;
+                    Ok(())
+                })
             })
-        });
+            .unwrap();
 
         builder.set_entry(proc_idx);
 
         let result = builder.write_dream(&mut output);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn string_offsets() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let hello = builder.add_string("hello");
+        let world = builder.add_string("world!");
+        assert_eq!(hello, 8);
+        assert_eq!(world, 8 + 5 + 8 + 8);
+        assert_eq!(builder.add_string("hello"), hello);
+        assert_eq!(builder.add_string("world!"), world);
+        assert_eq!(builder.image.strings.len(), 2);
+
+        let text = builder.image.text();
+        assert_eq!(&text[world..world + 6], b"world!");
+    }
+
+    #[test]
+    fn write_dream_round_trip() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        builder.add_string("Hello world!\n");
+        let proc_idx = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_set(Register::RSI);
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(proc_idx);
+
+        let mut bytes = vec![];
+        builder.write_dream(&mut bytes).unwrap();
+
+        let image = DreamImage::parse(&bytes).unwrap();
+        assert_eq!(image, builder.link().unwrap());
+    }
+
+    #[test]
+    fn forward_references() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let callee = builder.declare_procedure();
+
+        let entry = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_call(callee);
+                    block.emit_proc_addr(Register::new(RegisterType::Q, 0).unwrap(), callee)?;
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+
+        builder
+            .define_procedure(callee, |proc| proc.body(|_| Ok(())))
+            .unwrap();
+
+        let image = builder.link().unwrap();
+        let callee_offset = 1 + 8 + 1 + 1 + 8 + 1;
+        assert_eq!(image.entry_point, 0);
+        assert_eq!(&image.code[1..9], &(callee_offset as u64).to_le_bytes());
+        assert_eq!(&image.code[11..19], &(callee_offset as u64).to_le_bytes());
+    }
+
+    #[test]
+    fn undefined_procedures() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let a = builder.declare_procedure();
+        let b = builder.declare_procedure();
+        let entry = builder.declare_procedure();
+        builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_call(b);
+                    block.emit_call(a);
+                    block.emit_call(b);
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+
+        match builder.link() {
+            Err(Error::UndefinedProcedures(undefined)) => assert_eq!(undefined, vec![a, b, entry]),
+            other => panic!("expected undefined procedures, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn define_twice() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let id = builder.procedure(|proc| proc.body(|_| Ok(()))).unwrap();
+        let result = builder.define_procedure(id, |proc| proc.body(|_| Ok(())));
+        assert!(matches!(result, Err(Error::ProcedureAlreadyDefined)));
+    }
+
+    #[test]
+    fn unbound_label() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let callee = builder.declare_procedure();
+        let result = builder.procedure(|proc| {
+            proc.body(|block| {
+                let label = block.new_label();
+                block.emit_call(callee);
+                block.emit_jmp(label);
+                Ok(())
+            })
+        });
+        assert!(matches!(result, Err(Error::UnboundLabel)));
+
+        // The failed body leaves nothing behind.
+        builder
+            .define_procedure(callee, |proc| proc.body(|_| Ok(())))
+            .unwrap();
+        let image = builder.link().unwrap();
+        assert_eq!(image.code, [Instruction::Ret as u8]);
+    }
+
+    #[test]
+    fn body_errors_are_returned() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let result = builder.procedure(|proc| {
+            proc.body(|block| {
+                let label = block.new_label();
+                block.emit_noop();
+                block.bind_label(label)?;
+                block.bind_label(label)?;
+                block.emit_noop();
+                Ok(())
+            })
+        });
+        assert!(matches!(result, Err(Error::LabelAlreadyBound)));
+        assert!(builder.image.code.is_empty());
+    }
 }
@@ -8,7 +8,7 @@ pub use proc_builder::*;
 
 use crate::errors::{Error, Result};
 
-use quicksand::{OperandType, Register};
+use quicksand::{DecodedOperand, OperandEncoding, OperandType, Register};
 use std::io::Write as _;
 
 pub trait Write {
@@ -45,6 +45,7 @@ macro_rules! impl_io_write {
 }
 
 impl_io_write!(std::fs::File);
+impl_io_write!(Vec<u8>);
 impl_io_write!(std::io::Cursor<&mut Vec<u8>>);
 impl_io_write!(std::io::Stdout);
 impl_io_write!(std::io::Stderr);
@@ -57,19 +58,7 @@ impl Write for String {
     }
 }
 
-trait IteratorExt: Iterator {
-    fn consume(&mut self) {
-        loop {
-            let n = self.next();
-            if n.is_none() {
-                break;
-            }
-        }
-    }
-}
-
-impl<I: Iterator> IteratorExt for I {}
-
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Operand {
     kind: OperandType,
     value: u64,
@@ -96,6 +85,73 @@ impl Operand {
             value: lit,
         }
     }
+
+    /// A float immediate for D registers.
+    pub fn lit_f32(lit: f32) -> Self {
+        Self::lit64(lit.to_bits() as u64)
+    }
+
+    /// A float immediate for Q registers.
+    pub fn lit_f64(lit: f64) -> Self {
+        Self::lit64(lit.to_bits())
+    }
+
+    /// Parses an operand of type `kind`, written the way it is displayed.
+    /// Fails with `BadOperandType` for an operand of another type.
+    pub fn parse(s: &str, kind: OperandType) -> Result<Self> {
+        let operand = OperandEncoding::from(kind)
+            .parse(s)
+            .map_err(|err| parse_error(s, err))?;
+        Self::from_decoded(operand).ok_or(Error::BadOperandType)
+    }
+
+    fn from_decoded(operand: DecodedOperand) -> Option<Self> {
+        match operand {
+            DecodedOperand::Register(reg) => Some(Self::reg(reg)),
+            DecodedOperand::Address(addr) => Some(Self::addr(addr)),
+            DecodedOperand::Lit64(lit) => Some(Self::lit64(lit)),
+            DecodedOperand::Stack(_) | DecodedOperand::Frame(_) => None,
+        }
+    }
+
+    /// The operand as `quicksand::encode` takes it.
+    fn to_decoded(self) -> DecodedOperand {
+        match self.kind {
+            // Register operands can only be made from a `Register`.
+            OperandType::Register => {
+                DecodedOperand::Register(Register::try_from(self.value as u8).unwrap())
+            }
+            OperandType::Address => DecodedOperand::Address(self.value),
+            OperandType::Lit64 => DecodedOperand::Lit64(self.value),
+        }
+    }
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_decoded())
+    }
+}
+
+impl std::str::FromStr for Operand {
+    type Err = Error;
+
+    /// Parses an operand the way it is displayed, e.g. `rq0`, `[16]` or `$42`.
+    fn from_str(s: &str) -> Result<Self> {
+        let operand = s.parse().map_err(|err| parse_error(s, err))?;
+        Self::from_decoded(operand).ok_or(Error::BadOperandType)
+    }
+}
+
+/// Turns an error from parsing the operand `s` into the one for its type.
+fn parse_error(s: &str, err: quicksand::Error) -> Error {
+    match err {
+        quicksand::Error::WrongOperandKind => Error::BadOperandType,
+        quicksand::Error::LiteralOutOfRange => Error::BadOperandValue,
+        _ if s.starts_with('$') => Error::InvalidLit64,
+        _ if s.starts_with('[') => Error::InvalidAddr,
+        _ => Error::InvalidRegister,
+    }
 }
 
 #[repr(u32)]
@@ -111,6 +167,17 @@ impl OutputType {
     }
 }
 
+impl std::str::FromStr for OutputType {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "Bin" => Ok(Self::Bin),
+            "Lib" => Ok(Self::Lib),
+            _ => Err(Error::InvalidOutputType),
+        }
+    }
+}
+
 impl TryFrom<u32> for OutputType {
     type Error = Error;
     fn try_from(value: u32) -> std::result::Result<Self, Self::Error> {
@@ -123,3 +190,40 @@ impl TryFrom<u32> for OutputType {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operand_text() {
+        let operands = [
+            ("rsi", Operand::reg(Register::RSI)),
+            ("[16]", Operand::addr(16)),
+            ("$42", Operand::lit64(42)),
+        ];
+        for (text, operand) in operands {
+            assert_eq!(operand.to_string(), text);
+            assert_eq!(text.parse::<Operand>().unwrap(), operand);
+            assert_eq!(Operand::parse(text, operand.kind).unwrap(), operand);
+        }
+
+        assert!(matches!(
+            Operand::parse("$42", OperandType::Address),
+            Err(Error::BadOperandType)
+        ));
+        assert!(matches!(
+            "[stk+8]".parse::<Operand>(),
+            Err(Error::BadOperandType)
+        ));
+        assert!(matches!(
+            "$18446744073709551616".parse::<Operand>(),
+            Err(Error::BadOperandValue)
+        ));
+        assert!(matches!(
+            "rq99".parse::<Operand>(),
+            Err(Error::InvalidRegister)
+        ));
+        assert!(matches!("[x]".parse::<Operand>(), Err(Error::InvalidAddr)));
+        assert!(matches!("$x".parse::<Operand>(), Err(Error::InvalidLit64)));
+    }
+}
@@ -1,28 +1,21 @@
-use quicksand::Instruction;
-
-use super::block_builder::BlockBuilder;
+use super::{block_builder::BlockBuilder, dream_builder::Relocation};
+use crate::errors::Result;
 
 pub struct ProcedureBuilder<'out> {
     out: &'out mut Vec<u8>,
+    relocations: &'out mut Vec<Relocation>,
 }
 
 impl<'out> ProcedureBuilder<'out> {
-    pub fn new(out: &'out mut Vec<u8>) -> Self {
-        Self { out }
+    pub fn new(out: &'out mut Vec<u8>, relocations: &'out mut Vec<Relocation>) -> Self {
+        Self { out, relocations }
     }
 
-    pub fn body(&mut self, f: impl FnOnce(&mut BlockBuilder)) {
-        {
-            let mut block = BlockBuilder::new(self.out);
-            f(&mut block);
-        }
-        if self
-            .out
-            .last()
-            .filter(|&&l| l == Instruction::Ret as u8)
-            .is_none()
-        {
-            self.out.push(Instruction::Ret as u8);
-        }
+    /// Fails with the first error `f` returns, or with `Error::UnboundLabel`
+    /// if the body jumps to a label that was never bound.
+    pub fn body(&mut self, f: impl FnOnce(&mut BlockBuilder) -> Result<()>) -> Result<()> {
+        let mut block = BlockBuilder::new(self.out, self.relocations);
+        f(&mut block)?;
+        block.finish()
     }
 }
@@ -1,159 +1,108 @@
-use std::iter::Peekable;
+use std::collections::{BTreeMap, BTreeSet};
 
-use quicksand::{Instruction, Register};
+use quicksand::{decode, float_literal, DecodedOperand};
 
-use crate::{Error, OutputType, Result, Version, Write};
+use crate::{DisassemblyOptions, DreamImage, Error, OutputType, Result, Version, Write};
 
-pub struct Disassembler<'out, I: IntoIterator<Item = u8>> {
-    bytes: Peekable<I::IntoIter>,
+pub struct Disassembler<'img, 'out> {
+    image: &'img DreamImage,
+    bytes: std::slice::Iter<'img, u8>,
     offset: usize,
     out: &'out mut dyn Write,
-    version: u32,
-    output_type: OutputType,
+    options: DisassemblyOptions,
+    /// Length of the line being written, to align the raw bytes.
+    column: usize,
+    scanning: bool,
+    inst_starts: BTreeSet<usize>,
+    jump_targets: BTreeSet<usize>,
+    labels: BTreeMap<usize, usize>,
 }
 
-impl<'out, I> Disassembler<'out, I>
-where
-    I: IntoIterator<Item = u8>,
-{
-    pub fn new(bytes: I, out: &'out mut dyn Write) -> Self {
+impl<'img, 'out> Disassembler<'img, 'out> {
+    pub fn new(image: &'img DreamImage, out: &'out mut dyn Write) -> Self {
+        Self::with_options(image, DisassemblyOptions::default(), out)
+    }
+
+    pub fn with_options(
+        image: &'img DreamImage,
+        options: DisassemblyOptions,
+        out: &'out mut dyn Write,
+    ) -> Self {
         Self {
-            bytes: bytes.into_iter().peekable(),
+            image,
+            bytes: image.code.iter(),
             offset: 0,
             out,
-            version: 0,
-            output_type: OutputType::Bin,
+            options,
+            column: 0,
+            scanning: false,
+            inst_starts: BTreeSet::new(),
+            jump_targets: BTreeSet::new(),
+            labels: BTreeMap::new(),
         }
     }
 }
 
-impl<'out, I> Disassembler<'out, I> 
-where
-    I: IntoIterator<Item = u8>,
-{
+impl<'img, 'out> Disassembler<'img, 'out> {
+    /// Disassembles the instruction at `offset` into `code` on its own,
+    /// returning its size.
+    pub fn disassemble_at(code: &[u8], offset: usize, out: &mut dyn Write) -> Result<usize> {
+        let image = DreamImage::new(Version::from(0), OutputType::Bin);
+        let mut dismblr = Disassembler::new(&image, out);
+        let end_of_code = Error::DisassembleFailure(quicksand::Error::UnexpectedEndOfCode);
+        dismblr.bytes = code.get(offset..).ok_or(end_of_code)?.iter();
+        dismblr.offset = offset;
+        dismblr.disassemble_instruction()?;
+        Ok(dismblr.offset - offset)
+    }
+
     pub fn disassemble(&mut self) -> Result<()> {
         self.disassemble_header()?;
-
-        let mut disassembled_text = false;
-        let mut disassembled_code = false;
-
-        while self.peek().is_some() {
-            if self.matches_all(b"TEXT") {
-                if disassembled_text {
-                    eprintln!("ERROR: Cannot have more than one TEXT section in a dream file.");
-                    return Err(Error::DisassembleFailure);
-                }
-                disassembled_text = true;
-                self.disassemble_text_section()?;
-            } else if self.matches_all(b"CODE") {
-                if disassembled_code {
-                    eprintln!("ERROR: Cannot have more than one CODE section in a dream file.");
-                    return Err(Error::DisassembleFailure);
-                }
-                disassembled_code = true;
-                self.disassemble_code_section()?;
-            } else {
-                eprintln!("ERROR: Didn't encounter valid section header while parsing dream file.");
-                return Err(Error::DisassembleFailure);
-            }
-        }
-
+        self.disassemble_text_section()?;
+        self.disassemble_code_section()?;
         Ok(())
     }
 
-    fn peek(&mut self) -> Option<u8> {
-        self.bytes.peek().copied()
-    }
-
-    fn next(&mut self) -> Option<u8> {
-        self.offset += 1;
-        self.bytes.next()
-    }
-
-    fn matches(&mut self, byte: u8) -> bool {
-        if self.bytes.next_if_eq(&byte).is_some() {
-            self.offset += 1;
-            true
-        } else {
-            false
-        }
-    }
-
-    fn matches_all(&mut self, bytes: &[u8]) -> bool {
-        for &b in bytes {
-            if !self.matches(b) {
-                return false;
-            }
-        }
-        return true;
-    }
-
-    fn extract(&mut self, dst: &mut [u8]) -> Result<()> {
-        for i in 0..dst.len() {
-            dst[i] = self.next().ok_or_else(|| {
-                eprintln!("ERROR: Unexpected end of dream file.");
-                Error::DisassembleFailure
-            })?;
+    /// Writes `s` to the output unless we are only scanning for labels.
+    fn emit(&mut self, s: &str) -> Result<()> {
+        if !self.scanning {
+            self.out.write_str(s)?;
+            self.column = match s.rfind('\n') {
+                Some(newline) => s.len() - newline - 1,
+                None => self.column + s.len(),
+            };
         }
         Ok(())
     }
 
-    fn extract_u32(&mut self) -> Result<u32> {
-        let mut bytes = [0u8; std::mem::size_of::<u32>()];
-        self.extract(&mut bytes)?;
-        Ok(u32::from_le_bytes(bytes))
-    }
+    /// Writes the encoding of the instruction starting at `start` as a
+    /// comment, so that the listing still assembles.
+    fn emit_raw_bytes(&mut self, start: usize) -> Result<()> {
+        const BYTES_COLUMN: usize = 48;
 
-    fn extract_u64(&mut self) -> Result<u64> {
-        let mut bytes = [0u8; std::mem::size_of::<u64>()];
-        self.extract(&mut bytes)?;
-        Ok(u64::from_le_bytes(bytes))
+        let code_begin = self.image.code_offset() + DreamImage::CODE_HEADER_SIZE;
+        let bytes = &self.image.code[start - code_begin..self.offset - code_begin];
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02X}")).collect();
+        let padding = BYTES_COLUMN.saturating_sub(self.column).max(1);
+        self.emit(&format!("{:padding$}; {}", "", hex.join(" ")))
     }
 
-    fn extract_reg(&mut self) -> Result<Register> {
-        let reg = self.next().ok_or(Error::DisassembleFailure)?;
-        reg.try_into().or(Err(Error::DisassembleFailure))
+    /// Writes a code offset as a label, remembering it as a label to create
+    /// while scanning.
+    fn emit_target(&mut self, target: usize) -> Result<()> {
+        self.jump_targets.insert(target);
+        match self.labels.get(&target) {
+            Some(n) => self.emit(&format!("L{n}")),
+            None => self.emit(&format!("${target}")),
+        }
     }
 
     fn disassemble_header(&mut self) -> Result<()> {
-        if !self.matches_all(b"DREAM") {
-            eprintln!("ERROR: Not a dream file.");
-            return Err(Error::DisassembleFailure);
-        }
-
-        let mut version_bytes = [0u8; 3];
-        self.extract(&mut version_bytes)?;
-
-        let version: Version = std::str::from_utf8(&version_bytes)
-            .map_err(|err| {
-                eprintln!("ERROR: Version bytes not valid UTF-8: {err}");
-                Error::DisassembleFailure
-            })?
-            .parse().map_err(|_| {
-                eprintln!("ERROR: Failed to parse version number.");
-                Error::DisassembleFailure
-            })?;
-
-        self.version = version.as_u32();
-
         self.out.write_str("#Version ")?;
-        self.out.write_bytes(&version_bytes).map_err(|err| {
-            eprintln!("ERROR: Failed to write version to disassembly file.");
-            err
-        })?;
+        self.out.write_bytes(&self.image.version.as_bytes())?;
         self.out.write_chr('\n')?;
 
-        if !self.matches_all(b"OUTT") {
-            eprintln!("ERROR: No output type found for dream file.");
-            return Err(Error::DisassembleFailure);
-        }
-
-        self.output_type = self.extract_u32().and_then(|ot| OutputType::try_from(ot)).map_err(|_| {
-            eprintln!("ERROR: Failed to parse output type of dream file.");
-            Error::DisassembleFailure
-        })?;
-
-        let output_type_line = format!("#OutputType {:?}\n", self.output_type);
+        let output_type_line = format!("#OutputType {:?}\n", self.image.output_type);
         self.out.write_str(&output_type_line)?;
 
         self.out.write_chr('\n')?;
@@ -162,53 +111,23 @@ where
     }
 
     fn disassemble_text_section(&mut self) -> Result<()> {
-        if !self.matches_all(&[0u8; 4]) {
-            eprintln!("ERROR: No padding bytes after 'TEXT' header.");
-            return Err(Error::DisassembleFailure);
-        }
-
-        self.out.write_str(&format!("{:08X}  TEXT:\n", self.offset - 8))?;
-
-        let data_size = self.extract_u64().map_err(|_| {
-            eprintln!("ERROR: Failed to parse data size of TEXT section.");
-            Error::DisassembleFailure
-        })? as usize;
-
-        let mut data_remaining = data_size;
-        while data_remaining > 0 {
-            let string_offset = self.offset;
-
-            let string_size = self.extract_u64().map_err(|_| {
-                eprintln!("ERROR: Failed to parse length of string in TEXT section.");
-                Error::DisassembleFailure
-            })?;
-
-            data_remaining -= std::mem::size_of::<u64>();
+        let text_offset = self.image.text_offset();
+        self.out.write_str(&format!("{text_offset:08X}  TEXT:\n"))?;
 
+        let mut string_offset = text_offset + DreamImage::SECTION_HEADER_SIZE;
+        for s in self.image.strings.iter() {
             self.out.write_str(&format!("{string_offset:08X}      \""))?;
 
-            for _ in 0..string_size {
-                let c = self.next().ok_or_else(|| {
-                    eprintln!("ERROR: Unexpected end of dream file while parsing TEXT section.");
-                    Error::DisassembleFailure
-                })?;
-
-                data_remaining -= 1;
-
+            for &c in s.iter() {
                 let escaped = std::ascii::escape_default(c);
                 for e in escaped {
                     self.out.write_bytes(&[e])?;
                 }
             }
 
-            if !self.matches_all(&[0u8; 8]) {
-                eprintln!("ERROR: Did not encounter padding bytes after string data in TEXT section.");
-                return Err(Error::DisassembleFailure);
-            }
-
-            data_remaining -= 8;
-
             self.out.write_str("\"\n")?;
+
+            string_offset += std::mem::size_of::<u64>() + s.len() + DreamImage::STRING_PADDING;
         }
 
         self.out.write_chr('\n')?;
@@ -217,175 +136,317 @@ where
     }
 
     fn disassemble_code_section(&mut self) -> Result<()> {
-        if !self.matches_all(&[0u8; 4]) {
-            eprintln!("ERROR: No padding bytes after 'CODE' header.");
-            return Err(Error::DisassembleFailure);
-        }
+        let code_offset = self.image.code_offset();
+        self.out.write_str(&format!("{code_offset:08X}  CODE:\n"))?;
+
+        self.scan_labels()?;
+        self.disassemble_instructions()
+    }
 
-        self.out.write_str(&format!("{:08X}  CODE:\n", self.offset - 8))?;
+    /// Code offsets of the `L0`, `L1`, ... labels the listing gives to jump
+    /// targets, in order.
+    pub fn labels(&mut self) -> Result<Vec<usize>> {
+        self.scan_labels()?;
+        Ok(self.labels.keys().copied().collect())
+    }
+
+    /// Jump targets are only known after walking every instruction so this
+    /// does a silent pass first to give them labels.
+    fn scan_labels(&mut self) -> Result<()> {
+        self.scanning = true;
+        self.disassemble_instructions()?;
+        self.scanning = false;
+
+        self.labels = self
+            .jump_targets
+            .iter()
+            .filter(|target| self.inst_starts.contains(target))
+            .enumerate()
+            .map(|(n, &target)| (target, n))
+            .collect();
 
-        let code_size = self.extract_u64().map_err(|_| {
-            eprintln!("ERROR: Failed to parse length of code section.");
-            Error::DisassembleFailure
-        })?;
+        Ok(())
+    }
 
-        let entry_point = self.extract_u64().map_err(|_| {
-            eprintln!("ERROR: Failed to parse entry point in code section.");
-            Error::DisassembleFailure
-        })?;
+    fn disassemble_instructions(&mut self) -> Result<()> {
+        let entry_point = self.image.entry_point;
 
+        self.bytes = self.image.code.iter();
+        self.offset = self.image.code_offset() + DreamImage::CODE_HEADER_SIZE;
         let code_begin = self.offset;
+        let code_end = code_begin + self.image.code.len();
 
-        let mut code_remaining = code_size;
-        while code_remaining > 0 {
+        while self.offset < code_end {
             let inst_offset = self.offset;
 
-            let inst = self.next().ok_or_else(|| {
-                eprintln!("ERROR: Unexpected end of dream file in CODE section.");
-                Error::DisassembleFailure
-            })?;
+            if inst_offset - code_begin == entry_point {
+                self.emit("ENTRY:\n")?;
+            }
 
-            code_remaining -= 1;
+            self.inst_starts.insert(inst_offset - code_begin);
+            if let Some(n) = self.labels.get(&(inst_offset - code_begin)) {
+                self.emit(&format!("L{n}:\n"))?;
+            }
 
-            let is_alt = inst & Instruction::ALT_MODE != 0;
-            let inst: Instruction = inst.try_into().map_err(|_| {
-                eprintln!("ERROR: Invalid instruction in CODE section.");
-                Error::DisassembleFailure
-            })?;
+            self.emit(&format!("{inst_offset:08X}      "))?;
+            self.disassemble_instruction()?;
 
-            if inst_offset - code_begin == entry_point as usize {
-                self.out.write_str("ENTRY:\n")?;
+            if self.options.raw_bytes {
+                self.emit_raw_bytes(inst_offset)?;
             }
+            self.emit("\n")?;
+        }
 
-            let inst_str = format!("{inst:?}");
-            self.out.write_str(&format!("{inst_offset:08X}      {inst_str:<12}"))?;
-
-            match inst {
-                Instruction::NoOp => {}
-                Instruction::Move => {
-                    if is_alt {
-                        let dst = self.extract_u64()?;
-                        code_remaining -= 8;
-                        let src = self.extract_reg()?;
-                        code_remaining -= 1;
-                        self.out.write_str(&format!("[{dst}], {src}"))?;
-                    } else {
-                        let dst = self.extract_reg()?;
-                        code_remaining -= 1;
-                        let src = self.extract_reg()?;
-                        code_remaining -= 1;
-                        self.out.write_str(&format!("{dst}, {src}"))?;
-                    }
-                }
-                Instruction::MoveImm => {
-                    if is_alt {
-                        let dst = self.extract_u64()?;
-                        code_remaining -= 8;
-                        let value = self.extract_u64()?;
-                        code_remaining -= 8;
-                        self.out.write_str(&format!("[{dst}], ${value}"))?;
-                    } else {
-                        let dst = self.extract_reg()?;
-                        code_remaining -= 1;
-                        let value = self.extract_u64()?;
-                        code_remaining -= 8;
-                        self.out.write_str(&format!("{dst}, ${value}"))?;
-                    }
-                }
-                Instruction::MoveAddr => {
-                    if is_alt {
-                        let dst = self.extract_u64()?;
-                        code_remaining -= 8;
-                        let src = self.extract_u64()?;
-                        code_remaining -= 8;
-                        let size = self.extract_u64()?;
-                        code_remaining -= 8;
-                        self.out.write_str(&format!("[{dst}], [{src}], ${size}"))?;
-                    } else {
-                        let dst = self.extract_reg()?;
-                        code_remaining -= 1;
-                        let src = self.extract_u64()?;
-                        code_remaining -= 8;
-                        self.out.write_str(&format!("{dst}, [{src}]"))?;
-                    }
-                }
-                Instruction::Clear => {
-                    if is_alt {
-                        return Err(Error::InvalidInstruction);
-                    }
-                    let reg = self.extract_reg()?;
-                    code_remaining -= 1;
-                    self.out.write_str(&format!("{reg}"))?;
-                }
-                Instruction::Set => {
-                    if is_alt {
-                        return Err(Error::InvalidInstruction);
-                    }
-                    let reg = self.extract_reg()?;
-                    code_remaining -= 1;
-                    self.out.write_str(&format!("{reg}"))?;
-                }
-                Instruction::Push => {
-                    if is_alt {
-                        let src = self.extract_u64()?;
-                        code_remaining -= 8;
-                        self.out.write_str(&format!("[{src}]"))?;
-                    } else {
-                        let src = self.extract_reg()?;
-                        code_remaining -= 1;
-                        self.out.write_str(&format!("{src}"))?;
-                    }
-                }
-                Instruction::PushImm => {
-                    if is_alt {
-                        return Err(Error::InvalidInstruction);
-                    }
-                    let value = self.extract_u64()?;
-                    code_remaining -= 8;
-                    self.out.write_str(&format!("${value}"))?;
-                }
-                Instruction::Pop => {
-                    if is_alt {
-                        return Err(Error::InvalidInstruction);
-                    }
-                    let reg = self.extract_reg()?;
-                    code_remaining -= 1;
-                    self.out.write_str(&format!("{reg}"))?;
-                }
-                Instruction::StackLoad => {
-                    if is_alt {
-                        return Err(Error::InvalidInstruction);
-                    }
-                    let dst = self.extract_reg()?;
-                    code_remaining -= 1;
-                    let src = self.extract_u64()?;
-                    code_remaining -= 8;
-                    self.out.write_str(&format!("{dst}, [stk+{src}]"))?;
-                }
-                Instruction::Map => {
-                    if is_alt {
-                        return Err(Error::InvalidInstruction);
-                    }
-                    let dst = self.extract_reg()?;
-                    code_remaining -= 1;
-                    let index = self.extract_u64()?;
-                    code_remaining -= 8;
-                    self.out.write_str(&format!("{dst}, ${index}"))?;
+        Ok(())
+    }
+
+    /// Disassembles the instruction at the current offset, leaving the offset
+    /// just past it.
+    fn disassemble_instruction(&mut self) -> Result<()> {
+        let code = self.bytes.as_slice();
+        let (decoded, size) = decode(code).map_err(Error::DisassembleFailure)?;
+        self.bytes = code[size..].iter();
+        self.offset += size;
+
+        let inst = decoded.inst;
+        self.emit(&format!("{:<12}", inst.mnemonic()))?;
+
+        match (inst, decoded.operands()) {
+            (inst, &[DecodedOperand::Lit64(target)]) if inst.is_jump() || inst.is_call() => {
+                self.emit_target(target as usize)?;
+            }
+            (inst, &[DecodedOperand::Register(dst), DecodedOperand::Lit64(bits)])
+                if inst.is_float() =>
+            {
+                self.emit(&format!("{dst}, {}", float_literal(dst, bits)))?;
+            }
+            (inst, operands) => {
+                let mut operands: Vec<String> = operands.iter().map(|op| op.to_string()).collect();
+                if inst.is_reversed() {
+                    operands.reverse();
                 }
-                Instruction::Syscall0 => {}
-                Instruction::Syscall1 => {}
-                Instruction::Syscall2 => {}
-                Instruction::Syscall3 => {}
-                Instruction::Syscall4 => {}
-                Instruction::Syscall5 => {}
-                Instruction::Syscall6 => {}
-                Instruction::Ret => {}
+                self.emit(&operands.join(", "))?;
             }
-
-            self.out.write_chr('\n')?;
         }
 
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use quicksand::{Instruction, Register, RegisterType};
+
+    use crate::{Builder, Operand, OutputType, Version};
+
+    use super::*;
+
+    fn disassemble_code(f: impl FnOnce(&mut crate::BlockBuilder) -> crate::Result<()>) -> String {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        builder.procedure(|proc| proc.body(f)).unwrap();
+
+        let mut out = String::new();
+        crate::disassemble_image(&builder.link().unwrap(), &mut out).unwrap();
+
+        out.lines()
+            .skip_while(|line| !line.ends_with("ENTRY:"))
+            .skip(1)
+            .map(|line| line.get(14..).unwrap_or(line).trim_end())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn arithmetic() {
+        let q0 = Register::new(RegisterType::Q, 0).unwrap();
+        let b1 = Register::new(RegisterType::B, 1).unwrap();
+        let listing = disassemble_code(|block| {
+            block.emit_add(q0, Operand::lit64(42))?;
+            block.emit_idiv(b1, Operand::reg(q0))?;
+            block.emit_neg(q0);
+            Ok(())
+        });
+
+        assert_eq!(
+            listing,
+            "Add         rq0, $42\nIDiv        rb1, rq0\nNeg         rq0\nRet"
+        );
+    }
+
+    #[test]
+    fn stack_frames() {
+        let q0 = Register::new(RegisterType::Q, 0).unwrap();
+        let d1 = Register::new(RegisterType::D, 1).unwrap();
+        let listing = disassemble_code(|block| {
+            block.emit_reserve(16);
+            block.emit_stack_store(8, q0);
+            block.emit_frame_load(d1, -8);
+            block.emit_frame_store(0, d1);
+            block.emit_release(16);
+            block.emit_move_sx(q0, d1)?;
+            block.emit_load_sx(d1, 0x20, 2)?;
+            Ok(())
+        });
+
+        assert_eq!(
+            listing,
+            "Reserve     $16\n\
+             StackStore  [stk+8], rq0\n\
+             StackLoad   rd1, [fp-8]\n\
+             StackStore  [fp+0], rd1\n\
+             Release     $16\n\
+             MoveSx      rq0, rd1\n\
+             MoveSx      rd1, [32], $2\n\
+             Ret"
+        );
+    }
+
+    #[test]
+    fn floats() {
+        let q0 = Register::new(RegisterType::Q, 0).unwrap();
+        let d1 = Register::new(RegisterType::D, 1).unwrap();
+        let listing = disassemble_code(|block| {
+            block.emit_fadd(q0, Operand::lit_f64(1.5))?;
+            block.emit_fmul(d1, Operand::lit_f32(-0.1))?;
+            block.emit_fdiv(q0, Operand::lit_f64(1e-7))?;
+            block.emit_fcmp(q0, Operand::lit_f64(f64::INFINITY))?;
+            block.emit_itof(d1, q0)?;
+            block.emit_ftof(q0, d1)?;
+            block.emit_fneg(q0)?;
+            Ok(())
+        });
+
+        assert_eq!(
+            listing,
+            "FAdd        rq0, $1.5\n\
+             FMul        rd1, $-0.1\n\
+             FDiv        rq0, $1e-7\n\
+             FCmp        rq0, $inf\n\
+             IToF        rd1, rq0\n\
+             FToF        rq0, rd1\n\
+             FNeg        rq0\n\
+             Ret"
+        );
+    }
+
+    #[test]
+    fn memory_clear_and_set() {
+        let listing = disassemble_code(|block| {
+            block.emit_move(Operand::addr(0x1_0000_0020), Operand::lit64(0), None)?;
+            block.emit_move(Operand::addr(0x20), Operand::lit64(1), None)?;
+            Ok(())
+        });
+
+        assert_eq!(listing, "Clear       [4294967328]\nSet         [32]\nRet");
+    }
+
+    #[test]
+    fn invalid_encodings() {
+        let mut out = String::new();
+        for code in [
+            &[Instruction::Pop as u8 | Instruction::ALT_MODE, 0x20][..],
+            &[0x0F],
+            &[Instruction::Jmp as u8, 0x00],
+        ] {
+            assert!(matches!(
+                Disassembler::disassemble_at(code, 0, &mut out),
+                Err(Error::DisassembleFailure(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn jumps_use_labels() {
+        let q0 = Register::new(RegisterType::Q, 0).unwrap();
+        let listing = disassemble_code(|block| {
+            let top = block.new_label();
+            let end = block.new_label();
+            block.bind_label(top)?;
+            block.emit_cmp(q0, Operand::lit64(10))?;
+            block.emit_jge(end);
+            block.emit_add(q0, Operand::lit64(1))?;
+            block.emit_jmp(top);
+            block.bind_label(end)?;
+            Ok(())
+        });
+
+        assert_eq!(
+            listing,
+            "L0:\nCmp         rq0, $10\nJge         L1\nAdd         rq0, $1\nJmp         L0\nL1:\nRet"
+        );
+    }
+
+    #[test]
+    fn calls() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        let callee = builder.procedure(|proc| proc.body(|_| Ok(()))).unwrap();
+        let entry = builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_call(callee);
+                    block.emit_proc_addr(Register::new(RegisterType::Q, 1).unwrap(), callee)?;
+                    block.emit_call_indirect(Register::new(RegisterType::Q, 1).unwrap())?;
+                    Ok(())
+                })
+            })
+            .unwrap();
+        builder.set_entry(entry);
+        let image = builder.link().unwrap();
+
+        let mut out = String::new();
+        crate::disassemble_image(&image, &mut out).unwrap();
+
+        assert!(out.contains("L0:\n00000038      Ret"));
+        assert_eq!(crate::code_labels(&image).unwrap(), [0]);
+        assert!(out.contains("Call        L0\n"));
+        assert!(out.contains("Call        rq1\n"));
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let mut builder = Builder::new(Version::from(0), OutputType::Bin);
+        builder
+            .procedure(|proc| {
+                proc.body(|block| {
+                    block.emit_pop(Register::RS1);
+                    Ok(())
+                })
+            })
+            .unwrap();
+
+        let options = crate::DisassemblyOptions { raw_bytes: true };
+        let mut out = String::new();
+        crate::disassemble_image_with(&builder.link().unwrap(), options, &mut out).unwrap();
+
+        let pop = format!("{:<48}; 08 21\n", "00000038      Pop         rs1");
+        let ret = format!("{:<48}; 20\n", "0000003A      Ret");
+        assert!(out.ends_with(&(pop + &ret)), "{out}");
+    }
+
+    #[test]
+    fn single_instruction() {
+        let mut code = vec![];
+        let mut relocations = vec![];
+        let mut block = crate::BlockBuilder::new(&mut code, &mut relocations);
+        block.emit_noop();
+        block.emit_add(Register::RS1, Operand::lit64(3)).unwrap();
+        block.emit_syscall(2).unwrap();
+        let target = block.label_at(1);
+        block.emit_jnz(target);
+        block.finish_exact().unwrap();
+
+        let mut listing = vec![];
+        let mut offset = 0;
+        while offset < code.len() {
+            let (text, size) = crate::disassemble_instruction(&code, offset).unwrap();
+            listing.push(text);
+            offset += size;
+        }
+        assert_eq!(
+            listing,
+            ["NoOp", "Add         rs1, $3", "Syscall2", "Jnz         $1"]
+        );
+
+        assert!(crate::disassemble_instruction(&code, code.len()).is_err());
+    }
+}
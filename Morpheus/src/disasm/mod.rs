@@ -1,11 +1,48 @@
 pub mod disassembler;
 
-use crate::{Result, Write};
+use crate::{DreamImage, Result, Write};
 
 use disassembler::Disassembler;
 
 pub fn disassemble(dream: impl IntoIterator<Item = u8>, f: &mut dyn Write) -> Result<()> {
-    let mut dismblr = Disassembler::new(dream, f);
+    let bytes: Vec<u8> = dream.into_iter().collect();
+    let image = DreamImage::parse(&bytes)?;
+    disassemble_image(&image, f)
+}
+
+pub fn disassemble_image(image: &DreamImage, f: &mut dyn Write) -> Result<()> {
+    let mut dismblr = Disassembler::new(image, f);
+    dismblr.disassemble()
+}
+
+/// Code offsets of the labels a listing of `image` uses, so that `L3` is
+/// `code_labels(image)?[3]`.
+pub fn code_labels(image: &DreamImage) -> Result<Vec<usize>> {
+    let mut listing = String::new();
+    Disassembler::new(image, &mut listing).labels()
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DisassemblyOptions {
+    /// Print the encoding of every instruction as a trailing comment.
+    pub raw_bytes: bool,
+}
+
+pub fn disassemble_image_with(
+    image: &DreamImage,
+    options: DisassemblyOptions,
+    f: &mut dyn Write,
+) -> Result<()> {
+    let mut dismblr = Disassembler::with_options(image, options, f);
     dismblr.disassemble()
 }
 
+/// Disassembles the single instruction at `offset` into `code`, returning its
+/// text and its size in bytes. Jump targets are printed as code offsets since
+/// there are no labels.
+pub fn disassemble_instruction(code: &[u8], offset: usize) -> Result<(String, usize)> {
+    let mut text = String::new();
+    let size = Disassembler::disassemble_at(code, offset, &mut text)?;
+    text.truncate(text.trim_end().len());
+    Ok((text, size))
+}
@@ -1,3 +1,5 @@
+use crate::ProcId;
+
 #[derive(Debug)]
 pub enum Error {
     InvalidRegister,
@@ -7,12 +9,79 @@ pub enum Error {
     BadOperandType,
     BadOperandValue,
     TooManyArgsForSyscall,
+    WrongNumberOfSyscallArgs,
     InvalidInstruction,
     NotEnoughOperandsForInstruction,
     InvalidAddr,
     InvalidLit64,
-    DisassembleFailure,
+    DisassembleFailure(quicksand::Error),
     InvalidOutputType,
+    NotADreamFile,
+    UnexpectedEndOfFile,
+    MissingPadding,
+    BadSectionSize,
+    DuplicateSection,
+    UnknownSection,
+    MissingCodeSection,
+    InvalidEntryPoint,
+    UnboundLabel,
+    LabelAlreadyBound,
+    ProcedureAlreadyDefined,
+    UndefinedProcedures(Vec<ProcId>),
+    Syntax(SyntaxError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidRegister => write!(f, "invalid register"),
+            Error::VersionOutOfBounds => write!(f, "version out of bounds"),
+            Error::VersionFromStrError => write!(f, "invalid version"),
+            Error::WriteError => write!(f, "failed to write output"),
+            Error::BadOperandType => write!(f, "wrong kind of operand"),
+            Error::BadOperandValue => write!(f, "operand not allowed here"),
+            Error::TooManyArgsForSyscall => write!(f, "too many syscall arguments"),
+            Error::WrongNumberOfSyscallArgs => write!(f, "wrong number of syscall arguments"),
+            Error::InvalidInstruction => write!(f, "invalid instruction"),
+            Error::NotEnoughOperandsForInstruction => write!(f, "not enough operands"),
+            Error::InvalidAddr => write!(f, "invalid address"),
+            Error::InvalidLit64 => write!(f, "invalid literal"),
+            Error::DisassembleFailure(quicksand::Error::UnexpectedEndOfCode) => {
+                write!(f, "unexpected end of code")
+            }
+            Error::DisassembleFailure(_) => write!(f, "invalid instruction encoding"),
+            Error::InvalidOutputType => write!(f, "invalid output type"),
+            Error::NotADreamFile => write!(f, "not a dream file"),
+            Error::UnexpectedEndOfFile => write!(f, "unexpected end of file"),
+            Error::MissingPadding => write!(f, "missing section padding"),
+            Error::BadSectionSize => write!(f, "bad section size"),
+            Error::DuplicateSection => write!(f, "duplicate section"),
+            Error::UnknownSection => write!(f, "unknown section"),
+            Error::MissingCodeSection => write!(f, "missing CODE section"),
+            Error::InvalidEntryPoint => write!(f, "invalid entry point"),
+            Error::UnboundLabel => write!(f, "jump to a label that is never bound"),
+            Error::LabelAlreadyBound => write!(f, "label is already bound"),
+            Error::ProcedureAlreadyDefined => write!(f, "procedure is already defined"),
+            Error::UndefinedProcedures(procs) => {
+                write!(f, "{} procedures are never defined", procs.len())
+            }
+            Error::Syntax(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// An error in assembly source. Lines and columns start at 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,331 @@
+use crate::{
+    errors::{Error, Result},
+    version::Version,
+    OutputType, Write,
+};
+
+/// In-memory representation of a dream file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DreamImage {
+    pub version: Version,
+    pub output_type: OutputType,
+    pub strings: Vec<Box<[u8]>>,
+    pub code: Vec<u8>,
+    pub entry_point: usize,
+}
+
+impl DreamImage {
+    pub const HEADER_SIZE: usize = 16;
+    pub const SECTION_HEADER_SIZE: usize = 16;
+    pub const CODE_HEADER_SIZE: usize = Self::SECTION_HEADER_SIZE + 8;
+    pub const SECTION_PADDING: usize = 4;
+    pub const STRING_PADDING: usize = 8;
+
+    pub fn new(version: Version, output_type: OutputType) -> Self {
+        Self {
+            version,
+            output_type,
+            strings: vec![],
+            code: vec![],
+            entry_point: 0,
+        }
+    }
+
+    /// Parses a dream file, validating its header and sections.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut rdr = Reader::new(bytes);
+
+        rdr.expect(b"DREAM", Error::NotADreamFile)?;
+        let version = std::str::from_utf8(rdr.take(3)?)
+            .map_err(|_| Error::VersionFromStrError)?
+            .parse()?;
+
+        rdr.expect(b"OUTT", Error::InvalidOutputType)?;
+        let output_type = OutputType::try_from(rdr.take_u32()?)?;
+
+        let mut strings = None;
+        let mut code = None;
+
+        while !rdr.is_empty() {
+            let name = rdr.take(4)?;
+            rdr.expect(&[0; Self::SECTION_PADDING], Error::MissingPadding)?;
+
+            match name {
+                b"TEXT" => {
+                    if strings.is_some() {
+                        return Err(Error::DuplicateSection);
+                    }
+                    let size = rdr.take_u64()?;
+                    let data = rdr.take_section(size)?;
+                    strings = Some(Self::parse_strings(data)?);
+                }
+                b"CODE" => {
+                    if code.is_some() {
+                        return Err(Error::DuplicateSection);
+                    }
+                    let size = rdr.take_u64()?;
+                    let entry_point = rdr.take_u64()? as usize;
+                    let bytes = rdr.take_section(size)?;
+                    if entry_point >= bytes.len() && !(bytes.is_empty() && entry_point == 0) {
+                        return Err(Error::InvalidEntryPoint);
+                    }
+                    code = Some((bytes.to_vec(), entry_point));
+                }
+                _ => return Err(Error::UnknownSection),
+            }
+        }
+
+        let (code, entry_point) = code.ok_or(Error::MissingCodeSection)?;
+
+        Ok(Self {
+            version,
+            output_type,
+            strings: strings.unwrap_or_default(),
+            code,
+            entry_point,
+        })
+    }
+
+    fn parse_strings(data: &[u8]) -> Result<Vec<Box<[u8]>>> {
+        let mut rdr = Reader::new(data);
+        let mut strings = vec![];
+
+        while !rdr.is_empty() {
+            let len = rdr.take_u64().map_err(|_| Error::BadSectionSize)?;
+            let s = rdr.take_section(len)?;
+            if rdr.take_section(Self::STRING_PADDING as u64)? != [0; Self::STRING_PADDING] {
+                return Err(Error::MissingPadding);
+            }
+            strings.push(Box::from(s));
+        }
+
+        Ok(strings)
+    }
+
+    /// The contents of the TEXT section without its section header.
+    pub fn text(&self) -> Vec<u8> {
+        let mut text = Vec::with_capacity(self.text_size());
+        for s in self.strings.iter() {
+            text.extend(s.len().to_le_bytes());
+            text.extend(s.iter());
+            text.extend([0; Self::STRING_PADDING]);
+        }
+        text
+    }
+
+    /// Offsets into `text()` of the bytes of each string, skipping their
+    /// length prefix.
+    pub fn string_offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.strings.iter().scan(0, |offset, s| {
+            let start = *offset + std::mem::size_of::<u64>();
+            *offset = start + s.len() + Self::STRING_PADDING;
+            Some(start)
+        })
+    }
+
+    pub fn text_size(&self) -> usize {
+        self.strings
+            .iter()
+            .map(|s| std::mem::size_of::<u64>() + s.len() + Self::STRING_PADDING)
+            .sum()
+    }
+
+    /// File offset of the TEXT section header.
+    pub fn text_offset(&self) -> usize {
+        Self::HEADER_SIZE
+    }
+
+    /// File offset of the CODE section header.
+    pub fn code_offset(&self) -> usize {
+        self.text_offset() + Self::SECTION_HEADER_SIZE + self.text_size()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.write(&mut bytes)
+            .expect("INTERNAL ERROR: writing to a Vec cannot fail.");
+        bytes
+    }
+
+    pub fn write(&self, f: &mut dyn Write) -> Result<()> {
+        self.write_header(f)?;
+        self.write_text_section(f)?;
+        self.write_code_section(f)?;
+        Ok(())
+    }
+
+    pub(crate) fn write_header(&self, f: &mut dyn Write) -> Result<()> {
+        f.write_str("DREAM")?;
+        f.write_bytes(&self.version.as_bytes())?;
+        f.write_str("OUTT")?;
+        f.write_bytes(&self.output_type.as_bytes())?;
+        Ok(())
+    }
+
+    pub(crate) fn write_text_section(&self, f: &mut dyn Write) -> Result<usize> {
+        let mut section_size = 0;
+
+        section_size += f.write_str("TEXT")?;
+        section_size += f.pad(Self::SECTION_PADDING)?;
+        section_size += f.write_bytes(&(self.text_size() as u64).to_le_bytes())?;
+        section_size += f.write_bytes(&self.text())?;
+
+        Ok(section_size)
+    }
+
+    pub(crate) fn write_code_section(&self, f: &mut dyn Write) -> Result<usize> {
+        let mut section_size = 0;
+
+        section_size += f.write_str("CODE")?;
+        section_size += f.pad(Self::SECTION_PADDING)?;
+        section_size += f.write_bytes(&(self.code.len() as u64).to_le_bytes())?;
+        section_size += f.write_bytes(&(self.entry_point as u64).to_le_bytes())?;
+        section_size += f.write_bytes(&self.code)?;
+
+        Ok(section_size)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.bytes.len() {
+            return Err(Error::UnexpectedEndOfFile);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    /// Like `take` but a short read means the size of a section is wrong.
+    fn take_section(&mut self, size: u64) -> Result<&'a [u8]> {
+        match usize::try_from(size) {
+            Ok(n) if n <= self.bytes.len() => self.take(n),
+            _ => Err(Error::BadSectionSize),
+        }
+    }
+
+    fn take_u32(&mut self) -> Result<u32> {
+        let bytes = self.take(std::mem::size_of::<u32>())?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn take_u64(&mut self) -> Result<u64> {
+        let bytes = self.take(std::mem::size_of::<u64>())?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn expect(&mut self, expected: &[u8], err: Error) -> Result<()> {
+        match self.take(expected.len()) {
+            Ok(bytes) if bytes == expected => Ok(()),
+            _ => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> DreamImage {
+        let mut image = DreamImage::new(Version::from(3), OutputType::Lib);
+        image.strings.push(Box::from(&b"Hello world!\n"[..]));
+        image.strings.push(Box::from(&b""[..]));
+        image.code = vec![0x00, 0x00, 0x20];
+        image.entry_point = 1;
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = image();
+        let bytes = image.to_bytes();
+        let parsed = DreamImage::parse(&bytes).unwrap();
+        assert_eq!(parsed, image);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn section_offsets() {
+        let image = image();
+        let bytes = image.to_bytes();
+        assert_eq!(&bytes[image.text_offset()..][..4], b"TEXT");
+        assert_eq!(&bytes[image.code_offset()..][..4], b"CODE");
+    }
+
+    #[test]
+    fn sections_in_any_order() {
+        let image = image();
+        let mut bytes = vec![];
+        image.write_header(&mut bytes).unwrap();
+        image.write_code_section(&mut bytes).unwrap();
+        image.write_text_section(&mut bytes).unwrap();
+        assert_eq!(DreamImage::parse(&bytes).unwrap(), image);
+    }
+
+    #[test]
+    fn not_a_dream_file() {
+        let result = DreamImage::parse(b"NIGHTMARE");
+        assert!(matches!(result, Err(Error::NotADreamFile)));
+    }
+
+    #[test]
+    fn duplicate_section() {
+        let image = image();
+        let mut bytes = image.to_bytes();
+        image.write_text_section(&mut bytes).unwrap();
+        assert!(matches!(DreamImage::parse(&bytes), Err(Error::DuplicateSection)));
+    }
+
+    #[test]
+    fn missing_code_section() {
+        let image = image();
+        let mut bytes = vec![];
+        image.write_header(&mut bytes).unwrap();
+        image.write_text_section(&mut bytes).unwrap();
+        assert!(matches!(DreamImage::parse(&bytes), Err(Error::MissingCodeSection)));
+    }
+
+    #[test]
+    fn bad_section_padding() {
+        let image = image();
+        let mut bytes = image.to_bytes();
+        bytes[image.code_offset() + 4] = 1;
+        assert!(matches!(DreamImage::parse(&bytes), Err(Error::MissingPadding)));
+    }
+
+    #[test]
+    fn bad_string_padding() {
+        let image = image();
+        let mut bytes = image.to_bytes();
+        bytes[image.code_offset() - 1] = 1;
+        assert!(matches!(DreamImage::parse(&bytes), Err(Error::MissingPadding)));
+    }
+
+    #[test]
+    fn bad_section_size() {
+        let image = image();
+        let mut bytes = image.to_bytes();
+        bytes.pop();
+        assert!(matches!(DreamImage::parse(&bytes), Err(Error::BadSectionSize)));
+    }
+
+    #[test]
+    fn bad_entry_point() {
+        let mut image = image();
+        image.entry_point = image.code.len();
+        let bytes = image.to_bytes();
+        assert!(matches!(DreamImage::parse(&bytes), Err(Error::InvalidEntryPoint)));
+    }
+}
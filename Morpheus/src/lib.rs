@@ -1,14 +1,19 @@
+mod asm;
 mod builder;
 mod disasm;
 mod errors;
+mod image;
 mod version;
 mod register_allocator;
 
+pub use asm::*;
 pub use builder::*;
 pub use disasm::*;
 pub use errors::*;
+pub use image::*;
 pub use version::*;
 pub use register_allocator::*;
 
+pub use quicksand::abi;
 pub use quicksand::{OperandType, RegisterType, Register};
 
@@ -51,11 +51,11 @@ macro_rules! inc_with_max {
 impl<'ator> RegisterArena<'ator> {
     pub fn new_arena(&mut self) -> RegisterArena<'_> {
         RegisterArena {
-            allocator: &mut self.allocator,
-            reset_b: self.reset_b,
-            reset_w: self.reset_w,
-            reset_d: self.reset_d,
-            reset_q: self.reset_q,
+            reset_b: self.allocator.next_b,
+            reset_w: self.allocator.next_w,
+            reset_d: self.allocator.next_d,
+            reset_q: self.allocator.next_q,
+            allocator: self.allocator,
         }
     }
 
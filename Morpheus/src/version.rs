@@ -66,9 +66,10 @@ impl FromStr for Version {
             return Err(Error::VersionFromStrError);
         }
 
-        let units = CHARS64[s[2] as usize] as usize;
-        let tens  = CHARS64[s[1] as usize] as usize;
-        let hnds  = CHARS64[s[0] as usize] as usize;
+        let digit = |c: u8| CHARS64.get(c as usize).copied().unwrap_or(0xFF) as usize;
+        let units = digit(s[2]);
+        let tens  = digit(s[1]);
+        let hnds  = digit(s[0]);
 
         if units == 0xFF || tens == 0xFF || hnds == 0xFF {
             return Err(Error::VersionFromStrError);
@@ -115,4 +116,12 @@ mod tests {
         let bytes = version.as_bytes();
         assert_eq!(&bytes, b"///");
     }
+
+    #[test]
+    fn decode() {
+        assert_eq!("00A".parse::<Version>().unwrap(), Version::from(10));
+        assert_eq!("///".parse::<Version>().unwrap(), Version::from(MAX_VERSION_NUMBER));
+        assert!(matches!("0\u{7f}0".parse::<Version>(), Err(Error::VersionFromStrError)));
+        assert!(matches!("é0".parse::<Version>(), Err(Error::VersionFromStrError)));
+    }
 }
@@ -0,0 +1,191 @@
+// The interface between guest programs and the DreamVM: syscall numbers and
+// their arguments, the memory layout, file ids, open flags and the error codes
+// syscalls return.
+//
+// A syscall is issued by moving its number into rsi and its arguments into
+// rs0 onwards, then executing the `Syscall{N}` instruction matching its arity.
+// The result is left in rsr. A failed syscall leaves the negated errno instead
+// (see `error_code`), and a buffer at an unmapped address fails with EFAULT.
+
+/// What a syscall argument or result holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyscallValue {
+    FileID,    // A FileID returned by Open or one of the standard ones.
+    Ptr,       // A guest address.
+    Size,      // A byte count.
+    OpenFlags, // The bits of an OpenFlags.
+    Zero,      // Always 0, the syscall only reports success or failure.
+}
+
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscall {
+    Read = 0,    // fid:FileID, buf:ptr, size:u64 -> num_bytes_read:u64
+    Write = 1,   // fid:FileID, buf:ptr, size:u64 -> num_bytes_written:u64
+    Open = 2,    // path_ptr:ptr, path_len:u64, flags:OpenFlags -> fid:FileID
+    Close = 3,   // fid:FileID -> 0
+    Alloc = 4,   // size:u64 -> ptr
+    Realloc = 5, // ptr:ptr, size:u64 -> ptr
+    Free = 6,    // ptr:ptr -> 0
+}
+
+impl Syscall {
+    pub const ALL: [Syscall; 7] = [
+        Syscall::Read,
+        Syscall::Write,
+        Syscall::Open,
+        Syscall::Close,
+        Syscall::Alloc,
+        Syscall::Realloc,
+        Syscall::Free,
+    ];
+
+    /// The value moved into rsi to select the syscall.
+    pub const fn number(self) -> u16 {
+        self as u16
+    }
+
+    /// The arguments, in the order they are passed in rs0 onwards.
+    pub const fn args(self) -> &'static [SyscallValue] {
+        use SyscallValue::*;
+        match self {
+            Syscall::Read | Syscall::Write => &[FileID, Ptr, Size],
+            Syscall::Open => &[Ptr, Size, OpenFlags],
+            Syscall::Close => &[FileID],
+            Syscall::Alloc => &[Size],
+            Syscall::Realloc => &[Ptr, Size],
+            Syscall::Free => &[Ptr],
+        }
+    }
+
+    /// What is left in rsr when the syscall succeeds.
+    pub const fn returns(self) -> SyscallValue {
+        match self {
+            Syscall::Read | Syscall::Write => SyscallValue::Size,
+            Syscall::Open => SyscallValue::FileID,
+            Syscall::Alloc | Syscall::Realloc => SyscallValue::Ptr,
+            Syscall::Close | Syscall::Free => SyscallValue::Zero,
+        }
+    }
+
+    /// Number of argument registers (rs0 onwards) the syscall takes.
+    pub const fn arity(self) -> usize {
+        self.args().len()
+    }
+}
+
+impl TryFrom<u16> for Syscall {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Syscall::ALL
+            .into_iter()
+            .find(|syscall| syscall.number() == value)
+            .ok_or(value)
+    }
+}
+
+// Guest addresses never refer to host memory. The top 16 bits of an address
+// select a segment and the rest is an offset into it, which is bounds-checked
+// on every access. Address 0 doesn't belong to any segment.
+pub const SEGMENT_SHIFT: u32 = 48;
+pub const OFFSET_MASK: u64 = (1 << SEGMENT_SHIFT) - 1;
+
+/// Read-only strings from the TEXT section.
+pub const TEXT_BASE: u64 = 1 << SEGMENT_SHIFT;
+/// Zero-initialized read-write data.
+pub const DATA_BASE: u64 = 2 << SEGMENT_SHIFT;
+/// The live part of the stack, from its base up to its current depth.
+pub const STACK_BASE: u64 = 3 << SEGMENT_SHIFT;
+/// Memory handed out by the Alloc and Realloc syscalls.
+pub const HEAP_BASE: u64 = 4 << SEGMENT_SHIFT;
+
+// The standard file ids are the host's file descriptors offset by one, so 0
+// is never a valid file.
+pub type FileID = u64;
+pub const BADFID: FileID = 0;
+pub const STDIN: FileID = 1;
+pub const STDOUT: FileID = 2;
+pub const STDERR: FileID = 3;
+
+// Errno-style codes handed back to guests in rsr (negated) when a syscall
+// fails. These follow the Linux numbering on every host.
+pub const ENOENT: u64 = 2;
+pub const EINTR: u64 = 4;
+pub const EIO: u64 = 5;
+pub const EBADF: u64 = 9;
+pub const EAGAIN: u64 = 11;
+pub const ENOMEM: u64 = 12;
+pub const EACCES: u64 = 13;
+pub const EFAULT: u64 = 14;
+pub const EEXIST: u64 = 17;
+pub const EINVAL: u64 = 22;
+pub const EPIPE: u64 = 32;
+
+/// The value a failed syscall leaves in rsr: the errno negated.
+pub const fn error_code(errno: u64) -> u64 {
+    errno.wrapping_neg()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OpenFlags(pub u64);
+
+impl OpenFlags {
+    pub const READ: Self = Self(0x1);
+    pub const WRITE: Self = Self(0x2);
+    pub const APPEND: Self = Self(0x4);
+    pub const TRUNCATE: Self = Self(0x8);
+    pub const CREATE: Self = Self(0x10);
+    pub const CREATE_NEW: Self = Self(0x20);
+
+    const ALL: u64 = 0x3F;
+
+    /// Returns `None` if any bit outside of the known flags is set.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        (bits & !Self::ALL == 0).then_some(Self(bits))
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self & other == other
+    }
+}
+
+impl std::ops::BitOr for OpenFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for OpenFlags {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syscall_numbers() {
+        for syscall in Syscall::ALL {
+            assert_eq!(Syscall::try_from(syscall.number()), Ok(syscall));
+            // Syscall6 is the widest syscall instruction.
+            assert!(syscall.arity() <= 6);
+        }
+        assert_eq!(Syscall::try_from(Syscall::ALL.len() as u16), Err(7));
+        assert_eq!(Syscall::Write.arity(), 3);
+        assert_eq!(Syscall::Open.returns(), SyscallValue::FileID);
+    }
+
+    #[test]
+    fn open_flags() {
+        let flags = OpenFlags::CREATE | OpenFlags::WRITE;
+        assert!(flags.contains(OpenFlags::WRITE));
+        assert!(!flags.contains(OpenFlags::READ));
+        assert_eq!(OpenFlags::from_bits(flags.0), Some(flags));
+        assert_eq!(OpenFlags::from_bits(0x40), None);
+    }
+}
@@ -0,0 +1,774 @@
+use std::fmt::Display;
+use std::num::IntErrorKind;
+use std::str::FromStr;
+
+use crate::errors::{Error, Result};
+use crate::{Instruction, InstructionSignature, OperandType, Register};
+
+/// How an operand is encoded after the opcode byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandEncoding {
+    Register, // A register byte.
+    Address,  // A 64-bit address.
+    Lit64,    // A 64-bit literal, also used for code offsets and sizes.
+    Lit8,     // An 8-bit literal.
+    Stack,    // A 64-bit offset from the base of the stack.
+    Frame,    // A signed 64-bit offset from the frame pointer.
+    Binary,   // A signature byte, then a register and a register or 64-bit literal.
+}
+
+impl OperandEncoding {
+    /// Number of operands the encoding holds.
+    pub const fn arity(self) -> usize {
+        match self {
+            OperandEncoding::Binary => 2,
+            _ => 1,
+        }
+    }
+
+    /// Parses an operand for a slot with this encoding, written the way
+    /// `DecodedOperand` displays it. For `Binary` the slot is the second
+    /// operand, a register or a 64-bit literal, the first is a `Register`.
+    ///
+    /// Fails with `WrongOperandKind` for an operand of another kind, e.g.
+    /// `[stk+8]` for an `Address`, and `LiteralOutOfRange` for a literal that
+    /// doesn't fit, e.g. `$300` for a `Lit8`.
+    pub fn parse(self, s: &str) -> Result<DecodedOperand> {
+        let operand: DecodedOperand = s.parse()?;
+        let fits = match (self, operand) {
+            (OperandEncoding::Lit8, DecodedOperand::Lit64(value)) => {
+                if value > u8::MAX as u64 {
+                    return Err(Error::LiteralOutOfRange);
+                }
+                true
+            }
+            (OperandEncoding::Register, DecodedOperand::Register(_))
+            | (OperandEncoding::Address, DecodedOperand::Address(_))
+            | (OperandEncoding::Lit64, DecodedOperand::Lit64(_))
+            | (OperandEncoding::Stack, DecodedOperand::Stack(_))
+            | (OperandEncoding::Frame, DecodedOperand::Frame(_))
+            | (OperandEncoding::Binary, DecodedOperand::Register(_) | DecodedOperand::Lit64(_)) => {
+                true
+            }
+            _ => false,
+        };
+        if fits {
+            Ok(operand)
+        } else {
+            Err(Error::WrongOperandKind)
+        }
+    }
+}
+
+impl From<OperandType> for OperandEncoding {
+    fn from(kind: OperandType) -> Self {
+        match kind {
+            OperandType::Register => OperandEncoding::Register,
+            OperandType::Address => OperandEncoding::Address,
+            OperandType::Lit64 => OperandEncoding::Lit64,
+        }
+    }
+}
+
+/// A decoded operand. Literals are zero-extended to 64 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodedOperand {
+    Register(Register),
+    Address(u64),
+    Lit64(u64),
+    Stack(u64),
+    Frame(i64),
+}
+
+impl Display for DecodedOperand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            DecodedOperand::Register(reg) => write!(f, "{reg}"),
+            DecodedOperand::Address(addr) => write!(f, "[{addr}]"),
+            DecodedOperand::Lit64(value) => write!(f, "${value}"),
+            DecodedOperand::Stack(offset) => write!(f, "[stk+{offset}]"),
+            DecodedOperand::Frame(offset) if offset < 0 => {
+                write!(f, "[fp-{}]", offset.unsigned_abs())
+            }
+            DecodedOperand::Frame(offset) => write!(f, "[fp+{offset}]"),
+        }
+    }
+}
+
+impl FromStr for DecodedOperand {
+    type Err = Error;
+
+    /// Parses an operand the way it is displayed, e.g. `rq3`, `$42`, `[0x10]`,
+    /// `[stk+8]` or `[fp-8]`. Numbers are decimal or `0x` prefixed hexadecimal,
+    /// literals can also be negative, see `parse_literal`.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(value) = s.strip_prefix('$') {
+            return parse_literal(value).map(DecodedOperand::Lit64);
+        }
+        let Some(slot) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) else {
+            return s.parse().map(DecodedOperand::Register);
+        };
+
+        if let Some(offset) = slot.strip_prefix("stk+") {
+            Ok(DecodedOperand::Stack(parse_number(offset)?))
+        } else if let Some(offset) = slot.strip_prefix("fp+") {
+            let offset = i64::try_from(parse_number(offset)?);
+            offset
+                .map(DecodedOperand::Frame)
+                .or(Err(Error::LiteralOutOfRange))
+        } else if let Some(offset) = slot.strip_prefix("fp-") {
+            let offset = 0i64.checked_sub_unsigned(parse_number(offset)?);
+            offset
+                .map(DecodedOperand::Frame)
+                .ok_or(Error::LiteralOutOfRange)
+        } else {
+            Ok(DecodedOperand::Address(parse_number(slot)?))
+        }
+    }
+}
+
+/// Parses a literal the way it is written after the `$`: a decimal or `0x`
+/// prefixed hexadecimal number, negated as a two's complement if it starts
+/// with a `-`.
+pub fn parse_literal(s: &str) -> Result<u64> {
+    match s.strip_prefix('-') {
+        Some(digits) => parse_number(digits).map(u64::wrapping_neg),
+        None => parse_number(s),
+    }
+}
+
+fn parse_number(s: &str) -> Result<u64> {
+    let (digits, radix) = match s.strip_prefix("0x") {
+        Some(hex) => (hex, 16),
+        None => (s, 10),
+    };
+    // `from_str_radix` also takes a leading `+`, which is never displayed.
+    if digits.starts_with('+') {
+        return Err(Error::InvalidOperand);
+    }
+    u64::from_str_radix(digits, radix).map_err(|err| match err.kind() {
+        IntErrorKind::PosOverflow => Error::LiteralOutOfRange,
+        _ => Error::InvalidOperand,
+    })
+}
+
+/// Formats the bits of a float literal as the type `reg` holds, an f32 for D
+/// registers and an f64 otherwise, e.g. `$1.5`, `$-inf` or `$NaN`. `Debug`
+/// prints the shortest text that reads back as the same value.
+pub fn float_literal(reg: Register, bits: u64) -> String {
+    if reg.is_d() {
+        format!("${:?}", f32::from_bits(bits as u32))
+    } else {
+        format!("${:?}", f64::from_bits(bits))
+    }
+}
+
+/// Parses a float literal for the D or Q register `reg` the way
+/// `float_literal` prints it, e.g. `$1.5`, `$1e-7` or `$inf`. Fails with
+/// `WrongOperandKind` for any other register and `LiteralOutOfRange` for a
+/// finite number too large for the register.
+pub fn parse_float_literal(s: &str, reg: Register) -> Result<DecodedOperand> {
+    if !reg.is_float() {
+        return Err(Error::WrongOperandKind);
+    }
+    let text = s.strip_prefix('$').ok_or(Error::InvalidOperand)?;
+    // `parse` also takes a leading `+`, which is never displayed.
+    if text.starts_with('+') {
+        return Err(Error::InvalidOperand);
+    }
+
+    let (bits, is_infinite) = if reg.is_d() {
+        let float: f32 = text.parse().or(Err(Error::InvalidOperand))?;
+        (float.to_bits() as u64, float.is_infinite())
+    } else {
+        let float: f64 = text.parse().or(Err(Error::InvalidOperand))?;
+        (float.to_bits(), float.is_infinite())
+    };
+    // `parse` takes `inf` and `infinity` in any case, while a number that
+    // overflows to infinity starts with a digit or a point.
+    let is_number = text
+        .trim_start_matches('-')
+        .starts_with(|c: char| c.is_ascii_digit() || c == '.');
+    if is_infinite && is_number {
+        return Err(Error::LiteralOutOfRange);
+    }
+    Ok(DecodedOperand::Lit64(bits))
+}
+
+/// An instruction and its operands in the order they are encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub inst: Instruction,
+    pub is_alt: bool,
+    operands: [DecodedOperand; DecodedInstruction::MAX_OPERANDS],
+    len: usize,
+}
+
+impl DecodedInstruction {
+    pub const MAX_OPERANDS: usize = 3;
+
+    pub fn operands(&self) -> &[DecodedOperand] {
+        &self.operands[..self.len]
+    }
+
+    fn push(&mut self, operand: DecodedOperand) {
+        self.operands[self.len] = operand;
+        self.len += 1;
+    }
+}
+
+/// Decodes the instruction at the start of `code`, returning it along with
+/// the number of bytes it takes up.
+pub fn decode(code: &[u8]) -> Result<(DecodedInstruction, usize)> {
+    let mut bytes = Bytes { code, pos: 0 };
+
+    let opcode = bytes.u8()?;
+    let is_alt = opcode & Instruction::ALT_MODE != 0;
+    let inst = Instruction::try_from(opcode)?;
+    let encodings = inst
+        .operand_encodings(is_alt)
+        .ok_or(Error::InvalidInstruction)?;
+
+    let mut decoded = DecodedInstruction {
+        inst,
+        is_alt,
+        operands: [DecodedOperand::Lit64(0); DecodedInstruction::MAX_OPERANDS],
+        len: 0,
+    };
+    for encoding in encodings {
+        match encoding {
+            OperandEncoding::Register => decoded.push(DecodedOperand::Register(bytes.reg()?)),
+            OperandEncoding::Address => decoded.push(DecodedOperand::Address(bytes.u64()?)),
+            OperandEncoding::Lit64 => decoded.push(DecodedOperand::Lit64(bytes.u64()?)),
+            OperandEncoding::Lit8 => decoded.push(DecodedOperand::Lit64(bytes.u8()? as u64)),
+            OperandEncoding::Stack => decoded.push(DecodedOperand::Stack(bytes.u64()?)),
+            OperandEncoding::Frame => decoded.push(DecodedOperand::Frame(bytes.u64()? as i64)),
+            OperandEncoding::Binary => {
+                let sig = InstructionSignature::try_from(bytes.u8()?)?;
+                if sig.arity() != Some(2) || sig.fst() != Some(OperandType::Register) {
+                    return Err(Error::InvalidSignature);
+                }
+                decoded.push(DecodedOperand::Register(bytes.reg()?));
+                match sig.snd() {
+                    Some(OperandType::Register) => {
+                        decoded.push(DecodedOperand::Register(bytes.reg()?))
+                    }
+                    Some(OperandType::Lit64) => decoded.push(DecodedOperand::Lit64(bytes.u64()?)),
+                    _ => return Err(Error::InvalidSignature),
+                }
+            }
+        }
+    }
+
+    Ok((decoded, bytes.pos))
+}
+
+/// Appends the encoding of an instruction to `out`. Nothing is written unless
+/// the operands match one of the instruction's encodings.
+pub fn encode(
+    inst: Instruction,
+    is_alt: bool,
+    operands: &[DecodedOperand],
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let encodings = inst
+        .operand_encodings(is_alt)
+        .ok_or(Error::InvalidInstruction)?;
+    let arity: usize = encodings.iter().map(|encoding| encoding.arity()).sum();
+    if operands.len() != arity {
+        return Err(Error::InvalidArgument);
+    }
+
+    let mut operands = operands.iter().copied();
+    let mut bytes = Vec::with_capacity(1 + arity * 8);
+    bytes.push(inst as u8 | if is_alt { Instruction::ALT_MODE } else { 0 });
+    for encoding in encodings {
+        // The number of operands was checked above.
+        let operand = operands.next().unwrap();
+        match (encoding, operand) {
+            (OperandEncoding::Register, DecodedOperand::Register(reg)) => bytes.push(reg.to_u8()),
+            (OperandEncoding::Address, DecodedOperand::Address(a))
+            | (OperandEncoding::Lit64, DecodedOperand::Lit64(a))
+            | (OperandEncoding::Stack, DecodedOperand::Stack(a)) => bytes.extend(a.to_le_bytes()),
+            (OperandEncoding::Lit8, DecodedOperand::Lit64(v)) => {
+                bytes.push(u8::try_from(v).map_err(|_| Error::InvalidArgument)?)
+            }
+            (OperandEncoding::Frame, DecodedOperand::Frame(o)) => bytes.extend(o.to_le_bytes()),
+            (OperandEncoding::Binary, DecodedOperand::Register(dst)) => {
+                let src = operands.next().unwrap();
+                let src_type = match src {
+                    DecodedOperand::Register(_) => OperandType::Register,
+                    DecodedOperand::Lit64(_) => OperandType::Lit64,
+                    _ => return Err(Error::InvalidArgument),
+                };
+                let sig = crate::inst_sig!(OperandType::Register, src_type);
+                bytes.push(sig.to_u8());
+                bytes.push(dst.to_u8());
+                match src {
+                    DecodedOperand::Register(src) => bytes.push(src.to_u8()),
+                    DecodedOperand::Lit64(v) => bytes.extend(v.to_le_bytes()),
+                    _ => unreachable!(),
+                }
+            }
+            _ => return Err(Error::InvalidArgument),
+        }
+    }
+    out.extend(bytes);
+    Ok(())
+}
+
+struct Bytes<'code> {
+    code: &'code [u8],
+    pos: usize,
+}
+
+impl Bytes<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = self
+            .code
+            .get(self.pos..self.pos + N)
+            .ok_or(Error::UnexpectedEndOfCode)?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn reg(&mut self) -> Result<Register> {
+        Register::try_from(self.u8()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{inst_sig, RegisterType};
+
+    #[test]
+    fn registers_and_literals() {
+        let q0 = Register::new(RegisterType::Q, 0).unwrap();
+        let mut code = vec![Instruction::MoveImm as u8, q0.to_u8()];
+        code.extend(42u64.to_le_bytes());
+        code.push(Instruction::Ret as u8);
+
+        let (decoded, size) = decode(&code).unwrap();
+        assert_eq!(size, 10);
+        assert_eq!(decoded.inst, Instruction::MoveImm);
+        assert!(!decoded.is_alt);
+        assert_eq!(
+            decoded.operands(),
+            [DecodedOperand::Register(q0), DecodedOperand::Lit64(42)]
+        );
+
+        let (decoded, size) = decode(&code[size..]).unwrap();
+        assert_eq!(
+            (decoded.inst, decoded.operands(), size),
+            (Instruction::Ret, &[][..], 1)
+        );
+    }
+
+    #[test]
+    fn alt_mode() {
+        let mut code = vec![Instruction::StackLoad as u8 | Instruction::ALT_MODE];
+        code.push(Register::RS0.to_u8());
+        code.extend((-8i64).to_le_bytes());
+
+        let (decoded, _) = decode(&code).unwrap();
+        assert!(decoded.is_alt);
+        assert_eq!(
+            decoded.operands(),
+            [
+                DecodedOperand::Register(Register::RS0),
+                DecodedOperand::Frame(-8)
+            ]
+        );
+
+        let pop = [
+            Instruction::Pop as u8 | Instruction::ALT_MODE,
+            Register::RS0.to_u8(),
+        ];
+        assert!(matches!(decode(&pop), Err(Error::InvalidInstruction)));
+    }
+
+    #[test]
+    fn binary_signatures() {
+        let sig = inst_sig!(OperandType::Register, OperandType::Lit64);
+        let mut code = vec![Instruction::Add as u8, sig.to_u8(), Register::RS1.to_u8()];
+        code.extend(3u64.to_le_bytes());
+        let (decoded, size) = decode(&code).unwrap();
+        assert_eq!(size, code.len());
+        assert_eq!(
+            decoded.operands(),
+            [
+                DecodedOperand::Register(Register::RS1),
+                DecodedOperand::Lit64(3)
+            ]
+        );
+
+        let sig = inst_sig!(OperandType::Address, OperandType::Register);
+        let code = [Instruction::Add as u8, sig.to_u8(), 0, 0];
+        assert!(matches!(decode(&code), Err(Error::InvalidSignature)));
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        assert!(matches!(decode(&[]), Err(Error::UnexpectedEndOfCode)));
+        assert!(matches!(decode(&[0x0F]), Err(Error::InvalidInstruction)));
+        assert!(matches!(decode(&[0x7F]), Err(Error::InvalidInstruction)));
+        assert!(matches!(
+            decode(&[Instruction::Jmp as u8, 0, 0]),
+            Err(Error::UnexpectedEndOfCode)
+        ));
+        assert!(matches!(
+            decode(&[Instruction::Not as u8, 0x1F]),
+            Err(Error::InvalidRegister)
+        ));
+
+        // Every opcode byte either decodes or is rejected.
+        for opcode in 0..=u8::MAX {
+            let mut code = vec![opcode];
+            code.extend([0x20; 24]);
+            if let Ok((decoded, size)) = decode(&code) {
+                assert_eq!(decoded.inst as u8, opcode & !Instruction::ALT_MODE);
+                assert!(size <= code.len());
+            }
+        }
+    }
+
+    /// An operand that fits each encoding, varied by `n`.
+    fn sample(encoding: OperandEncoding, n: u64) -> Vec<DecodedOperand> {
+        let reg = Register::new(RegisterType::Q, n as u8).unwrap();
+        match encoding {
+            OperandEncoding::Register => vec![DecodedOperand::Register(reg)],
+            OperandEncoding::Address => vec![DecodedOperand::Address(0x1000 + n)],
+            OperandEncoding::Lit64 => vec![DecodedOperand::Lit64(u64::MAX - n)],
+            OperandEncoding::Lit8 => vec![DecodedOperand::Lit64(n)],
+            OperandEncoding::Stack => vec![DecodedOperand::Stack(8 * n)],
+            OperandEncoding::Frame => vec![DecodedOperand::Frame(-8 * n as i64)],
+            OperandEncoding::Binary => {
+                vec![DecodedOperand::Register(reg), DecodedOperand::Lit64(n)]
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for inst in Instruction::ALL {
+            for is_alt in [false, true] {
+                let Some(encodings) = inst.operand_encodings(is_alt) else {
+                    let mut code = vec![];
+                    assert!(matches!(
+                        encode(inst, is_alt, &[], &mut code),
+                        Err(Error::InvalidInstruction)
+                    ));
+                    assert!(code.is_empty());
+                    continue;
+                };
+                let operands: Vec<_> = encodings
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(n, encoding)| sample(*encoding, n as u64 + 1))
+                    .collect();
+
+                let mut code = vec![0xAA];
+                encode(inst, is_alt, &operands, &mut code).unwrap();
+                let (decoded, size) = decode(&code[1..]).unwrap();
+                assert_eq!(size, code.len() - 1);
+                assert_eq!((decoded.inst, decoded.is_alt), (inst, is_alt));
+                assert_eq!(decoded.operands(), operands);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_mismatches() {
+        let mut code = vec![];
+        let rs0 = DecodedOperand::Register(Register::RS0);
+        let add = |src, code: &mut Vec<u8>| encode(Instruction::Add, false, &[rs0, src], code);
+
+        add(rs0, &mut code).unwrap();
+        let sig = inst_sig!(OperandType::Register, OperandType::Register);
+        assert_eq!(code, [Instruction::Add as u8, sig.to_u8(), 0x20, 0x20]);
+
+        code.clear();
+        assert!(matches!(
+            add(DecodedOperand::Address(0), &mut code),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            encode(Instruction::Pop, false, &[], &mut code),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            encode(
+                Instruction::Pop,
+                false,
+                &[DecodedOperand::Lit64(0)],
+                &mut code
+            ),
+            Err(Error::InvalidArgument)
+        ));
+        let load = [rs0, DecodedOperand::Address(0), DecodedOperand::Lit64(256)];
+        assert!(matches!(
+            encode(Instruction::MoveSx, true, &load, &mut code),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(code.is_empty());
+    }
+
+    #[test]
+    fn operand_text() {
+        let q3 = Register::new(RegisterType::Q, 3).unwrap();
+        let operands = [
+            ("rq3", DecodedOperand::Register(q3)),
+            ("rsi", DecodedOperand::Register(Register::RSI)),
+            ("$42", DecodedOperand::Lit64(42)),
+            ("[16]", DecodedOperand::Address(16)),
+            ("[stk+8]", DecodedOperand::Stack(8)),
+            ("[fp-8]", DecodedOperand::Frame(-8)),
+            ("[fp+16]", DecodedOperand::Frame(16)),
+        ];
+        for (text, operand) in operands {
+            assert_eq!(operand.to_string(), text);
+            assert_eq!(text.parse::<DecodedOperand>().unwrap(), operand);
+        }
+        assert_eq!(
+            "$0x2A".parse::<DecodedOperand>().unwrap(),
+            DecodedOperand::Lit64(42)
+        );
+        assert_eq!(
+            "[0x10]".parse::<DecodedOperand>().unwrap(),
+            DecodedOperand::Address(16)
+        );
+        assert_eq!(
+            "$-1".parse::<DecodedOperand>().unwrap(),
+            DecodedOperand::Lit64(u64::MAX)
+        );
+        assert_eq!(parse_literal("-0x10").unwrap(), 16u64.wrapping_neg());
+
+        for text in [
+            "",
+            "$",
+            "$-",
+            "$--1",
+            "$+1",
+            "[-1]",
+            "[0x+1]",
+            "[]",
+            "[16",
+            "[stk-8]",
+            "[fp+9223372036854775808]",
+            "rq3,",
+        ] {
+            assert!(text.parse::<DecodedOperand>().is_err(), "{text:?}");
+        }
+        assert!(matches!(
+            "rq32".parse::<DecodedOperand>(),
+            Err(Error::InvalidRegister)
+        ));
+        assert!(matches!(
+            "$x".parse::<DecodedOperand>(),
+            Err(Error::InvalidOperand)
+        ));
+        assert!(matches!(
+            "$18446744073709551616".parse::<DecodedOperand>(),
+            Err(Error::LiteralOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn typed_operand_text() {
+        assert_eq!(
+            OperandEncoding::Lit8.parse("$255").unwrap(),
+            DecodedOperand::Lit64(255)
+        );
+        assert_eq!(
+            OperandEncoding::Stack.parse("[stk+8]").unwrap(),
+            DecodedOperand::Stack(8)
+        );
+        assert_eq!(
+            OperandEncoding::Binary.parse("rsi").unwrap(),
+            DecodedOperand::Register(Register::RSI)
+        );
+        assert_eq!(
+            OperandEncoding::Binary.parse("$1").unwrap(),
+            DecodedOperand::Lit64(1)
+        );
+        assert_eq!(
+            OperandEncoding::from(OperandType::Address)
+                .parse("[0x10]")
+                .unwrap(),
+            DecodedOperand::Address(16)
+        );
+
+        for (encoding, text) in [
+            (OperandEncoding::Address, "[stk+8]"),
+            (OperandEncoding::Address, "$16"),
+            (OperandEncoding::Register, "[16]"),
+            (OperandEncoding::Lit64, "rq0"),
+            (OperandEncoding::Lit8, "[fp-8]"),
+            (OperandEncoding::Frame, "[stk+8]"),
+            (OperandEncoding::Binary, "[16]"),
+        ] {
+            assert!(
+                matches!(encoding.parse(text), Err(Error::WrongOperandKind)),
+                "{encoding:?} {text:?}"
+            );
+        }
+        for (encoding, text) in [
+            (OperandEncoding::Lit8, "$256"),
+            (OperandEncoding::Lit8, "$0x100"),
+            (OperandEncoding::Lit64, "$0x10000000000000000"),
+            (OperandEncoding::Frame, "[fp+9223372036854775808]"),
+        ] {
+            assert!(
+                matches!(encoding.parse(text), Err(Error::LiteralOutOfRange)),
+                "{encoding:?} {text:?}"
+            );
+        }
+        assert!(matches!(
+            OperandEncoding::Lit8.parse("$x"),
+            Err(Error::InvalidOperand)
+        ));
+    }
+
+    #[test]
+    fn float_text() {
+        let d0 = Register::new(RegisterType::D, 0).unwrap();
+        let q0 = Register::new(RegisterType::Q, 0).unwrap();
+        let f32_bits = |f: f32| DecodedOperand::Lit64(f.to_bits() as u64);
+        let f64_bits = |f: f64| DecodedOperand::Lit64(f.to_bits());
+
+        assert_eq!(float_literal(d0, 1.5f32.to_bits() as u64), "$1.5");
+        assert_eq!(float_literal(q0, f64::INFINITY.to_bits()), "$inf");
+        assert_eq!(parse_float_literal("$1e-7", d0).unwrap(), f32_bits(1e-7));
+        assert_eq!(parse_float_literal("$3", q0).unwrap(), f64_bits(3.0));
+        assert_eq!(
+            parse_float_literal("$-inf", d0).unwrap(),
+            f32_bits(f32::NEG_INFINITY)
+        );
+
+        for text in ["", "$", "1.5", "$+1.5", "$1.5.", "$0x10", "$ 1"] {
+            assert!(
+                matches!(parse_float_literal(text, q0), Err(Error::InvalidOperand)),
+                "{text:?}"
+            );
+        }
+        assert!(matches!(
+            parse_float_literal("$1.5", Register::RS0),
+            Err(Error::WrongOperandKind)
+        ));
+        assert!(matches!(
+            parse_float_literal("$1e39", d0),
+            Err(Error::LiteralOutOfRange)
+        ));
+        assert!(parse_float_literal("$1e39", q0).is_ok());
+        assert!(matches!(
+            parse_float_literal("$-.5e39", d0),
+            Err(Error::LiteralOutOfRange)
+        ));
+        for text in ["$Inf", "$INF", "$infinity", "$Infinity", "$-INFINITY"] {
+            assert!(
+                matches!(
+                    parse_float_literal(text, d0),
+                    Ok(DecodedOperand::Lit64(bits)) if f32::from_bits(bits as u32).is_infinite()
+                ),
+                "{text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn float_round_trip() {
+        let d0 = Register::new(RegisterType::D, 0).unwrap();
+        let q0 = Register::new(RegisterType::Q, 0).unwrap();
+        let f32s = [
+            0.0,
+            -0.0,
+            0.1,
+            -2.5,
+            1e-7,
+            f32::MIN_POSITIVE,
+            f32::from_bits(1),
+            f32::MAX,
+            f32::MIN,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+        ];
+        for float in f32s {
+            let bits = float.to_bits() as u64;
+            let text = float_literal(d0, bits);
+            let parsed = parse_float_literal(&text, d0).unwrap();
+            assert_eq!(parsed, DecodedOperand::Lit64(bits), "{text}");
+        }
+        let f64s = [
+            0.0,
+            -0.0,
+            0.1,
+            -2.5e-9,
+            1e300,
+            f64::MIN_POSITIVE,
+            f64::from_bits(1),
+            f64::MAX,
+            f64::MIN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for float in f64s {
+            let text = float_literal(q0, float.to_bits());
+            let parsed = parse_float_literal(&text, q0).unwrap();
+            assert_eq!(parsed, DecodedOperand::Lit64(float.to_bits()), "{text}");
+        }
+
+        // NaN keeps neither its sign nor its payload, only that it is NaN.
+        let text = float_literal(d0, f32::NAN.to_bits() as u64);
+        assert_eq!(text, "$NaN");
+        let DecodedOperand::Lit64(bits) = parse_float_literal(&text, d0).unwrap() else {
+            panic!("float literals are Lit64");
+        };
+        assert!(f32::from_bits(bits as u32).is_nan());
+    }
+
+    #[test]
+    fn operand_round_trip() {
+        let values = [
+            0,
+            1,
+            8,
+            255,
+            0x1234_5678,
+            i64::MAX as u64,
+            1 << 63,
+            u64::MAX,
+        ];
+        for value in values {
+            let operands = [
+                DecodedOperand::Address(value),
+                DecodedOperand::Lit64(value),
+                DecodedOperand::Stack(value),
+                DecodedOperand::Frame(value as i64),
+            ];
+            for operand in operands {
+                assert_eq!(
+                    operand.to_string().parse::<DecodedOperand>().unwrap(),
+                    operand
+                );
+            }
+        }
+        for value in 0..=u8::MAX {
+            if let Ok(reg) = Register::try_from(value) {
+                let operand = DecodedOperand::Register(reg);
+                assert_eq!(
+                    operand.to_string().parse::<DecodedOperand>().unwrap(),
+                    operand
+                );
+            }
+        }
+    }
+}
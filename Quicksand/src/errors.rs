@@ -3,6 +3,11 @@ pub enum Error {
     InvalidArgument,
     InvalidRegister,
     InvalidInstruction,
+    InvalidOperand,
+    WrongOperandKind,
+    LiteralOutOfRange,
+    InvalidSignature,
+    UnexpectedEndOfCode,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
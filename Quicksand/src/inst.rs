@@ -1,25 +1,186 @@
-#[repr(u8)]
-#[derive(Debug)]
-pub enum Instruction {
-    NoOp = 0x00,      // Does nothing.
-    Move = 0x01,      // Move a value into a register.
-    MoveImm = 0x02,   // Move an immediate value into a register.
-    MoveAddr = 0x03,  // Move a value into a register via an address.
-    Clear = 0x04,     // Set a register to zero.
-    Set = 0x05,       // Set a register to one.
-    Push = 0x06,      // Push a value onto the stack.
-    PushImm = 0x07,   // Push an immediate value onto the stack.
-    Pop = 0x08,       // Pop a value from the stack and copy into a register.
-    StackLoad = 0x09, // Load a value from the stack into a register.
-    Map = 0x0A,       // Map a constant index into an address and store it in a 64-bit register.
-    Syscall0 = 0x10,  // Perform syscall with 0 arguments.
-    Syscall1 = 0x11,  // Perform syscall with 1 argument.
-    Syscall2 = 0x12,  // Perform syscall with 2 arguments.
-    Syscall3 = 0x13,  // Perform syscall with 3 arguments.
-    Syscall4 = 0x14,  // Perform syscall with 4 arguments.
-    Syscall5 = 0x15,  // Perform syscall with 5 arguments.
-    Syscall6 = 0x16,  // Perform syscall with 6 arguments.
-    Ret = 0x20,       // Returns from the current procedure.
+use crate::OperandEncoding;
+
+/// Declares the instruction set. Each entry is an instruction's opcode and the
+/// encodings of its operands, followed by those of its alt-mode variant if it
+/// has one and an optional flag:
+///
+/// - `[jump]`: a jump, its `Lit64` is a code offset.
+/// - `[call]`: a call, its `Lit64` is a code offset.
+/// - `[float]`: float arithmetic, a literal holds the bits of a float as wide
+///   as the destination register.
+/// - `[reversed]`: the operands are written in the opposite order to how they
+///   are encoded.
+///
+/// The encoder, decoder, assembler and disassembler all work from this table
+/// so adding an instruction only takes a new entry.
+macro_rules! isa {
+    ($($name:ident = $opcode:literal ($($enc:ident),*) $(alt ($($alt:ident),*))? $([$flag:ident])?;)*) => {
+        #[repr(u8)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Instruction {
+            $($name = $opcode,)*
+        }
+
+        impl Instruction {
+            /// Every instruction, in opcode order.
+            pub const ALL: [Instruction; [$($opcode),*].len()] = [$(Instruction::$name),*];
+
+            /// The name the disassembler and assembler use for the instruction.
+            pub const fn mnemonic(self) -> &'static str {
+                match self {
+                    $(Instruction::$name => stringify!($name),)*
+                }
+            }
+
+            /// The operands that follow the opcode, or `None` if `is_alt` is set
+            /// and the instruction has no alt-mode variant.
+            pub const fn operand_encodings(
+                self,
+                is_alt: bool,
+            ) -> Option<&'static [OperandEncoding]> {
+                match self {
+                    $(Instruction::$name => if is_alt {
+                        isa!(@alt $(($($alt),*))?)
+                    } else {
+                        Some(&[$(OperandEncoding::$enc),*])
+                    },)*
+                }
+            }
+
+            /// Returns `true` for the jump instructions, which take a code offset.
+            pub const fn is_jump(self) -> bool {
+                match self {
+                    $(Instruction::$name => isa!(@is jump $($flag)?),)*
+                }
+            }
+
+            /// Returns `true` if the instruction's `Lit64` is the code offset of a
+            /// procedure to call.
+            pub const fn is_call(self) -> bool {
+                match self {
+                    $(Instruction::$name => isa!(@is call $($flag)?),)*
+                }
+            }
+
+            /// Returns `true` for float arithmetic, whose literal operand is an f32
+            /// or f64 depending on the width of the destination register.
+            pub const fn is_float(self) -> bool {
+                match self {
+                    $(Instruction::$name => isa!(@is float $($flag)?),)*
+                }
+            }
+
+            /// Returns `true` if the operands are written in the opposite order to
+            /// how they are encoded, e.g. `StackStore [stk+8], rq0`.
+            pub const fn is_reversed(self) -> bool {
+                match self {
+                    $(Instruction::$name => isa!(@is reversed $($flag)?),)*
+                }
+            }
+        }
+    };
+    (@is jump jump) => { true };
+    (@is call call) => { true };
+    (@is float float) => { true };
+    (@is reversed reversed) => { true };
+    (@is $want:ident $($flag:ident)?) => { false };
+    (@alt ($($alt:ident),*)) => {
+        Some(&[$(OperandEncoding::$alt),*])
+    };
+    (@alt) => {
+        None
+    };
+}
+
+// Calling convention:
+//   Arguments are passed in order in the general purpose registers of their
+//   width starting from index 0 (e.g. rq0, rq1, rd0, ...) and the result is
+//   returned in index 0 of its width (e.g. rq0). Every general purpose register
+//   is caller-saved. `Ret` from the entry procedure halts the program and uses
+//   rq0 as the exit code.
+//
+// Stack frames:
+//   The stack grows up from offset 0. The frame pointer (fp) is the stack depth
+//   when the running procedure was called, so `[fp-8]` is the last value the
+//   caller pushed and `[fp+0]` is the first slot the procedure reserved. `Ret`
+//   releases everything above fp; the caller releases what it pushed.
+//
+// Register widths:
+//   The B, W, D and Q registers are separate 8, 16, 32 and 64-bit banks, rb0 is
+//   not part of rq0. Reading a register zero-extends it and writing one
+//   truncates the value to the register's width, so `Move` between widths
+//   zero-extends or truncates and `MoveSx` sign-extends. Memory and stack loads
+//   read as many bytes as the destination register holds, stores and pushes
+//   write as many as the source register holds.
+//
+// Floating point:
+//   D registers hold f32 and Q registers f64, as their IEEE-754 bits. Float
+//   immediates are encoded the same way, an f32 in the low 32 bits. `FCmp` sets
+//   the zero flag for equal operands and both the less and below flags when the
+//   left one is smaller. If either is NaN it only sets the unordered flag, which
+//   makes every conditional jump except `Jnz` fall through.
+isa! {
+    NoOp = 0x00 ();                                                       // Does nothing.
+    Move = 0x01 (Register, Register) alt (Address, Register);             // Move a value into a register.
+    MoveImm = 0x02 (Register, Lit64) alt (Address, Lit64);                // Move an immediate value into a register.
+    MoveAddr = 0x03 (Register, Address) alt (Address, Address, Lit64);    // Move a value into a register via an address.
+    Clear = 0x04 (Register) alt (Address);                                // Set a register to zero (alt-mode: the 64-bit value at an address).
+    Set = 0x05 (Register) alt (Address);                                  // Set a register to one (alt-mode: the 64-bit value at an address).
+    Push = 0x06 (Register) alt (Address);                                 // Push a value onto the stack.
+    PushImm = 0x07 (Lit64);                                               // Push an immediate value onto the stack.
+    Pop = 0x08 (Register);                                                // Pop a value from the stack and copy into a register.
+    StackLoad = 0x09 (Register, Stack) alt (Register, Frame);             // Load a value from the stack into a register (alt-mode: relative to the frame pointer).
+    Map = 0x0A (Register, Lit64);                                         // Map a TEXT string offset to its address in a 64-bit register, and its length in the next register.
+    StackStore = 0x0B (Register, Stack) alt (Register, Frame) [reversed]; // Store a register to the stack (alt-mode: relative to the frame pointer).
+    Reserve = 0x0C (Lit64);                                               // Grow the stack by a number of zeroed bytes.
+    Release = 0x0D (Lit64);                                               // Shrink the stack by a number of bytes.
+    MoveSx = 0x0E (Register, Register) alt (Register, Address, Lit8);     // Move a register into a wider one, sign-extending it (alt-mode: load 1, 2, 4 or 8 bytes from an address).
+    Syscall0 = 0x10 ();                                                   // Perform syscall with 0 arguments.
+    Syscall1 = 0x11 ();                                                   // Perform syscall with 1 argument.
+    Syscall2 = 0x12 ();                                                   // Perform syscall with 2 arguments.
+    Syscall3 = 0x13 ();                                                   // Perform syscall with 3 arguments.
+    Syscall4 = 0x14 ();                                                   // Perform syscall with 4 arguments.
+    Syscall5 = 0x15 ();                                                   // Perform syscall with 5 arguments.
+    Syscall6 = 0x16 ();                                                   // Perform syscall with 6 arguments.
+    Ret = 0x20 ();                                                        // Returns from the current procedure.
+    Call = 0x21 (Lit64) alt (Register) [call];                            // Call a procedure at a code offset (alt-mode: offset in a Q register).
+    Add = 0x30 (Binary);                                                  // Add a value to a register.
+    Sub = 0x31 (Binary);                                                  // Subtract a value from a register.
+    Mul = 0x32 (Binary);                                                  // Multiply a register by a value.
+    Div = 0x33 (Binary);                                                  // Divide a register by a value (unsigned).
+    IDiv = 0x34 (Binary);                                                 // Divide a register by a value (signed).
+    Mod = 0x35 (Binary);                                                  // Remainder of dividing a register by a value (unsigned).
+    IMod = 0x36 (Binary);                                                 // Remainder of dividing a register by a value (signed).
+    And = 0x37 (Binary);                                                  // Bitwise and a register with a value.
+    Or = 0x38 (Binary);                                                   // Bitwise or a register with a value.
+    Xor = 0x39 (Binary);                                                  // Bitwise xor a register with a value.
+    Not = 0x3A (Register);                                                // Bitwise not a register.
+    Shl = 0x3B (Binary);                                                  // Shift a register left.
+    Shr = 0x3C (Binary);                                                  // Shift a register right (logical).
+    Sar = 0x3D (Binary);                                                  // Shift a register right (arithmetic).
+    Neg = 0x3E (Register);                                                // Negate a register (two's complement).
+    Cmp = 0x40 (Binary);                                                  // Compare a register with a value and set flags.
+    Test = 0x41 (Binary);                                                 // Bitwise and a register with a value and set flags.
+    Jmp = 0x42 (Lit64) [jump];                                            // Jump to a code offset.
+    Jz = 0x43 (Lit64) [jump];                                             // Jump if zero/equal.
+    Jnz = 0x44 (Lit64) [jump];                                            // Jump if not zero/equal.
+    Jlt = 0x45 (Lit64) [jump];                                            // Jump if less than (signed).
+    Jle = 0x46 (Lit64) [jump];                                            // Jump if less than or equal (signed).
+    Jgt = 0x47 (Lit64) [jump];                                            // Jump if greater than (signed).
+    Jge = 0x48 (Lit64) [jump];                                            // Jump if greater than or equal (signed).
+    Jb = 0x49 (Lit64) [jump];                                             // Jump if below (unsigned).
+    Jbe = 0x4A (Lit64) [jump];                                            // Jump if below or equal (unsigned).
+    Ja = 0x4B (Lit64) [jump];                                             // Jump if above (unsigned).
+    Jae = 0x4C (Lit64) [jump];                                            // Jump if above or equal (unsigned).
+    FAdd = 0x50 (Binary) [float];                                         // Add a float to a D (f32) or Q (f64) register.
+    FSub = 0x51 (Binary) [float];                                         // Subtract a float from a register.
+    FMul = 0x52 (Binary) [float];                                         // Multiply a register by a float.
+    FDiv = 0x53 (Binary) [float];                                         // Divide a register by a float.
+    FNeg = 0x54 (Register) [float];                                       // Negate a float register.
+    FCmp = 0x55 (Binary) [float];                                         // Compare a float register with a float and set flags.
+    IToF = 0x58 (Register, Register);                                     // Convert a signed integer register to a float register.
+    FToI = 0x59 (Register, Register);                                     // Convert a float register to a signed integer register, rounding toward zero.
+    FToF = 0x5A (Register, Register);                                     // Convert between f32 and f64 registers.
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -29,6 +190,31 @@ impl InstructionSignature {
     pub const fn to_u8(self) -> u8 {
         self.0
     }
+
+    /// Returns the number of operands in the signature or `None` if an
+    /// operand slot is empty before the last occupied one.
+    pub const fn arity(self) -> Option<usize> {
+        let mut n = 0;
+        while n < 4 && (self.0 >> (n * 2)) & 0x03 != 0 {
+            n += 1;
+        }
+        if n < 4 && self.0 >> (n * 2) != 0 {
+            None
+        } else {
+            Some(n)
+        }
+    }
+}
+
+impl TryFrom<u8> for InstructionSignature {
+    type Error = crate::Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let sig = InstructionSignature(value);
+        match sig.arity() {
+            Some(_) => Ok(sig),
+            None => Err(crate::Error::InvalidSignature),
+        }
+    }
 }
 
 impl Instruction {
@@ -70,37 +256,35 @@ impl Instruction {
     }
 }
 
+impl Instruction {
+    /// Maps each opcode (without the alt-mode bit) to its instruction.
+    const BY_OPCODE: [Option<Instruction>; Instruction::MAX as usize + 1] = {
+        let mut table = [None; Instruction::MAX as usize + 1];
+        let mut i = 0;
+        while i < Instruction::ALL.len() {
+            table[Instruction::ALL[i] as usize] = Some(Instruction::ALL[i]);
+            i += 1;
+        }
+        table
+    };
+}
+
 impl TryFrom<u8> for Instruction {
     type Error = crate::Error;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        // SAFETY: We're explicitly allowing `inst` to be an incorrect value
-        // because the code below handles that case.
-        let inst = unsafe { std::mem::transmute(value & !Self::ALT_MODE) };
-        match inst {
-            Instruction::NoOp
-            | Instruction::Move
-            | Instruction::MoveImm
-            | Instruction::MoveAddr
-            | Instruction::Clear
-            | Instruction::Set
-            | Instruction::Push
-            | Instruction::PushImm
-            | Instruction::Pop
-            | Instruction::StackLoad
-            | Instruction::Map
-            | Instruction::Syscall0
-            | Instruction::Syscall1
-            | Instruction::Syscall2
-            | Instruction::Syscall3
-            | Instruction::Syscall4
-            | Instruction::Syscall5
-            | Instruction::Syscall6
-            | Instruction::Ret => return Ok(inst),
-        }
+        Self::BY_OPCODE[(value & !Self::ALT_MODE) as usize].ok_or(crate::Error::InvalidInstruction)
+    }
+}
 
-        // This is reachable because `inst` might be an invalid `Instruction`.
-        #[allow(unreachable_code)]
-        Err(crate::Error::InvalidInstruction)
+impl std::str::FromStr for Instruction {
+    type Err = crate::Error;
+
+    /// Parses a mnemonic in any case, e.g. `MoveImm` or `moveimm`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|inst| inst.mnemonic().eq_ignore_ascii_case(s))
+            .ok_or(crate::Error::InvalidInstruction)
     }
 }
 
@@ -129,27 +313,41 @@ macro_rules! inst_sig {
     };
 }
 
+impl TryFrom<u8> for OperandType {
+    type Error = crate::Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b01 => Ok(OperandType::Register),
+            0b10 => Ok(OperandType::Address),
+            0b11 => Ok(OperandType::Lit64),
+            _ => Err(crate::Error::InvalidSignature),
+        }
+    }
+}
+
 impl InstructionSignature {
-    pub fn fst(self) -> OperandType {
-        unsafe { std::mem::transmute(self.0 & 0x03) }
+    pub fn fst(self) -> Option<OperandType> {
+        self.get(0)
     }
 
-    pub fn snd(self) -> OperandType {
-        unsafe { std::mem::transmute((self.0 & 0x0C) >> 2) }
+    pub fn snd(self) -> Option<OperandType> {
+        self.get(1)
     }
 
-    pub fn thd(self) -> OperandType {
-        unsafe { std::mem::transmute((self.0 & 0x30) >> 4) }
+    pub fn thd(self) -> Option<OperandType> {
+        self.get(2)
     }
 
-    pub fn frth(self) -> OperandType {
-        unsafe { std::mem::transmute((self.0 & 0xC0) >> 6) }
+    pub fn frth(self) -> Option<OperandType> {
+        self.get(3)
     }
 
-    pub fn get(self, index: usize) -> OperandType {
+    /// Returns the type of the operand at `index`, or `None` if the slot is
+    /// empty.
+    pub fn get(self, index: usize) -> Option<OperandType> {
         assert!(index < 4);
         let shift = (index as u8) * 2;
-        unsafe { std::mem::transmute((self.0 >> shift) & 0x03) }
+        OperandType::try_from((self.0 >> shift) & 0x03).ok()
     }
 }
 
@@ -212,6 +410,27 @@ mod tests {
         assert_eq!(sig4, InstructionSignature(0b10101010));
     }
 
+    #[test]
+    fn arity() {
+        assert_eq!(InstructionSignature(0).arity(), Some(0));
+        assert_eq!(inst_sig!(OperandType::Register).arity(), Some(1));
+        assert_eq!(
+            inst_sig!(OperandType::Register, OperandType::Lit64).arity(),
+            Some(2)
+        );
+        assert_eq!(InstructionSignature(0b00000100).arity(), None);
+        assert_eq!(InstructionSignature(0b11001111).arity(), None);
+    }
+
+    #[test]
+    fn try_from_u8() {
+        assert!(InstructionSignature::try_from(0b00001101).is_ok());
+        assert!(matches!(
+            InstructionSignature::try_from(0b00110001),
+            Err(crate::Error::InvalidSignature)
+        ));
+    }
+
     #[test]
     fn fst() {
         let sig = inst_sig!(
@@ -220,7 +439,7 @@ mod tests {
             OperandType::Address,
             OperandType::Lit64
         );
-        assert_eq!(sig.fst(), OperandType::Lit64);
+        assert_eq!(sig.fst(), Some(OperandType::Lit64));
     }
 
     #[test]
@@ -231,7 +450,7 @@ mod tests {
             OperandType::Address,
             OperandType::Lit64
         );
-        assert_eq!(sig.snd(), OperandType::Register);
+        assert_eq!(sig.snd(), Some(OperandType::Register));
     }
 
     #[test]
@@ -242,7 +461,7 @@ mod tests {
             OperandType::Address,
             OperandType::Lit64
         );
-        assert_eq!(sig.thd(), OperandType::Address);
+        assert_eq!(sig.thd(), Some(OperandType::Address));
     }
 
     #[test]
@@ -253,7 +472,7 @@ mod tests {
             OperandType::Address,
             OperandType::Lit64
         );
-        assert_eq!(sig.frth(), OperandType::Lit64);
+        assert_eq!(sig.frth(), Some(OperandType::Lit64));
     }
 
     #[test]
@@ -264,9 +483,50 @@ mod tests {
             OperandType::Address,
             OperandType::Lit64
         );
-        assert_eq!(sig.get(0), OperandType::Lit64);
-        assert_eq!(sig.get(1), OperandType::Register);
-        assert_eq!(sig.get(2), OperandType::Address);
-        assert_eq!(sig.get(3), OperandType::Lit64);
+        assert_eq!(sig.get(0), Some(OperandType::Lit64));
+        assert_eq!(sig.get(1), Some(OperandType::Register));
+        assert_eq!(sig.get(2), Some(OperandType::Address));
+        assert_eq!(sig.get(3), Some(OperandType::Lit64));
+        assert_eq!(inst_sig!(OperandType::Register).get(1), None);
+    }
+
+    #[test]
+    fn opcodes() {
+        for inst in Instruction::ALL {
+            assert_eq!(Instruction::try_from(inst as u8).unwrap(), inst);
+            assert_eq!(
+                Instruction::try_from(inst as u8 | Instruction::ALT_MODE).unwrap(),
+                inst
+            );
+        }
+        assert!(Instruction::try_from(0x0F).is_err());
+        assert!(Instruction::try_from(0xFF).is_err());
+    }
+
+    #[test]
+    fn mnemonics() {
+        for inst in Instruction::ALL {
+            assert_eq!(inst.mnemonic().parse::<Instruction>().unwrap(), inst);
+            let lower = inst.mnemonic().to_ascii_lowercase();
+            assert_eq!(lower.parse::<Instruction>().unwrap(), inst);
+        }
+        assert_eq!("FToI".parse::<Instruction>().unwrap(), Instruction::FToI);
+        assert!("".parse::<Instruction>().is_err());
+        assert!("Move ".parse::<Instruction>().is_err());
+        assert!("Syscall7".parse::<Instruction>().is_err());
+    }
+
+    #[test]
+    fn flags() {
+        let jumps = Instruction::ALL
+            .iter()
+            .filter(|inst| inst.is_jump())
+            .count();
+        assert_eq!(jumps, 11);
+        assert!(Instruction::Jae.is_jump() && !Instruction::Jae.is_call());
+        assert!(Instruction::Call.is_call() && !Instruction::Call.is_jump());
+        assert!(Instruction::FCmp.is_float() && !Instruction::IToF.is_float());
+        assert!(Instruction::StackStore.is_reversed());
+        assert!(!Instruction::StackLoad.is_reversed());
     }
 }
@@ -1,7 +1,10 @@
+pub mod abi;
+mod encoding;
 mod errors;
 mod inst;
 mod register;
 
+pub use encoding::*;
 pub use errors::*;
 pub use inst::*;
 pub use register::*;
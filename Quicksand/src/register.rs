@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use std::str::FromStr;
 
 use crate::errors::{Error, Result};
 
@@ -32,6 +33,8 @@ impl SyscallRegisterPrefix {
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Register(u8);
 
+// The `| 0` and `as u8` spell out each register's encoding in full.
+#[allow(clippy::identity_op, clippy::unnecessary_cast)]
 impl Register {
     const RSX: u8 = RegisterType::S as u8 | SyscallRegisterPrefix::RSX as u8;
 
@@ -94,6 +97,45 @@ impl Register {
     pub const fn is_q(self) -> bool {
         self.0 & RegisterType::MASK as u8 == RegisterType::Q as u8
     }
+
+    /// Returns the number of bytes the register holds. Values read from a
+    /// register are zero-extended to 64 bits and values written to it are
+    /// truncated to this width.
+    pub const fn width(self) -> usize {
+        if self.is_x() || self.is_b() {
+            1
+        } else if self.0 == Self::RSI.0 || self.is_w() {
+            2
+        } else if self.is_d() {
+            4
+        } else {
+            8
+        }
+    }
+
+    /// Whether the register can hold a float: D registers hold f32 and Q
+    /// registers f64.
+    pub const fn is_float(self) -> bool {
+        self.is_d() || self.is_q()
+    }
+
+    /// The register with the next index and the same type, e.g. rs2 for rs1.
+    pub const fn next(self) -> Option<Register> {
+        if self.is_rsx() {
+            match Register::new(RegisterType::S, (self.0 & 0x07) + 1) {
+                Ok(reg) => Some(reg),
+                Err(_) => None,
+            }
+        } else if self.is_b() || self.is_w() || self.is_d() || self.is_q() {
+            if self.0 & 0x1F < Self::MAX - 1 {
+                Some(Register(self.0 + 1))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
 }
 
 impl TryFrom<u8> for Register {
@@ -195,6 +237,38 @@ impl Display for Register {
     }
 }
 
+impl FromStr for Register {
+    type Err = Error;
+
+    /// Parses a register the way it is displayed, e.g. `rq3`, `rs1` or `rsi`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rxz" => return Ok(Register::RXZ),
+            "rsi" => return Ok(Register::RSI),
+            "rsr" => return Ok(Register::RSR),
+            _ => {}
+        }
+
+        let rest = s.strip_prefix('r').ok_or(Error::InvalidRegister)?;
+        let mut chars = rest.chars();
+        let reg_type = match chars.next() {
+            Some('s') => RegisterType::S,
+            Some('b') => RegisterType::B,
+            Some('w') => RegisterType::W,
+            Some('d') => RegisterType::D,
+            Some('q') => RegisterType::Q,
+            _ => return Err(Error::InvalidRegister),
+        };
+        // Only plain decimal indices, `u8::from_str` would also take `+1`.
+        let index = chars.as_str();
+        if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Error::InvalidRegister);
+        }
+        let index = index.parse().map_err(|_| Error::InvalidRegister)?;
+        Register::new(reg_type, index)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -253,6 +327,18 @@ mod tests {
         assert!(matches!(result, Ok(Register(0x5F))));
     }
 
+    #[test]
+    pub fn width() {
+        let width = |t, x| Register::new(t, x).unwrap().width();
+        assert_eq!(width(RegisterType::B, 3), 1);
+        assert_eq!(width(RegisterType::W, 3), 2);
+        assert_eq!(width(RegisterType::D, 3), 4);
+        assert_eq!(width(RegisterType::Q, 3), 8);
+        assert_eq!(Register::RSI.width(), 2);
+        assert_eq!(Register::RSR.width(), 8);
+        assert_eq!(Register::RS5.width(), 8);
+    }
+
     #[test]
     pub fn is_x() {
         let x = Register::new(RegisterType::X, 0).unwrap();
@@ -310,4 +396,34 @@ mod tests {
         assert!(q.is_q());
         assert!(!b.is_q());
     }
+
+    #[test]
+    fn next() {
+        assert_eq!(Register::RS1.next(), Some(Register::RS2));
+        assert_eq!(Register::RS5.next(), None);
+        assert_eq!(Register::RSI.next(), None);
+        assert_eq!(Register::RXZ.next(), None);
+
+        let q0 = Register::new(RegisterType::Q, 0).unwrap();
+        let q1 = Register::new(RegisterType::Q, 1).unwrap();
+        assert_eq!(q0.next(), Some(q1));
+
+        let b31 = Register::new(RegisterType::B, 31).unwrap();
+        assert_eq!(b31.next(), None);
+    }
+
+    #[test]
+    fn parse() {
+        for value in 0..=u8::MAX {
+            if let Ok(reg) = Register::try_from(value) {
+                assert_eq!(reg.to_string().parse::<Register>().unwrap(), reg);
+            }
+        }
+        for name in ["", "r", "rq", "rq32", "rs6", "rx0", "rq+1", "Rq0", "rq0 "] {
+            assert!(matches!(
+                name.parse::<Register>(),
+                Err(Error::InvalidRegister)
+            ));
+        }
+    }
 }